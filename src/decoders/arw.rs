@@ -4,6 +4,8 @@ use std::cmp;
 use crate::decoders::*;
 use crate::decoders::tiff::*;
 use crate::decoders::basics::*;
+use crate::decoders::cfa::*;
+use crate::decoders::ljpeg::*;
 
 #[derive(Debug, Clone)]
 pub struct ArwDecoder<'a> {
@@ -25,6 +27,11 @@ impl<'a> ArwDecoder<'a> {
 impl<'a> Decoder for ArwDecoder<'a> {
   fn image(&self, dummy: bool) -> Result<RawImage,String> {
     let camera = self.rawhide.check_supported(&self.tiff)?;
+    if let Some(raw) = self.tiff.find_first_ifd(Tag::TileOffsets) {
+      if fetch_tag!(raw, Tag::Compression).get_u32(0) == 7 {
+        return self.image_lossless(camera, raw, dummy)
+      }
+    }
     let data = self.tiff.find_ifds_with_tag(Tag::StripOffsets);
     if data.len() == 0 {
       if camera.model == "DSLR-A100" {
@@ -34,6 +41,11 @@ impl<'a> Decoder for ArwDecoder<'a> {
       }
     }
     let raw = data[0];
+    if let Some(spp) = raw.find_entry(Tag::SamplesPerPixel) {
+      if spp.get_usize(0) == 4 {
+        return self.image_arq(camera, raw, dummy)
+      }
+    }
     let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
     let mut height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
     let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
@@ -127,6 +139,32 @@ impl<'a> ArwDecoder<'a> {
     ok_image(camera, width, height, wb_coeffs, image)
   }
 
+  // ARQ files are the pixel shift frames already combined by Sony's software with four
+  // 16 bit samples per pixel (R, G, G, B) so we just need to average the greens
+  fn image_arq(&self, camera: Camera, raw: &TiffIFD, dummy: bool) -> Result<RawImage,String> {
+    let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
+    let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
+    let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
+    let src = &self.buffer[offset..];
+    let little = raw.little_endian();
+
+    let image = decode_threaded(width*3, height, dummy, &(|out: &mut [u16], row| {
+      let inb = &src[row*width*8..];
+      for (o, bytes) in out.chunks_exact_mut(3).zip(inb.chunks_exact(8)) {
+        let get = |pos| if little { LEu16(bytes, pos) } else { BEu16(bytes, pos) };
+        o[0] = get(0);
+        o[1] = ((get(2) as u32 + get(4) as u32) >> 1) as u16;
+        o[2] = get(6);
+      }
+    }));
+
+    let mut img = RawImage::new(camera, width*3, height, self.get_wb()?, image, dummy);
+    img.cpp = 3;
+    img.width = width;
+    img.cfa = CFA::new("");
+    Ok(img)
+  }
+
   fn image_srf(&self, camera: Camera, dummy: bool) -> Result<RawImage,String> {
     let data = self.tiff.find_ifds_with_tag(Tag::ImageWidth);
     if data.len() == 0 {
@@ -226,13 +264,30 @@ impl<'a> ArwDecoder<'a> {
     }))
   }
 
-  fn get_wb(&self) -> Result<[f32;4], String> {
+  // The SR2 private data is encrypted, returns the decrypted buffer and its offset
+  fn get_sr2(&self) -> Result<(Vec<u8>, usize), String> {
     let priv_offset = fetch_tag!(self.tiff, Tag::DNGPrivateArea).get_force_u32(0) as usize;
     let priv_tiff = TiffIFD::new(self.buffer, priv_offset, 0, 0, 0, LITTLE_ENDIAN)?;
     let sony_offset = fetch_tag!(priv_tiff, Tag::SonyOffset).get_usize(0);
     let sony_length = fetch_tag!(priv_tiff, Tag::SonyLength).get_usize(0);
     let sony_key = fetch_tag!(priv_tiff, Tag::SonyKey).get_u32(0);
-    let decrypted_buf = ArwDecoder::sony_decrypt(self.buffer, sony_offset, sony_length, sony_key);
+    Ok((ArwDecoder::sony_decrypt(self.buffer, sony_offset, sony_length, sony_key), sony_offset))
+  }
+
+  // Black (RGGB) and white levels stored in the SR2 data by newer cameras
+  fn get_levels(&self) -> Option<([u16;4], u16)> {
+    let (decrypted_buf, sony_offset) = self.get_sr2().ok()?;
+    let decrypted_tiff = TiffIFD::new(&decrypted_buf, 0, sony_offset, 0, 0, LITTLE_ENDIAN).ok()?;
+    let black = decrypted_tiff.find_entry(Tag::SonyBlackLevel)?;
+    let white = decrypted_tiff.find_entry(Tag::SonyWhiteLevel)?;
+    if black.count() < 4 {
+      return None
+    }
+    Some(([black.get_u16(0), black.get_u16(1), black.get_u16(3), black.get_u16(2)], white.get_u16(0)))
+  }
+
+  fn get_wb(&self) -> Result<[f32;4], String> {
+    let (decrypted_buf, sony_offset) = self.get_sr2()?;
     let decrypted_tiff = TiffIFD::new(&decrypted_buf, 0, sony_offset, 0, 0, LITTLE_ENDIAN).unwrap();
     let grgb_levels = decrypted_tiff.find_entry(Tag::SonyGRBG);
     let rggb_levels = decrypted_tiff.find_entry(Tag::SonyRGGB);
@@ -247,6 +302,66 @@ impl<'a> ArwDecoder<'a> {
     }
   }
 
+  // Lossless compressed files are LJPEG tiles where each 4 component pixel is a 2x2
+  // block of the bayer pattern
+  fn image_lossless(&self, camera: Camera, raw: &TiffIFD, dummy: bool) -> Result<RawImage,String> {
+    let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
+    let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
+    let twidth = fetch_tag!(raw, Tag::TileWidth).get_usize(0);
+    let tlength = fetch_tag!(raw, Tag::TileLength).get_usize(0);
+    let offsets = fetch_tag!(raw, Tag::TileOffsets);
+    if twidth == 0 || tlength == 0 || twidth % 2 != 0 || tlength % 2 != 0 {
+      return Err(format!("ARW: invalid tile size {}x{}", twidth, tlength).to_string())
+    }
+    let coltiles = (width-1)/twidth + 1;
+    let rowtiles = (height-1)/tlength + 1;
+    if coltiles*rowtiles > offsets.count() {
+      return Err(format!("ARW: trying to decode {} tiles from {} offsets",
+                         coltiles*rowtiles, offsets.count()).to_string())
+    }
+
+    let image = decode_threaded_multiline_result(width, height, tlength, dummy, &(|strip: &mut [u16], row| {
+      let row = row / tlength;
+      let mut tile = vec![0 as u16; twidth * tlength];
+      for col in 0..coltiles {
+        let offset = offsets.get_usize(row*coltiles+col);
+        if offset >= self.buffer.len() {
+          return Err("ARW: tile starts beyond the end of the file".to_string())
+        }
+        let decompressor = LjpegDecompressor::new(&self.buffer[offset..])?;
+        decompressor.decode(&mut tile, 0, twidth*2, twidth*2, tlength/2, dummy)?;
+        let bwidth = cmp::min(width, (col+1)*twidth) - col*twidth;
+        let blength = cmp::min(strip.len() / width, tlength);
+        for (jrow, line) in tile.chunks_exact(twidth*2).enumerate().take(blength/2) {
+          for jcol in 0..bwidth/2 {
+            let pos = 2*jrow*width + col*twidth + 2*jcol;
+            strip[pos] = line[jcol*4];
+            strip[pos+1] = line[jcol*4+1];
+            strip[pos+width] = line[jcol*4+2];
+            strip[pos+width+1] = line[jcol*4+3];
+          }
+        }
+      }
+      Ok(())
+    }))?;
+
+    let wb = self.get_wb()?;
+    match self.get_levels() {
+      Some((blacks, white)) => {
+        let mut img = RawImage::new(camera, width, height, wb, image, dummy);
+        img.blacklevels = blacks;
+        img.whitelevels = [white, white, white, white];
+        Ok(img)
+      },
+      // Lossless data is always 14 bit, so scale the levels of cameras set up for 12 bit
+      None if camera.whitelevels[0] < 8192 => {
+        let (black, white) = (camera.blacklevels[0] << 2, ((camera.whitelevels[0] as u32 + 1) * 4 - 1) as u16);
+        ok_image_with_black_white(camera, width, height, wb, black, white, image)
+      },
+      None => ok_image(camera, width, height, wb, image),
+    }
+  }
+
   fn get_curve(raw: &TiffIFD) -> Result<LookupTable, String> {
     let centry = fetch_tag!(raw, Tag::SonyCurve);
     let mut curve: [usize;6] = [ 0, 0, 0, 0, 0, 4095 ];
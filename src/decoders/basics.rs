@@ -16,6 +16,76 @@ pub fn clampbits(val: i32, bits: u32) -> u16 {
   }
 }
 
+// Convert a 16 bit half float into an f32. Infinities are clamped to the largest
+// value and NaNs become 0 as they're never valid pixel values.
+pub fn fp16_to_f32(val: u16) -> f32 {
+  let sign = ((val >> 15) & 1) as u32;
+  let mut exponent = ((val >> 10) & 0x1f) as i32;
+  let mut mantissa = (val & 0x3ff) as u32;
+
+  if exponent == 0 {
+    if mantissa == 0 {
+      return f32::from_bits(sign << 31)
+    }
+    // Denormal, normalize it
+    while mantissa & 0x400 == 0 {
+      mantissa <<= 1;
+      exponent -= 1;
+    }
+    exponent += 1;
+    mantissa &= !0x400;
+  } else if exponent == 31 {
+    return if mantissa == 0 {
+      f32::from_bits((sign << 31) | ((0x1e + 127 - 15) << 23) | (0x3ff << 13))
+    } else {
+      0.0
+    }
+  }
+
+  f32::from_bits((sign << 31) | (((exponent + 127 - 15) as u32) << 23) | (mantissa << 13))
+}
+
+// Convert the 24 bit floats used in DNG (1 sign, 7 exponent and 16 mantissa bits)
+// into an f32. Infinities and NaNs are handled the same as in fp16_to_f32.
+pub fn fp24_to_f32(val: u32) -> f32 {
+  let sign = (val >> 23) & 1;
+  let mut exponent = ((val >> 16) & 0x7f) as i32;
+  let mut mantissa = val & 0xffff;
+
+  if exponent == 0 {
+    if mantissa == 0 {
+      return f32::from_bits(sign << 31)
+    }
+    while mantissa & 0x10000 == 0 {
+      mantissa <<= 1;
+      exponent -= 1;
+    }
+    exponent += 1;
+    mantissa &= !0x10000;
+  } else if exponent == 0x7f {
+    return if mantissa == 0 {
+      f32::from_bits((sign << 31) | ((0x7e + 128 - 64) << 23) | (0xffff << 7))
+    } else {
+      0.0
+    }
+  }
+
+  f32::from_bits((sign << 31) | (((exponent + 128 - 64) as u32) << 23) | (mantissa << 7))
+}
+
+// Convert the bits of a 32 bit float into an f32. Infinities and NaNs are handled
+// the same as in fp16_to_f32.
+pub fn fp32_to_f32(val: u32) -> f32 {
+  let val = f32::from_bits(val);
+  if val.is_nan() {
+    0.0
+  } else if val.is_infinite() {
+    if val > 0.0 { std::f32::MAX } else { std::f32::MIN }
+  } else {
+    val
+  }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Endian {
   big: bool,
@@ -68,10 +138,22 @@ pub static LITTLE_ENDIAN: Endian = Endian{big: false};
   LittleEndian::read_u32(&buf[pos..pos+4])
 }
 
+#[allow(non_snake_case)] #[inline] pub fn LEu64(buf: &[u8], pos: usize) -> u64 {
+  LittleEndian::read_u64(&buf[pos..pos+8])
+}
+
 #[allow(non_snake_case)] #[inline] pub fn LEf32(buf: &[u8], pos: usize) -> f32 {
   LittleEndian::read_f32(&buf[pos..pos+4])
 }
 
+#[allow(non_snake_case)] #[inline] pub fn BEf32(buf: &[u8], pos: usize) -> f32 {
+  BigEndian::read_f32(&buf[pos..pos+4])
+}
+
+#[allow(non_snake_case)] #[inline] pub fn BEf64(buf: &[u8], pos: usize) -> f64 {
+  BigEndian::read_f64(&buf[pos..pos+8])
+}
+
 #[allow(non_snake_case)] #[inline] pub fn BEu16(buf: &[u8], pos: usize) -> u16 {
   BigEndian::read_u16(&buf[pos..pos+2])
 }
@@ -100,6 +182,17 @@ pub fn decode_threaded_multiline<F>(width: usize, height: usize, lines: usize, d
   out
 }
 
+// Same as decode_threaded_multiline for decoders that can fail halfway
+pub fn decode_threaded_multiline_result<F>(width: usize, height: usize, lines: usize, dummy: bool, closure: &F) -> Result<Vec<u16>,String>
+  where F : Fn(&mut [u16], usize) -> Result<(),String>+Sync {
+
+  let mut out: Vec<u16> = alloc_image_ok!(width, height, dummy);
+  out.par_chunks_mut(width*lines).enumerate().try_for_each(|(row, line)| {
+    closure(line, row*lines)
+  })?;
+  Ok(out)
+}
+
 #[derive(Debug, Clone)]
 pub struct LookupTable {
   table: Vec<(u16, u16, u16)>,
@@ -0,0 +1,83 @@
+use std::f32::NAN;
+
+use crate::decoders::*;
+use crate::decoders::basics::*;
+use crate::decoders::cfa::*;
+
+// The raw block is a fixed size header followed by the CSI-2 packed image data
+const BRCM_HEADER_SIZE: usize = 32768;
+// Sizes of the whole raw block for the OV5647, IMX219 and IMX477 sensors
+const BRCM_BLOCK_SIZES: [usize;3] = [6404096, 10270208, 18711040];
+
+// Raspberry Pi JPEG+RAW files are a normal JPEG with the raw data appended at the end in a
+// block starting with "BRCM". Nothing in the JPEG points to it so check the known block
+// sizes from the end and otherwise look for the block right after a JPEG end marker.
+pub fn find_brcm(buf: &[u8]) -> Option<usize> {
+  if buf.len() < BRCM_HEADER_SIZE || buf[0] != 0xFF || buf[1] != 0xD8 {
+    return None
+  }
+  let is_block = |pos: usize| buf[pos..pos+4] == b"BRCM"[..];
+  BRCM_BLOCK_SIZES.iter().filter(|&&size| size <= buf.len()).map(|size| buf.len() - size).find(|&pos| is_block(pos))
+    .or_else(|| {
+      (2..buf.len()-BRCM_HEADER_SIZE).find(|&pos| buf[pos-2] == 0xFF && buf[pos-1] == 0xD9 && is_block(pos))
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct BrcmDecoder<'a> {
+  buffer: &'a [u8],
+  offset: usize,
+  rawhide: &'a RawHide,
+}
+
+impl<'a> BrcmDecoder<'a> {
+  pub fn new(buf: &'a [u8], offset: usize, rawhide: &'a RawHide) -> BrcmDecoder<'a> {
+    BrcmDecoder {
+      buffer: buf,
+      offset: offset,
+      rawhide: rawhide,
+    }
+  }
+}
+
+impl<'a> Decoder for BrcmDecoder<'a> {
+  fn image(&self, dummy: bool) -> Result<RawImage,String> {
+    let buf = self.buffer;
+    let header = self.offset + 176;
+    let model = String::from_utf8_lossy(&buf[header..header+32]).split_terminator("\0").next().unwrap_or("").trim().to_lowercase();
+    let width = LEu16(buf, header+32) as usize;
+    let height = LEu16(buf, header+34) as usize;
+    let padding_right = LEu16(buf, header+36) as usize;
+    let padding_down = LEu16(buf, header+38) as usize;
+    let bayer_order = buf[header+68];
+
+    // Rows are padded to a multiple of 32 bytes and the row count to a multiple of 16 on
+    // top of the padding in the header. The bit depth isn't stored anywhere obvious so
+    // use the deepest one whose padded image fits in the data.
+    let start = self.offset + BRCM_HEADER_SIZE;
+    let rows = (height+padding_down+15)/16*16;
+    let stride_for = |bps: usize| ((width+padding_right)*bps/8 + 31)/32*32;
+    let bps = match [12, 10].iter().find(|&&bps| stride_for(bps) * rows <= buf.len() - start) {
+      Some(bps) => *bps,
+      None => return Err(format!("BRCM: Couldn't figure out the bit depth of a {}x{} image", width, height)),
+    };
+    let stride = stride_for(bps);
+
+    let mut camera = self.rawhide.check_supported_with_everything("RaspberryPi", &model, "")?;
+    camera.cfa = CFA::new(match bayer_order {
+      0 => "RGGB",
+      1 => "GBRG",
+      2 => "BGGR",
+      3 => "GRBG",
+      o => return Err(format!("BRCM: Unknown bayer order {}", o)),
+    });
+
+    let src = &buf[start..];
+    let image = match bps {
+      10 => decode_10_csi2(src, width, height, stride, dummy),
+      _  => decode_12_csi2(src, width, height, stride, dummy),
+    };
+
+    ok_image(camera, width, height, [NAN,NAN,NAN,NAN], image)
+  }
+}
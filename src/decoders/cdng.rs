@@ -0,0 +1,190 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::panic;
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+
+use crate::decoders::*;
+use crate::decoders::basics::*;
+use crate::decoders::tiff::*;
+use crate::decoders::dng::*;
+
+/// A CinemaDNG clip, a directory of DNG files that are each one frame of the video. The
+/// metadata is read once from the first frame and shared by all the others so decoding
+/// a frame only needs to decode its image data.
+#[derive(Debug, Clone)]
+pub struct DngSequence<'a> {
+  rawhide: &'a RawHide,
+  paths: Vec<PathBuf>,
+  // First frame decoded as a dummy, everything but the image data is reused
+  template: RawImage,
+}
+
+impl<'a> DngSequence<'a> {
+  #[doc(hidden)] pub fn new(dir: &Path, rawhide: &'a RawHide) -> Result<DngSequence<'a>, String> {
+    let entries = match fs::read_dir(dir) {
+      Ok(val) => val,
+      Err(e) => return Err(e.to_string()),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+      let path = match entry {
+        Ok(val) => val.path(),
+        Err(e) => return Err(e.to_string()),
+      };
+      let is_dng = path.extension().map(|ext| ext.to_string_lossy().eq_ignore_ascii_case("dng")).unwrap_or(false);
+      if is_dng && path.is_file() {
+        paths.push(path);
+      }
+    }
+    if paths.is_empty() {
+      return Err(format!("CinemaDNG: no DNG files found in {}", dir.display()))
+    }
+    // Put the frames in recording order by timecode when all of them have one, otherwise
+    // rely on CinemaDNG file names ending in the zero padded frame number
+    paths.sort();
+    let timecodes: Vec<Option<TimeCode>> = paths.par_iter().map(|path| Self::read_timecode(path)).collect();
+    if let Some(timecodes) = timecodes.into_iter().collect::<Option<Vec<TimeCode>>>() {
+      // Count from the first file by name within half a day either way so that clips
+      // recorded across midnight stay in order
+      let secs = |tc: &TimeCode| tc.hours as i64 * 3600 + tc.minutes as i64 * 60 + tc.seconds as i64;
+      let start = secs(&timecodes[0]);
+      let relative = |tc: &TimeCode| {
+        let secs = (secs(tc) - start).rem_euclid(86400);
+        if secs >= 43200 { secs - 86400 } else { secs }
+      };
+      let mut frames: Vec<(PathBuf, TimeCode)> = paths.into_iter().zip(timecodes.into_iter()).collect();
+      frames.sort_by_key(|(_, tc)| (relative(tc), tc.frames));
+      paths = frames.into_iter().map(|(path, _)| path).collect();
+    }
+
+    let template = Self::open(&paths[0], rawhide, |decoder| decoder.image(true))?;
+
+    Ok(DngSequence {
+      rawhide: rawhide,
+      paths: paths,
+      template: template,
+    })
+  }
+
+  fn open<F, T>(path: &Path, rawhide: &RawHide, closure: F) -> Result<T, String>
+    where F: FnOnce(&DngDecoder) -> Result<T, String> {
+    let file = match File::open(path) {
+      Ok(val) => val,
+      Err(e) => return Err(e.to_string()),
+    };
+    let buffer = Buffer::new(&mut BufReader::new(file))?;
+    let tiff = TiffIFD::new_file(&buffer.buf)?;
+    if !tiff.has_entry(Tag::DNGVersion) {
+      return Err(format!("CinemaDNG: {} is not a DNG", path.display()))
+    }
+    closure(&DngDecoder::new(&buffer.buf, tiff, rawhide))
+  }
+
+  // Read just the TimeCodes tag from the first IFD instead of loading the whole frame
+  fn read_timecode(path: &Path) -> Option<TimeCode> {
+    let mut file = File::open(path).ok()?;
+    let mut read_at = |pos: u64, len: usize| -> Option<Vec<u8>> {
+      let mut data = vec![0u8; len];
+      file.seek(SeekFrom::Start(pos)).ok()?;
+      file.read_exact(&mut data).ok()?;
+      Some(data)
+    };
+    let header = read_at(0, 8)?;
+    let little_endian = match &header[0..2] {
+      b"II" => true,
+      b"MM" => false,
+      _ => return None,
+    };
+    let get_u16 = |buf: &[u8], pos: usize| if little_endian { LEu16(buf, pos) } else { BEu16(buf, pos) };
+    let get_u32 = |buf: &[u8], pos: usize| if little_endian { LEu32(buf, pos) } else { BEu32(buf, pos) };
+
+    let ifd = get_u32(&header, 4) as u64;
+    let count = get_u16(&read_at(ifd, 2)?, 0) as usize;
+    let entries = read_at(ifd + 2, count * 12)?;
+    let entry = entries.chunks(12).find(|entry| get_u16(entry, 0) == Tag::TimeCodes as u16 && get_u32(entry, 4) >= 8)?;
+    // 8 bytes don't fit in the entry so the value is always at an offset
+    let data = read_at(get_u32(entry, 8) as u64, 8)?;
+    Some(TimeCode::from_smpte(&data))
+  }
+
+  /// Number of frames in the sequence
+  pub fn frame_count(&self) -> usize {
+    self.paths.len()
+  }
+
+  /// File each frame is stored in
+  pub fn path(&self, frame: usize) -> Option<&Path> {
+    self.paths.get(frame).map(|p| p.as_path())
+  }
+
+  /// Frame rate of the clip, if recorded
+  pub fn frame_rate(&self) -> Option<f32> {
+    self.template.frame_info.map(|info| info.fps).filter(|fps| fps.is_finite())
+  }
+
+  /// Timecode of the first frame of the clip, if recorded
+  pub fn start_timecode(&self) -> Option<TimeCode> {
+    self.template.frame_info.and_then(|info| info.timecode)
+  }
+
+  /// Decodes a single frame. The image metadata is the one of the first frame except for
+  /// the white balance and `frame_info` that are read from the frame itself.
+  pub fn decode_frame(&self, frame: usize) -> Result<RawImage, String> {
+    match panic::catch_unwind(|| {
+      self.decode_frame_unsafe(frame)
+    }) {
+      Ok(val) => val,
+      Err(_) => Err(format!("Caught a panic while decoding.{}", BUG).to_string()),
+    }
+  }
+
+  /// Decodes a range of frames in parallel
+  pub fn decode_frames(&self, frames: Range<usize>) -> Vec<Result<RawImage, String>> {
+    frames.into_par_iter().map(|frame| self.decode_frame(frame)).collect()
+  }
+
+  fn decode_frame_unsafe(&self, frame: usize) -> Result<RawImage, String> {
+    let path = match self.paths.get(frame) {
+      Some(path) => path,
+      None => return Err(format!("CinemaDNG: Couldn't find frame {}", frame)),
+    };
+    let (width, height, cpp, data, wb, info) = Self::open(path, self.rawhide, |decoder| {
+      let (width, height, cpp, data) = decoder.image_data(false)?;
+      Ok((width, height, cpp, data, decoder.get_wb()?, decoder.get_frame_info()))
+    })?;
+    let template = &self.template;
+    if width != template.width || height != template.height || cpp != template.cpp {
+      return Err(format!("CinemaDNG: frame {} is {}x{}x{} but the sequence is {}x{}x{}",
+                         frame, width, height, cpp, template.width, template.height, template.cpp))
+    }
+
+    let fps = info.or(template.frame_info).map(|info| info.fps).unwrap_or(std::f32::NAN);
+    Ok(RawImage {
+      data: data,
+      // Only the first frame's masks would be in the template and as dummies
+      semantic_masks: Vec::new(),
+      wb_coeffs: wb,
+      frame_info: Some(FrameInfo {
+        number: frame,
+        // Without timecodes the frame rate is all there is to place the frame in time
+        timestamp: match (info.and_then(|info| info.timecode), self.start_timecode()) {
+          (Some(tc), Some(start)) => {
+            let secs = tc.to_seconds(fps) - start.to_seconds(fps);
+            // Recordings can go past midnight
+            if secs < 0.0 { secs + 86400.0 } else { secs }
+          },
+          _ => frame as f64 / fps as f64,
+        },
+        exposure_time: info.map(|info| info.exposure_time).unwrap_or(std::f32::NAN),
+        iso: info.map(|info| info.iso).unwrap_or(0),
+        fps: fps,
+        timecode: info.and_then(|info| info.timecode),
+        utc_time: None,
+      }),
+      ..template.clone()
+    })
+  }
+}
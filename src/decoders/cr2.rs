@@ -1,4 +1,6 @@
+use std::cmp;
 use std::f32::NAN;
+use std::ptr;
 
 use crate::decoders::*;
 use crate::decoders::tiff::*;
@@ -24,10 +26,21 @@ impl<'a> Cr2Decoder<'a> {
 
 impl<'a> Decoder for Cr2Decoder<'a> {
   fn image(&self, dummy: bool) -> Result<RawImage,String> {
+    self.frame(0, dummy)
+  }
+
+  fn frame_count(&self) -> usize {
+    cmp::max(1, self.raw_ifds().len())
+  }
+
+  fn frame(&self, frame: usize, dummy: bool) -> Result<RawImage,String> {
     let camera = self.rawhide.check_supported(&self.tiff)?;
+    let raws = self.raw_ifds();
     let (raw, offset) = {
-      if let Some(raw) = self.tiff.find_first_ifd(Tag::Cr2Id) {
-        (raw, fetch_tag!(raw, Tag::StripOffsets).get_usize(0))
+      if let Some(raw) = raws.get(frame) {
+        (*raw, fetch_tag!(raw, Tag::StripOffsets).get_usize(0))
+      } else if frame > 0 {
+        return Err(format!("CR2: Couldn't find frame {}", frame))
       } else if let Some(raw) = self.tiff.find_first_ifd(Tag::CFAPattern) {
         (raw, fetch_tag!(raw, Tag::StripOffsets).get_usize(0))
       } else if let Some(off) = self.tiff.find_entry(Tag::Cr2OldOffset) {
@@ -159,6 +172,17 @@ impl<'a> Decoder for Cr2Decoder<'a> {
 }
 
 impl<'a> Cr2Decoder<'a> {
+  // Dual Pixel RAW files have a second raw IFD with the A-only frame after the main one
+  fn raw_ifds(&self) -> Vec<&TiffIFD> {
+    let mut raws: Vec<&TiffIFD> = Vec::new();
+    for ifd in self.tiff.find_ifds_with_tag(Tag::Cr2Id) {
+      if !raws.iter().any(|r| ptr::eq(*r, ifd)) && ifd.has_entry(Tag::StripOffsets) {
+        raws.push(ifd);
+      }
+    }
+    raws
+  }
+
   fn get_wb(&self, cam: &Camera) -> Result<[f32;4], String> {
     if let Some(levels) = self.tiff.find_entry(Tag::Cr2ColorData) {
       let offset = if cam.wb_offset != 0 {cam.wb_offset} else {63};
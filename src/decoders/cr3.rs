@@ -0,0 +1,1144 @@
+use std::f32::NAN;
+use std::cmp;
+use rayon::prelude::*;
+
+use crate::decoders::*;
+use crate::decoders::tiff::*;
+use crate::decoders::basics::*;
+
+const CANON_UUID: [u8;16] = [0x85,0xc0,0xb6,0x87,0x82,0x0f,0x11,0xe0,0x81,0x11,0xf4,0xce,0x46,0x2b,0x6a,0x48];
+
+// Run length tables for the adaptive run mode of the CRX entropy coder
+const JS: [usize;32] = [1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 8, 8, 8, 8,
+                        0x10, 0x10, 0x20, 0x20, 0x40, 0x40, 0x80, 0x80,
+                        0x100, 0x200, 0x400, 0x800, 0x1000, 0x2000, 0x4000, 0x8000];
+const J: [u32;32] = [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3,
+                     4, 4, 5, 5, 6, 6, 7, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+const Q_STEP_TBL: [i32;6] = [0x28, 0x2D, 0x33, 0x39, 0x40, 0x48];
+
+pub fn is_cr3(buf: &[u8]) -> bool {
+  buf[4..8] == b"ftyp"[..] && buf[8..12] == b"crx "[..]
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CrxHeader {
+  version: u16,
+  width: usize,
+  height: usize,
+  tile_width: usize,
+  tile_height: usize,
+  nbits: u32,
+  nplanes: usize,
+  cfa_layout: usize,
+  enc_type: u32,
+  levels: usize,
+  mdat_hdr_size: usize,
+  median_bits: u32,
+}
+
+impl CrxHeader {
+  fn new(data: &[u8]) -> Result<CrxHeader, String> {
+    if data.len() < 40 {
+      return Err("CR3: CMP1 box too short".to_string())
+    }
+    let nplanes = (data[25] >> 4) as usize;
+    let nbits = data[24] as u32;
+    let ext_header = data[32] >> 7 == 1;
+    let use_median_bits = ext_header && data.len() > 56 && nplanes == 4 && (data[56] >> 6) & 1 == 1;
+    let median_bits = if use_median_bits && data.len() > 84 { data[84] as u32 } else { nbits };
+
+    let hdr = CrxHeader {
+      version: BEu16(data, 4),
+      width: BEu32(data, 8) as usize,
+      height: BEu32(data, 12) as usize,
+      tile_width: BEu32(data, 16) as usize,
+      tile_height: BEu32(data, 20) as usize,
+      nbits: nbits,
+      nplanes: nplanes,
+      cfa_layout: (data[25] & 0xf) as usize,
+      enc_type: (data[26] >> 4) as u32,
+      levels: (data[26] & 0xf) as usize,
+      mdat_hdr_size: BEu32(data, 28) as usize,
+      median_bits: median_bits,
+    };
+
+    if (hdr.version != 0x100 && hdr.version != 0x200) || hdr.mdat_hdr_size == 0 {
+      return Err(format!("CR3: unknown CRX version 0x{:x}", hdr.version).to_string())
+    }
+    if hdr.nplanes != 4 || hdr.cfa_layout > 3 || hdr.nbits <= 8 || hdr.nbits > 15 ||
+       hdr.width & 1 != 0 || hdr.height & 1 != 0 ||
+       hdr.tile_width & 1 != 0 || hdr.tile_height & 1 != 0 ||
+       hdr.tile_width > hdr.width || hdr.tile_height > hdr.height || hdr.levels > 3 {
+      return Err("CR3: unsupported CRX image layout".to_string())
+    }
+    if hdr.enc_type != 0 && hdr.enc_type != 3 {
+      return Err(format!("CR3: unsupported CRX encoding type {}", hdr.enc_type).to_string())
+    }
+    if hdr.enc_type == 3 && (hdr.median_bits == 0 || hdr.median_bits > 16) {
+      return Err("CR3: invalid median bits".to_string())
+    }
+    Ok(hdr)
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cr3Track {
+  header: Option<CrxHeader>,
+  offset: usize,
+  size: usize,
+}
+
+#[derive(Debug, Clone)]
+struct CrxSubband {
+  offset: usize,
+  size: usize,
+  width: usize,
+  height: usize,
+  supports_partial: bool,
+  qparam: i32,
+}
+
+#[derive(Debug, Clone)]
+struct CrxComponent {
+  supports_partial: bool,
+  rounded_bits_mask: i32,
+  subbands: Vec<CrxSubband>,
+}
+
+// Which sides of a tile have other tiles, the wavelet bands overlap into those
+#[derive(Debug, Clone, Copy)]
+struct CrxNeighbors {
+  left: bool,
+  right: bool,
+  top: bool,
+  bottom: bool,
+}
+
+#[derive(Debug, Clone)]
+struct CrxTile {
+  row: usize,
+  col: usize,
+  width: usize,
+  height: usize,
+  neighbors: CrxNeighbors,
+  components: Vec<CrxComponent>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cr3Decoder<'a> {
+  buffer: &'a [u8],
+  rawhide: &'a RawHide,
+  tiff: TiffIFD<'a>,
+  makernote: Option<TiffIFD<'a>>,
+  tracks: Vec<Cr3Track>,
+}
+
+#[derive(Debug, Default)]
+struct Cr3Boxes<'a> {
+  cmt1: Option<TiffIFD<'a>>,
+  cmt3: Option<TiffIFD<'a>>,
+  tracks: Vec<Cr3Track>,
+}
+
+fn parse_boxes<'a>(buf: &'a [u8], start: usize, end: usize, depth: u32, boxes: &mut Cr3Boxes<'a>) -> Result<(), String> {
+  if depth > 10 {
+    return Err("CR3: boxes nested too deep".to_string())
+  }
+
+  let mut pos = start;
+  while pos + 8 <= end {
+    let mut size = BEu32(buf, pos) as usize;
+    let mut header = 8;
+    if size == 1 {
+      size = ((BEu32(buf, pos+8) as u64) << 32 | BEu32(buf, pos+12) as u64) as usize;
+      header = 16;
+    } else if size == 0 {
+      size = end - pos;
+    }
+    if size < header || size > end - pos {
+      return Err("CR3: invalid box size".to_string())
+    }
+    let content = pos + header;
+    let box_end = pos + size;
+
+    match &buf[pos+4..pos+8] {
+      b"moov" | b"mdia" | b"minf" | b"stbl" => {
+        parse_boxes(buf, content, box_end, depth+1, boxes)?;
+      },
+      b"trak" => {
+        boxes.tracks.push(Cr3Track { header: None, offset: 0, size: 0 });
+        parse_boxes(buf, content, box_end, depth+1, boxes)?;
+      },
+      b"uuid" => {
+        if box_end - content >= 16 && buf[content..content+16] == CANON_UUID[..] {
+          parse_boxes(buf, content+16, box_end, depth+1, boxes)?;
+        }
+      },
+      b"CMT1" => { boxes.cmt1 = Some(TiffIFD::new_root(buf, content)?); },
+      b"CMT3" => { boxes.cmt3 = Some(TiffIFD::new_root(buf, content)?); },
+      b"stsd" => {
+        // Skip the version/flags and the entry count
+        parse_boxes(buf, content+8, box_end, depth+1, boxes)?;
+      },
+      b"CRAW" => {
+        // Skip the visual sample entry fields that come before the child boxes
+        parse_boxes(buf, content+82, box_end, depth+1, boxes)?;
+      },
+      b"CMP1" => {
+        if let Some(track) = boxes.tracks.last_mut() {
+          track.header = CrxHeader::new(&buf[content..box_end]).ok();
+        }
+      },
+      b"stsz" => {
+        if let Some(track) = boxes.tracks.last_mut() {
+          if box_end - content < 12 {
+            return Err("CR3: stsz box too short".to_string())
+          }
+          let sample_size = BEu32(buf, content+4) as usize;
+          track.size = if sample_size != 0 {
+            sample_size
+          } else if box_end - content >= 16 {
+            BEu32(buf, content+12) as usize
+          } else {
+            return Err("CR3: stsz box too short".to_string())
+          };
+        }
+      },
+      b"co64" => {
+        if let Some(track) = boxes.tracks.last_mut() {
+          if box_end - content < 16 {
+            return Err("CR3: co64 box too short".to_string())
+          }
+          track.offset = ((BEu32(buf, content+8) as u64) << 32 | BEu32(buf, content+12) as u64) as usize;
+        }
+      },
+      _ => {},
+    }
+    pos = box_end;
+  }
+  Ok(())
+}
+
+impl<'a> Cr3Decoder<'a> {
+  pub fn new(buf: &'a [u8], rawhide: &'a RawHide) -> Result<Cr3Decoder<'a>, String> {
+    let mut boxes = Cr3Boxes::default();
+    parse_boxes(buf, 0, buf.len(), 0, &mut boxes)?;
+
+    let tiff = match boxes.cmt1 {
+      Some(tiff) => tiff,
+      None => return Err("CR3: Couldn't find the CMT1 metadata box".to_string()),
+    };
+
+    // The file has several tracks (JPEG preview, small preview, raw, metadata),
+    // pick the largest ones that have a valid CRX header. Dual Pixel files have a
+    // second full size raw track with the A-only frame.
+    let mut tracks: Vec<Cr3Track> = Vec::new();
+    for t in boxes.tracks {
+      if let Some(hdr) = t.header {
+        if t.offset == 0 || t.size == 0 || t.offset + t.size > buf.len() || hdr.mdat_hdr_size > t.size {
+          continue
+        }
+        let (larger, same) = match tracks.first() {
+          Some(prev) => {
+            let phdr = prev.header.unwrap();
+            (hdr.width > phdr.width, hdr.width == phdr.width && hdr.height == phdr.height)
+          },
+          None => (true, false),
+        };
+        if larger {
+          tracks = vec![t];
+        } else if same {
+          tracks.push(t);
+        }
+      }
+    }
+    if tracks.is_empty() {
+      return Err("CR3: Couldn't find a raw track".to_string())
+    }
+
+    Ok(Cr3Decoder {
+      buffer: buf,
+      rawhide: rawhide,
+      tiff: tiff,
+      makernote: boxes.cmt3,
+      tracks: tracks,
+    })
+  }
+}
+
+impl<'a> Decoder for Cr3Decoder<'a> {
+  fn image(&self, dummy: bool) -> Result<RawImage,String> {
+    self.frame(0, dummy)
+  }
+
+  fn frame_count(&self) -> usize {
+    self.tracks.len()
+  }
+
+  fn frame(&self, frame: usize, dummy: bool) -> Result<RawImage,String> {
+    let track = match self.tracks.get(frame) {
+      Some(track) => track,
+      None => return Err(format!("CR3: Couldn't find frame {}", frame)),
+    };
+    let mut camera = self.rawhide.check_supported(&self.tiff)?;
+    let hdr = track.header.unwrap();
+    let width = hdr.width;
+    let height = hdr.height;
+
+    if let Some(ref makernote) = self.makernote {
+      // SensorInfo has the inclusive coordinates of the usable area of the sensor
+      if let Some(info) = makernote.find_entry(Tag::CanonSensorInfo) {
+        if info.count() > 8 {
+          let left = info.get_usize(5);
+          let top = info.get_usize(6);
+          let right = info.get_usize(7);
+          let bottom = info.get_usize(8);
+          if left < right && top < bottom && right < width && bottom < height {
+            camera.crops = [top, width-right-1, height-bottom-1, left];
+          }
+        }
+      }
+    }
+
+    let image = if dummy {
+      vec![0]
+    } else {
+      self.decode_crx(track, &hdr)?
+    };
+
+    let wb = self.get_wb(&camera)?;
+    Ok(RawImage::new(camera, width, height, wb, image, dummy))
+  }
+}
+
+impl<'a> Cr3Decoder<'a> {
+  fn get_wb(&self, cam: &Camera) -> Result<[f32;4], String> {
+    if let Some(ref makernote) = self.makernote {
+      if let Some(levels) = makernote.find_entry(Tag::Cr2ColorData) {
+        let offset = if cam.wb_offset != 0 {cam.wb_offset} else {63};
+        return Ok([levels.get_force_u16(offset) as f32, levels.get_force_u16(offset+1) as f32,
+                   levels.get_force_u16(offset+3) as f32, NAN])
+      }
+    }
+    Ok([NAN,NAN,NAN,NAN])
+  }
+
+  fn decode_crx(&self, track: &Cr3Track, hdr: &CrxHeader) -> Result<Vec<u16>, String> {
+    let pwidth = hdr.width / 2;
+    let pheight = hdr.height / 2;
+    let tiles = self.parse_tiles(track, hdr)?;
+
+    let planes = (0..hdr.nplanes).into_par_iter().map(|plane| {
+      let mut out = vec![0 as i32; pwidth*pheight];
+      for tile in &tiles {
+        let data = decode_tile_plane(self.buffer, hdr, tile, &tile.components[plane])?;
+        for row in 0..tile.height {
+          let start = (tile.row+row)*pwidth + tile.col;
+          out[start..start+tile.width].copy_from_slice(&data[row*tile.width..(row+1)*tile.width]);
+        }
+      }
+      Ok(out)
+    }).collect::<Result<Vec<Vec<i32>>, String>>()?;
+
+    let layout = hdr.cfa_layout;
+    let median = 1 << (hdr.nbits - 1);
+    let maxval = (1 << hdr.nbits) - 1;
+    let ymedian = (1 << (hdr.median_bits - 1)) << 10;
+    let ymaxval = (1 << hdr.median_bits) - 1;
+    Ok(decode_threaded(hdr.width, hdr.height, false, &(|out: &mut [u16], row| {
+      let pline = (row >> 1)*pwidth;
+      for pcol in 0..pwidth {
+        let vals = if hdr.enc_type == 3 {
+          // Planes are stored in a luma/chroma like space that needs converting back to RGGB
+          let p0 = planes[0][pline+pcol];
+          let p1 = planes[1][pline+pcol];
+          let p2 = planes[2][pline+pcol];
+          let p3 = planes[3][pline+pcol];
+
+          let gr = ymedian + (p0 << 10) - 168 * p1 - 585 * p3;
+          let gr = if gr < 0 {
+            -(((gr.abs() + 512) >> 9) & !1)
+          } else {
+            ((gr.abs() + 512) >> 9) & !1
+          };
+          [cmp::min(cmp::max((ymedian + (p0 << 10) + 1510 * p3 + 512) >> 10, 0), ymaxval),
+           cmp::min(cmp::max((p2 + gr + 1) >> 1, 0), ymaxval),
+           cmp::min(cmp::max((gr - p2 + 1) >> 1, 0), ymaxval),
+           cmp::min(cmp::max((ymedian + (p0 << 10) + 1927 * p1 + 512) >> 10, 0), ymaxval)]
+        } else {
+          let mut vals = [0 as i32;4];
+          for plane in 0..4 {
+            vals[plane] = cmp::min(cmp::max(median + planes[plane][pline+pcol], 0), maxval);
+          }
+          vals
+        };
+        for plane in 0..4 {
+          // The CFA layout says which plane lands in which position of the 2x2 block
+          let pos = plane ^ layout;
+          if pos >> 1 == row & 1 {
+            out[pcol*2 + (pos & 1)] = vals[plane] as u16;
+          }
+        }
+      }
+    })))
+  }
+
+  fn parse_tiles(&self, track: &Cr3Track, hdr: &CrxHeader) -> Result<Vec<CrxTile>, String> {
+    let pwidth = hdr.width / 2;
+    let pheight = hdr.height / 2;
+    let twidth = hdr.tile_width / 2;
+    let theight = hdr.tile_height / 2;
+    if twidth < 0x16 || theight < 0x16 || pwidth > 0x7fff || pheight > 0x7fff {
+      return Err("CR3: invalid tile sizes".to_string())
+    }
+    let tile_cols = (pwidth + twidth - 1) / twidth;
+    let tile_rows = (pheight + theight - 1) / theight;
+    if tile_cols > 0xff || tile_rows > 0xff ||
+       pwidth - twidth*(tile_cols-1) < 0x16 || pheight - theight*(tile_rows-1) < 0x16 {
+      return Err("CR3: invalid tile sizes".to_string())
+    }
+    let nbands = 3*hdr.levels + 1;
+
+    let data = &self.buffer[track.offset..track.offset+hdr.mdat_hdr_size];
+    let mut pos = 0;
+    let mut tile_offset = track.offset + hdr.mdat_hdr_size;
+    let mut tiles = Vec::new();
+
+    for tnum in 0..tile_cols*tile_rows {
+      let (trow, tcol) = (tnum / tile_cols, tnum % tile_cols);
+      let width = if tcol == tile_cols-1 { pwidth - twidth*(tile_cols-1) } else { twidth };
+      let height = if trow == tile_rows-1 { pheight - theight*(tile_rows-1) } else { theight };
+      let neighbors = CrxNeighbors {
+        left: tcol > 0,
+        right: tcol < tile_cols-1,
+        top: trow > 0,
+        bottom: trow < tile_rows-1,
+      };
+
+      if data.len() < pos + 12 {
+        return Err("CR3: tile header truncated".to_string())
+      }
+      let sign = BEu16(data, pos);
+      let size = BEu16(data, pos+2) as usize;
+      if (sign != 0xFF01 && sign != 0xFF11) || size != 8 || BEu16(data, pos+8) as usize != tnum {
+        return Err("CR3: invalid tile header".to_string())
+      }
+      if BEu16(data, pos+10) != 0 {
+        return Err("CR3: tiles with quantization tables are not supported".to_string())
+      }
+      let tile_size = BEu32(data, pos+4) as usize;
+      pos += 12;
+
+      let mut comp_offset = tile_offset;
+      let mut components = Vec::new();
+      for cnum in 0..hdr.nplanes {
+        if data.len() < pos + 12 {
+          return Err("CR3: plane header truncated".to_string())
+        }
+        let sign = BEu16(data, pos);
+        let size = BEu16(data, pos+2);
+        if (sign != 0xFF02 && sign != 0xFF12) || size != 8 || (data[pos+8] >> 4) as usize != cnum ||
+           data[pos+9] != 0 || data[pos+10] != 0 || data[pos+11] != 0 {
+          return Err("CR3: invalid plane header".to_string())
+        }
+        let comp_size = BEu32(data, pos+4) as usize;
+        let supports_partial = data[pos+8] & 8 != 0;
+        let rounded_bits = ((data[pos+8] >> 1) & 3) as i32;
+        let rounded_bits_mask = if rounded_bits > 0 {
+          if hdr.levels > 0 || !supports_partial {
+            return Err("CR3: invalid plane rounding".to_string())
+          }
+          1 << (rounded_bits - 1)
+        } else { 0 };
+        pos += 12;
+
+        let dims = subband_dims(width, height, hdr.levels, neighbors);
+        let mut band_offset = comp_offset;
+        let mut subbands = Vec::new();
+        for bnum in 0..nbands {
+          if data.len() < pos + 4 {
+            return Err("CR3: subband header truncated".to_string())
+          }
+          let sign = BEu16(data, pos);
+          let size = BEu16(data, pos+2) as usize;
+          if !((sign == 0xFF03 && size == 8) || (sign == 0xFF13 && size == 16)) ||
+             data.len() < pos + size + 4 || (data[pos+8] >> 4) as usize != bnum {
+            return Err("CR3: invalid subband header".to_string())
+          }
+          let band_size = BEu32(data, pos+4) as usize;
+          if band_offset + band_size > self.buffer.len() {
+            return Err("CR3: subband data goes beyond the end of the file".to_string())
+          }
+          let (datasize, partial, qparam) = if sign == 0xFF03 {
+            let bits = BEu32(data, pos+8);
+            (band_size.saturating_sub((bits & 0x7ffff) as usize), bits & 0x8000000 != 0, ((bits >> 19) & 0xff) as i32)
+          } else {
+            if hdr.levels > 0 {
+              return Err("CR3: subbands with quantization steps are not supported".to_string())
+            }
+            (band_size.saturating_sub(BEu16(data, pos+16) as usize), false, 0)
+          };
+          subbands.push(CrxSubband {
+            offset: band_offset,
+            size: datasize,
+            width: dims[bnum].0,
+            height: dims[bnum].1,
+            supports_partial: partial,
+            qparam: qparam,
+          });
+          band_offset += band_size;
+          pos += size + 4;
+        }
+
+        components.push(CrxComponent {
+          supports_partial: supports_partial,
+          rounded_bits_mask: rounded_bits_mask,
+          subbands: subbands,
+        });
+        comp_offset += comp_size;
+      }
+
+      tiles.push(CrxTile {
+        row: trow*theight,
+        col: tcol*twidth,
+        width: width,
+        height: height,
+        neighbors: neighbors,
+        components: components,
+      });
+      tile_offset += tile_size;
+    }
+
+    if tile_offset > track.offset + track.size || tile_offset > self.buffer.len() {
+      return Err("CR3: tile data goes beyond the end of the file".to_string())
+    }
+
+    Ok(tiles)
+  }
+}
+
+// Number of lowpass and highpass coefficients of each level along one side of a tile,
+// from the smallest level up. Next to another tile the bands also store the coefficients
+// the inverse transform needs from it: one highpass coefficient before the start, and
+// after the end enough of both to reconstruct the last sample without extending the edge.
+fn band_sizes(size: usize, levels: usize, before: bool, after: bool) -> Vec<(usize,usize)> {
+  let mut sizes = Vec::with_capacity(levels);
+  let mut n = size;
+  for _ in 0..levels {
+    let (low, high) = if after { (n/2 + 1, n/2 + 1) } else { ((n+1)/2, n/2) };
+    sizes.push((low, high + before as usize));
+    n = low;
+  }
+  sizes.reverse();
+  sizes
+}
+
+// Dimensions of each subband in decoding order, first the lowpass band of the
+// smallest level and then the HL/LH/HH bands of each level
+fn subband_dims(width: usize, height: usize, levels: usize, neighbors: CrxNeighbors) -> Vec<(usize,usize)> {
+  let xs = band_sizes(width, levels, neighbors.left, neighbors.right);
+  let ys = band_sizes(height, levels, neighbors.top, neighbors.bottom);
+  let mut dims = vec![(width, height); 3*levels+1];
+  for level in 0..levels {
+    dims[3*level+1] = (xs[level].1, ys[level].0);
+    dims[3*level+2] = (xs[level].0, ys[level].1);
+    dims[3*level+3] = (xs[level].1, ys[level].1);
+  }
+  if levels > 0 {
+    dims[0] = (xs[0].0, ys[0].0);
+  }
+  dims
+}
+
+fn band_data<'a>(buf: &'a [u8], band: &CrxSubband) -> Result<&'a [u8], String> {
+  if band.offset + band.size > buf.len() {
+    return Err("CR3: subband data goes beyond the end of the file".to_string())
+  }
+  Ok(&buf[band.offset..])
+}
+
+fn decode_tile_plane(buf: &[u8], hdr: &CrxHeader, tile: &CrxTile, comp: &CrxComponent) -> Result<Vec<i32>, String> {
+  if hdr.levels == 0 {
+    let band = &comp.subbands[0];
+    let mut out = vec![0 as i32; tile.width*tile.height];
+    if band.size > 0 {
+      let mut decoder = CrxBandDecoder::new(band_data(buf, band)?, band.width, comp.supports_partial, comp.rounded_bits_mask);
+      for line in out.chunks_exact_mut(tile.width) {
+        decoder.decode_line(line)?;
+      }
+    }
+    return Ok(out)
+  }
+
+  let mut bands = Vec::with_capacity(comp.subbands.len());
+  for (num, band) in comp.subbands.iter().enumerate() {
+    let mut out = vec![0 as i32; band.width*band.height];
+    if band.size > 0 && band.width > 0 {
+      let mut decoder = CrxBandDecoder::new(band_data(buf, band)?, band.width, comp.supports_partial && num == 0, 0);
+      let mut qparam = band.qparam;
+      let mut qkparam = 0;
+      for line in out.chunks_exact_mut(band.width) {
+        if band.supports_partial {
+          decoder.update_qparam(&mut qparam, &mut qkparam)?;
+        }
+        decoder.decode_line(line)?;
+        if qparam < 0 || qparam > 100 {
+          return Err("CR3: invalid quantization parameter".to_string())
+        }
+        let qscale = if qparam / 6 >= 6 {
+          Q_STEP_TBL[(qparam % 6) as usize] << (qparam/6 - 6)
+        } else {
+          Q_STEP_TBL[(qparam % 6) as usize] >> (6 - qparam/6)
+        };
+        if qscale != 1 {
+          for v in line.iter_mut() {
+            *v *= qscale;
+          }
+        }
+      }
+    }
+    bands.push(out);
+  }
+
+  // Each level reconstructs the lowpass band of the next one, overlap included
+  let n = tile.neighbors;
+  let xs = band_sizes(tile.width, hdr.levels, n.left, n.right);
+  let ys = band_sizes(tile.height, hdr.levels, n.top, n.bottom);
+  let mut out = bands[0].clone();
+  for level in 0..hdr.levels {
+    let (width, height) = if level == hdr.levels-1 {
+      (tile.width, tile.height)
+    } else {
+      (xs[level+1].0, ys[level+1].0)
+    };
+    out = idwt53(&out, &bands[3*level+1], &bands[3*level+2], &bands[3*level+3],
+                 xs[level], ys[level], width, height, n);
+  }
+  Ok(out)
+}
+
+// Inverse of the reversible 5/3 wavelet. The edges are extended symmetrically unless
+// the bands have the coefficients of the neighboring tile, a highpass one before the
+// start and one of each past the end.
+fn idwt53_line(low: &[i32], high: &[i32], out: &mut [i32], before: bool, after: bool) {
+  let n = out.len();
+  if n == 1 {
+    out[0] = low[0];
+    return
+  }
+  let skip = before as isize;
+  let last = high.len() as isize - 1;
+  let h = |i: isize| high[cmp::min(cmp::max(i + skip, 0), last) as usize];
+  let even = |i: usize| low[i] - ((h(i as isize - 1) + h(i as isize) + 2) >> 2);
+  for i in 0..(n+1)/2 {
+    out[2*i] = even(i);
+  }
+  for i in 0..n/2 {
+    let right = if 2*i+2 < n { out[2*i+2] } else if after { even(i+1) } else { out[2*i] };
+    out[2*i+1] = h(i as isize) + ((out[2*i] + right) >> 1);
+  }
+}
+
+fn idwt53(ll: &[i32], hl: &[i32], lh: &[i32], hh: &[i32], xs: (usize, usize), ys: (usize, usize),
+          width: usize, height: usize, neighbors: CrxNeighbors) -> Vec<i32> {
+  let (lwidth, hwidth) = xs;
+  let (lheight, hheight) = ys;
+  let (left, right) = (neighbors.left, neighbors.right);
+
+  // First undo the horizontal transform of the low and high vertical bands
+  let mut lows = vec![0 as i32; width*lheight];
+  for row in 0..lheight {
+    idwt53_line(&ll[row*lwidth..(row+1)*lwidth], &hl[row*hwidth..(row+1)*hwidth],
+                &mut lows[row*width..(row+1)*width], left, right);
+  }
+  let mut highs = vec![0 as i32; width*hheight];
+  for row in 0..hheight {
+    idwt53_line(&lh[row*lwidth..(row+1)*lwidth], &hh[row*hwidth..(row+1)*hwidth],
+                &mut highs[row*width..(row+1)*width], left, right);
+  }
+
+  // And then the vertical one column by column
+  let mut out = vec![0 as i32; width*height];
+  let mut low = vec![0 as i32; lheight];
+  let mut high = vec![0 as i32; hheight];
+  let mut column = vec![0 as i32; height];
+  for col in 0..width {
+    for row in 0..lheight {
+      low[row] = lows[row*width+col];
+    }
+    for row in 0..hheight {
+      high[row] = highs[row*width+col];
+    }
+    idwt53_line(&low, &high, &mut column, neighbors.top, neighbors.bottom);
+    for row in 0..height {
+      out[row*width+col] = column[row];
+    }
+  }
+  out
+}
+
+#[inline(always)]
+fn predict_k(prev: u32, code: u32, max: u32) -> u32 {
+  let prev = cmp::min(prev, 31);
+  let newk = prev + (((code >> prev) > 2) as u32) + (((code >> prev) > 5) as u32) - ((code < ((1 << prev) >> 1)) as u32);
+  if max == 0 || newk < max { newk } else { max }
+}
+
+#[inline(always)]
+fn to_signed(code: u32) -> i32 {
+  -((code & 1) as i32) ^ ((code >> 1) as i32)
+}
+
+// Decodes the lines of a single subband. Each line is coded with an adaptive
+// golomb-rice code and a run mode for flat areas, predicting from the previous
+// line in lossless bands and coding the values directly in the wavelet ones.
+#[derive(Debug, Clone)]
+struct CrxBandDecoder<'a> {
+  pump: BitPumpMSB<'a>,
+  width: usize,
+  supports_partial: bool,
+  rounded_bits_mask: i32,
+  rounded_bits: u32,
+  line: usize,
+  sparam: usize,
+  kparam: u32,
+  prev: Vec<i32>,
+  cur: Vec<i32>,
+  kvals: Vec<i32>,
+}
+
+impl<'a> CrxBandDecoder<'a> {
+  fn new(src: &'a [u8], width: usize, supports_partial: bool, rounded_bits_mask: i32) -> CrxBandDecoder<'a> {
+    CrxBandDecoder {
+      pump: BitPumpMSB::new(src),
+      width: width,
+      supports_partial: supports_partial,
+      rounded_bits_mask: rounded_bits_mask,
+      rounded_bits: 0,
+      line: 0,
+      sparam: 0,
+      kparam: 0,
+      prev: vec![0; width+2],
+      cur: vec![0; width+2],
+      kvals: vec![0; width+1],
+    }
+  }
+
+  fn decode_line(&mut self, out: &mut [i32]) -> Result<(), String> {
+    if self.line == 0 {
+      self.sparam = 0;
+      self.kparam = 0;
+      if !self.supports_partial {
+        self.decode_top_line_noref()?;
+      } else if self.rounded_bits_mask <= 0 {
+        self.decode_top_line()?;
+      } else {
+        self.rounded_bits = 1;
+        while (self.rounded_bits_mask >> self.rounded_bits) != 0 {
+          self.rounded_bits += 1;
+        }
+        self.decode_top_line_rounded()?;
+      }
+    } else if !self.supports_partial {
+      self.decode_line_noref()?;
+    } else if self.rounded_bits_mask <= 0 {
+      self.decode_line_median()?;
+    } else {
+      self.decode_line_rounded()?;
+    }
+
+    out.copy_from_slice(&self.cur[1..self.width+1]);
+    std::mem::swap(&mut self.prev, &mut self.cur);
+    self.line += 1;
+    Ok(())
+  }
+
+  fn update_qparam(&mut self, qparam: &mut i32, qkparam: &mut u32) -> Result<(), String> {
+    let mut code = self.get_zeros();
+    if code >= 23 {
+      code = self.pump.get_bits(8);
+    } else if *qkparam > 0 {
+      code = self.pump.get_bits(*qkparam) | (code << *qkparam);
+    }
+    *qparam += to_signed(code);
+    *qkparam = predict_k(*qkparam, code, 0);
+    if *qparam / 6 >= 6 {
+      return Err("CR3: invalid quantization parameter".to_string())
+    }
+    Ok(())
+  }
+
+  fn get_zeros(&mut self) -> u32 {
+    let mut zeros = 0;
+    loop {
+      let bits = self.pump.peek_bits(32);
+      if bits != 0 {
+        let lz = bits.leading_zeros();
+        self.pump.consume_bits(lz+1);
+        return zeros + lz
+      }
+      self.pump.consume_bits(32);
+      zeros += 32;
+    }
+  }
+
+  fn get_code(&mut self) -> u32 {
+    let code = self.get_zeros();
+    if code >= 41 {
+      self.pump.get_bits(21)
+    } else if self.kparam > 0 {
+      self.pump.get_bits(self.kparam) | (code << self.kparam)
+    } else {
+      code
+    }
+  }
+
+  // Reads the length of a run of repeated values, never more than remaining
+  fn get_run(&mut self, remaining: usize) -> Result<usize, String> {
+    if self.pump.get_bits(1) == 0 {
+      return Ok(0)
+    }
+    let mut nsyms = 1;
+    while self.pump.get_bits(1) == 1 {
+      nsyms += JS[self.sparam];
+      if nsyms > remaining {
+        nsyms = remaining;
+        break
+      }
+      if self.sparam < 31 {
+        self.sparam += 1;
+      }
+      if nsyms == remaining {
+        break
+      }
+    }
+    if nsyms < remaining {
+      if J[self.sparam] > 0 {
+        nsyms += self.pump.get_bits(J[self.sparam]) as usize;
+      }
+      if self.sparam > 0 {
+        self.sparam -= 1;
+      }
+      if nsyms > remaining {
+        return Err("CR3: run goes beyond the end of the line".to_string())
+      }
+    }
+    Ok(nsyms)
+  }
+
+  fn decode_top_line(&mut self) -> Result<(), String> {
+    let mut p = 0;
+    let mut length = self.width;
+    self.cur[0] = 0;
+    while length > 1 {
+      if self.cur[p] != 0 {
+        self.cur[p+1] = self.cur[p];
+      } else {
+        let nsyms = self.get_run(length)?;
+        if nsyms > 0 {
+          length -= nsyms;
+          for _ in 0..nsyms {
+            self.cur[p+1] = self.cur[p];
+            p += 1;
+          }
+          if length == 0 {
+            break
+          }
+        }
+        self.cur[p+1] = 0;
+      }
+      let code = self.get_code();
+      self.cur[p+1] += to_signed(code);
+      self.kparam = predict_k(self.kparam, code, 15);
+      p += 1;
+      length -= 1;
+    }
+    if length == 1 {
+      self.cur[p+1] = self.cur[p];
+      let code = self.get_code();
+      self.cur[p+1] += to_signed(code);
+      self.kparam = predict_k(self.kparam, code, 15);
+      p += 1;
+    }
+    self.cur[p+1] = self.cur[p] + 1;
+    Ok(())
+  }
+
+  fn decode_symbol_median(&mut self, p: &mut usize, q: &mut usize, median: bool, not_eol: bool) {
+    let (pp, qq) = (*p, *q);
+    if median {
+      let left = self.cur[pp];
+      let top = self.prev[qq+1];
+      let topleft = self.prev[qq];
+      let delta = top - topleft;
+      let symb = [delta + left, delta + left, left, top];
+      let idx = ((((topleft < left) ^ (delta < 0)) as usize) << 1) + (((left < top) ^ (delta < 0)) as usize);
+      self.cur[pp+1] = symb[idx];
+    } else {
+      self.cur[pp+1] = self.cur[pp];
+    }
+
+    let mut code = self.get_code();
+    self.cur[pp+1] += to_signed(code);
+
+    // When not at the end of the line use the next value of the previous line
+    // to estimate the next K
+    if not_eol {
+      let next_delta = (self.prev[qq+2] - self.prev[qq+1]) << 1;
+      code = (code + next_delta.abs() as u32) >> 1;
+      *q += 1;
+    }
+    self.kparam = predict_k(self.kparam, code, 15);
+    *p += 1;
+  }
+
+  fn decode_line_median(&mut self) -> Result<(), String> {
+    let (mut p, mut q) = (0, 0);
+    let mut length = self.width;
+    self.cur[0] = self.prev[1];
+    while length > 1 {
+      if self.cur[p] != self.prev[q+1] || self.cur[p] != self.prev[q+2] {
+        self.decode_symbol_median(&mut p, &mut q, true, true);
+      } else {
+        let nsyms = self.get_run(length)?;
+        if nsyms > 0 {
+          length -= nsyms;
+          q += nsyms;
+          for _ in 0..nsyms {
+            self.cur[p+1] = self.cur[p];
+            p += 1;
+          }
+          if length == 0 {
+            break
+          }
+        }
+        self.decode_symbol_median(&mut p, &mut q, false, length > 1);
+      }
+      length -= 1;
+    }
+    if length == 1 {
+      self.decode_symbol_median(&mut p, &mut q, true, false);
+    }
+    self.cur[p+1] = self.cur[p] + 1;
+    Ok(())
+  }
+
+  fn rounded_value(&self, code: u32) -> i32 {
+    let sval = to_signed(code);
+    self.rounded_bits_mask * 2 * sval + (sval >> 31)
+  }
+
+  fn decode_top_line_rounded(&mut self) -> Result<(), String> {
+    let mut p = 0;
+    let mut length = self.width;
+    self.cur[0] = 0;
+    while length > 1 {
+      if self.cur[p].abs() > self.rounded_bits_mask {
+        self.cur[p+1] = self.cur[p];
+      } else {
+        let nsyms = self.get_run(length)?;
+        if nsyms > 0 {
+          length -= nsyms;
+          for _ in 0..nsyms {
+            self.cur[p+1] = self.cur[p];
+            p += 1;
+          }
+          if length == 0 {
+            break
+          }
+        }
+        self.cur[p+1] = 0;
+      }
+      let code = self.get_code();
+      self.cur[p+1] += self.rounded_value(code);
+      self.kparam = predict_k(self.kparam, code, 15);
+      p += 1;
+      length -= 1;
+    }
+    if length == 1 {
+      self.cur[p+1] = self.cur[p];
+      let code = self.get_code();
+      self.cur[p+1] += self.rounded_value(code);
+      self.kparam = predict_k(self.kparam, code, 15);
+      p += 1;
+    }
+    self.cur[p+1] = self.cur[p] + 1;
+    Ok(())
+  }
+
+  fn decode_symbol_rounded(&mut self, p: &mut usize, q: usize, median: bool, use_next: bool) {
+    let pp = *p;
+    let mut sym = self.prev[q+1];
+    if median {
+      let left = self.cur[pp];
+      let top = self.prev[q+1];
+      let topleft = self.prev[q];
+      let delta = top - topleft;
+      let symb = [delta + left, delta + left, left, top];
+      let idx = ((((topleft < left) ^ (delta < 0)) as usize) << 1) + (((left < top) ^ (delta < 0)) as usize);
+      sym = symb[idx];
+    }
+
+    let code = self.get_code();
+    self.cur[pp+1] = self.rounded_value(code) + sym;
+
+    if use_next {
+      let (next, top) = (self.prev[q+2], self.prev[q+1]);
+      let delta = if next > top {
+        (next - top + self.rounded_bits_mask - 1) >> self.rounded_bits
+      } else {
+        -((top - next + self.rounded_bits_mask) >> self.rounded_bits)
+      };
+      self.kparam = predict_k(self.kparam, (code + 2 * delta.abs() as u32) >> 1, 15);
+    } else {
+      self.kparam = predict_k(self.kparam, code, 15);
+    }
+    *p += 1;
+  }
+
+  fn decode_line_rounded(&mut self) -> Result<(), String> {
+    let (mut p, mut q) = (0, 0);
+    let mut length = self.width;
+    let mut reached = false;
+    self.prev[0] = self.prev[1];
+    self.cur[0] = self.prev[1];
+    while length > 1 {
+      if (self.prev[q+2] - self.prev[q+1]).abs() > self.rounded_bits_mask {
+        self.decode_symbol_rounded(&mut p, q, true, true);
+        q += 1;
+        reached = true;
+      } else if reached || (self.prev[q] - self.cur[p]).abs() > self.rounded_bits_mask {
+        self.decode_symbol_rounded(&mut p, q, true, true);
+        q += 1;
+        reached = false;
+      } else {
+        let nsyms = self.get_run(length)?;
+        if nsyms > 0 {
+          length -= nsyms;
+          q += nsyms;
+          for _ in 0..nsyms {
+            self.cur[p+1] = self.cur[p];
+            p += 1;
+          }
+          if length == 0 {
+            break
+          }
+        }
+        if length > 1 {
+          self.decode_symbol_rounded(&mut p, q, false, true);
+          q += 1;
+          reached = (self.prev[q+1] - self.prev[q]).abs() > self.rounded_bits_mask;
+        } else {
+          self.decode_symbol_rounded(&mut p, q, false, false);
+        }
+      }
+      length -= 1;
+    }
+    if length == 1 {
+      self.decode_symbol_rounded(&mut p, q, true, false);
+    }
+    self.cur[p+1] = self.cur[p] + 1;
+    Ok(())
+  }
+
+  fn decode_top_line_noref(&mut self) -> Result<(), String> {
+    let mut p = 0;
+    let mut length = self.width;
+    self.prev[0] = 0;
+    self.cur[0] = 0;
+    while length > 1 {
+      if self.cur[p] != 0 {
+        let code = self.get_code();
+        self.cur[p+1] = to_signed(code);
+        self.kparam = predict_k(self.kparam, code, 0);
+      } else {
+        let nsyms = self.get_run(length)?;
+        if nsyms > 0 {
+          length -= nsyms;
+          for _ in 0..nsyms {
+            self.kvals[p] = 0;
+            self.cur[p+1] = 0;
+            p += 1;
+          }
+          if length == 0 {
+            break
+          }
+        }
+        // After a run of zeros the next value can't be zero so it's coded shifted by one
+        let code = self.get_code();
+        self.cur[p+1] = to_signed(code + 1);
+        self.kparam = predict_k(self.kparam, code, 0);
+      }
+      self.kvals[p] = self.kparam as i32;
+      p += 1;
+      length -= 1;
+    }
+    if length == 1 {
+      let code = self.get_code();
+      self.cur[p+1] = to_signed(code);
+      self.kparam = predict_k(self.kparam, code, 0);
+      self.kvals[p] = self.kparam as i32;
+      p += 1;
+    }
+    self.cur[p+1] = 0;
+    Ok(())
+  }
+
+  fn adjust_kparam(&mut self, above: i32) {
+    if above - (self.kparam as i32) <= 1 {
+      if self.kparam >= 15 {
+        self.kparam = 15;
+      }
+    } else {
+      self.kparam += 1;
+    }
+  }
+
+  fn decode_line_noref(&mut self) -> Result<(), String> {
+    let width = self.width;
+    let mut i = 0;
+    while i + 1 < width {
+      if self.prev[i+2] != 0 || self.prev[i+1] != 0 || self.cur[i] != 0 {
+        let code = self.get_code();
+        self.cur[i+1] = to_signed(code);
+        self.kparam = predict_k(self.kparam, code, 0);
+        let above = self.kvals[i+1];
+        self.adjust_kparam(above);
+      } else {
+        let nsyms = self.get_run(width - i)?;
+        for j in 0..nsyms {
+          self.cur[i+1+j] = 0;
+          self.kvals[i+j] = 0;
+        }
+        i += nsyms;
+
+        if i + 1 >= width {
+          if i + 1 == width {
+            let code = self.get_code();
+            self.cur[i+1] = to_signed(code + 1);
+            self.kparam = predict_k(self.kparam, code, 15);
+            self.kvals[i] = self.kparam as i32;
+          }
+          i += 1;
+          continue
+        }
+
+        let code = self.get_code();
+        self.cur[i+1] = to_signed(code + 1);
+        self.kparam = predict_k(self.kparam, code, 0);
+        let above = self.kvals[i+1];
+        self.adjust_kparam(above);
+      }
+      self.kvals[i] = self.kparam as i32;
+      i += 1;
+    }
+    if i + 1 == width {
+      let code = self.get_code();
+      self.cur[i+1] = to_signed(code);
+      self.kparam = predict_k(self.kparam, code, 15);
+      self.kvals[i] = self.kparam as i32;
+    }
+    Ok(())
+  }
+}
+
@@ -0,0 +1,652 @@
+// Decoder for normal lossy DCT JPEG (baseline, extended and progressive huffman), used
+// by lossy DNGs. Output is 8 bit RGB, or grayscale for single component images.
+
+use std::cmp;
+use std::f32::consts::PI;
+
+use crate::decoders::basics::*;
+
+const ZIGZAG: [usize;64] = [
+   0,  1,  8, 16,  9,  2,  3, 10,
+  17, 24, 32, 25, 18, 11,  4,  5,
+  12, 19, 26, 33, 40, 48, 41, 34,
+  27, 20, 13,  6,  7, 14, 21, 28,
+  35, 42, 49, 56, 57, 50, 43, 36,
+  29, 22, 15, 23, 30, 37, 44, 51,
+  58, 59, 52, 45, 38, 31, 39, 46,
+  53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Reads the entropy coded data, removing stuffed zero bytes and feeding zeroes when
+// a marker is found
+#[derive(Debug, Copy, Clone)]
+struct DctBits<'a> {
+  buffer: &'a [u8],
+  pos: usize,
+  bits: u64,
+  nbits: u32,
+  marker: bool,
+}
+
+impl<'a> DctBits<'a> {
+  fn new(src: &'a [u8], pos: usize) -> DctBits {
+    DctBits {
+      buffer: src,
+      pos: pos,
+      bits: 0,
+      nbits: 0,
+      marker: false,
+    }
+  }
+
+  #[inline(always)]
+  fn fill(&mut self) {
+    while self.nbits <= 56 {
+      let byte = if self.marker || self.pos >= self.buffer.len() {
+        0
+      } else if self.buffer[self.pos] != 0xff {
+        self.pos += 1;
+        self.buffer[self.pos-1]
+      } else if self.pos+1 < self.buffer.len() && self.buffer[self.pos+1] == 0 {
+        self.pos += 2;
+        0xff
+      } else {
+        self.marker = true;
+        0
+      };
+      self.bits |= (byte as u64) << (56 - self.nbits);
+      self.nbits += 8;
+    }
+  }
+
+  #[inline(always)]
+  fn peek(&mut self, num: u32) -> u32 {
+    if self.nbits < num {
+      self.fill();
+    }
+    (self.bits >> (64 - num)) as u32
+  }
+
+  #[inline(always)]
+  fn consume(&mut self, num: u32) {
+    self.bits <<= num;
+    self.nbits -= num;
+  }
+
+  #[inline(always)]
+  fn get(&mut self, num: u32) -> u32 {
+    if num == 0 {
+      return 0
+    }
+    let val = self.peek(num);
+    self.consume(num);
+    val
+  }
+
+  #[inline(always)]
+  fn get_extended(&mut self, num: u32) -> i32 {
+    if num == 0 {
+      return 0
+    }
+    let val = self.get(num) as i32;
+    if val < (1 << (num - 1)) {
+      val - (1 << num) + 1
+    } else {
+      val
+    }
+  }
+
+  // Throw away any buffered bits and skip over the next RSTn marker
+  fn restart(&mut self) {
+    self.bits = 0;
+    self.nbits = 0;
+    self.marker = false;
+    while self.pos+1 < self.buffer.len() {
+      if self.buffer[self.pos] == 0xff && (self.buffer[self.pos+1] & 0xf8) == 0xd0 {
+        self.pos += 2;
+        return
+      }
+      self.pos += 1;
+    }
+  }
+
+  // Position of the next marker after the entropy coded data
+  fn end(&self) -> usize {
+    let mut pos = self.pos;
+    while pos+1 < self.buffer.len() {
+      if self.buffer[pos] == 0xff && self.buffer[pos+1] != 0 && (self.buffer[pos+1] & 0xf8) != 0xd0 {
+        return pos
+      }
+      pos += 1;
+    }
+    self.buffer.len()
+  }
+}
+
+// Huffman table decoded by looking up the next 16 bits, each entry is (length << 8) | value
+#[derive(Debug, Clone)]
+struct DctHuffman {
+  table: Vec<u16>,
+}
+
+impl DctHuffman {
+  fn new(counts: &[u8], values: &[u8]) -> Result<DctHuffman, String> {
+    let mut table = vec![0 as u16; 1 << 16];
+    let mut code = 0 as usize;
+    let mut idx = 0;
+    for len in 1..17 {
+      for _ in 0..counts[len-1] {
+        if idx >= values.len() || code >= (1 << len) {
+          return Err("JPEG: invalid huffman table".to_string())
+        }
+        let shift = 16 - len;
+        for entry in table[code << shift..(code+1) << shift].iter_mut() {
+          *entry = ((len as u16) << 8) | values[idx] as u16;
+        }
+        code += 1;
+        idx += 1;
+      }
+      code <<= 1;
+    }
+    Ok(DctHuffman {
+      table: table,
+    })
+  }
+
+  #[inline(always)]
+  fn decode(&self, bits: &mut DctBits) -> Result<u8, String> {
+    let entry = self.table[bits.peek(16) as usize];
+    let len = (entry >> 8) as u32;
+    if len == 0 {
+      return Err("JPEG: invalid huffman code".to_string())
+    }
+    bits.consume(len);
+    Ok(entry as u8)
+  }
+}
+
+#[derive(Debug, Clone)]
+struct DctComponent {
+  id: u8,
+  h: usize,
+  v: usize,
+  tq: usize,
+  // Blocks per line and per column, padded to full MCUs
+  bwidth: usize,
+  bheight: usize,
+  coefs: Vec<i32>,
+  dc_table: usize,
+  ac_table: usize,
+  pred: i32,
+}
+
+#[derive(Debug, Clone)]
+struct DctScan {
+  comps: Vec<usize>,
+  ss: usize,
+  se: usize,
+  ah: u32,
+  al: u32,
+}
+
+/// Decoded JPEG image
+#[derive(Debug, Clone)]
+pub struct DctImage {
+  pub width: usize,
+  pub height: usize,
+  pub cpp: usize,
+  pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DctDecompressor<'a> {
+  buffer: &'a [u8],
+  width: usize,
+  height: usize,
+  progressive: bool,
+  hmax: usize,
+  vmax: usize,
+  mcus_x: usize,
+  mcus_y: usize,
+  comps: Vec<DctComponent>,
+  qtables: [[i32;64];4],
+  dc_tables: Vec<Option<DctHuffman>>,
+  ac_tables: Vec<Option<DctHuffman>>,
+  restart: usize,
+  eobrun: u32,
+  adobe_transform: Option<u8>,
+}
+
+impl<'a> DctDecompressor<'a> {
+  pub fn new(src: &'a [u8]) -> DctDecompressor<'a> {
+    DctDecompressor {
+      buffer: src,
+      width: 0,
+      height: 0,
+      progressive: false,
+      hmax: 1,
+      vmax: 1,
+      mcus_x: 0,
+      mcus_y: 0,
+      comps: Vec::new(),
+      qtables: [[0;64];4],
+      dc_tables: vec![None, None, None, None],
+      ac_tables: vec![None, None, None, None],
+      restart: 0,
+      eobrun: 0,
+      adobe_transform: None,
+    }
+  }
+
+  pub fn decode(mut self) -> Result<DctImage, String> {
+    let src = self.buffer;
+    if src.len() < 4 || src[0] != 0xff || src[1] != 0xd8 {
+      return Err("JPEG: no SOI marker".to_string())
+    }
+    let mut pos = 2;
+    loop {
+      // Find the next marker, skipping any fill bytes
+      while pos < src.len() && src[pos] != 0xff {
+        pos += 1;
+      }
+      while pos < src.len() && src[pos] == 0xff {
+        pos += 1;
+      }
+      if pos >= src.len() {
+        return Err("JPEG: no EOI marker".to_string())
+      }
+      let marker = src[pos];
+      pos += 1;
+      if marker == 0xd9 { // EOI
+        break
+      }
+      if (marker & 0xf8) == 0xd0 { // RSTn without data
+        continue
+      }
+      if pos + 2 > src.len() {
+        return Err("JPEG: marker segment is truncated".to_string())
+      }
+      let len = BEu16(src, pos) as usize;
+      if len < 2 || pos + len > src.len() {
+        return Err("JPEG: marker segment is truncated".to_string())
+      }
+      let data = &src[pos+2..pos+len];
+      pos += len;
+      match marker {
+        0xc0 | 0xc1 | 0xc2 => self.parse_sof(data, marker == 0xc2)?,
+        0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+          return Err(format!("JPEG: unsupported frame type {:x}", marker).to_string())
+        },
+        0xc4 => self.parse_dht(data)?,
+        0xdb => self.parse_dqt(data)?,
+        0xdd => self.restart = BEu16(data, 0) as usize,
+        0xee => {
+          if data.len() >= 12 && &data[0..5] == b"Adobe" {
+            self.adobe_transform = Some(data[11]);
+          }
+        },
+        0xda => {
+          let scan = self.parse_sos(data)?;
+          pos = self.decode_scan(&scan, pos)?;
+        },
+        _ => {},
+      }
+    }
+
+    if self.comps.is_empty() {
+      return Err("JPEG: no frame found".to_string())
+    }
+    Ok(self.output())
+  }
+
+  fn parse_sof(&mut self, data: &[u8], progressive: bool) -> Result<(), String> {
+    if data.len() < 6 || data[0] != 8 {
+      return Err("JPEG: only 8 bit images are supported".to_string())
+    }
+    self.height = BEu16(data, 1) as usize;
+    self.width = BEu16(data, 3) as usize;
+    self.progressive = progressive;
+    let ncomps = data[5] as usize;
+    if (ncomps != 1 && ncomps != 3) || data.len() < 6 + ncomps*3 || self.width == 0 || self.height == 0 {
+      return Err("JPEG: invalid frame header".to_string())
+    }
+    for i in 0..ncomps {
+      let c = &data[6+i*3..];
+      let (h, v) = ((c[1] >> 4) as usize, (c[1] & 0xf) as usize);
+      if h == 0 || h > 4 || v == 0 || v > 4 || c[2] > 3 {
+        return Err("JPEG: invalid component".to_string())
+      }
+      self.comps.push(DctComponent {
+        id: c[0],
+        h: h,
+        v: v,
+        tq: c[2] as usize,
+        bwidth: 0,
+        bheight: 0,
+        coefs: Vec::new(),
+        dc_table: 0,
+        ac_table: 0,
+        pred: 0,
+      });
+    }
+
+    self.hmax = self.comps.iter().map(|c| c.h).max().unwrap();
+    self.vmax = self.comps.iter().map(|c| c.v).max().unwrap();
+    self.mcus_x = (self.width + 8*self.hmax - 1) / (8*self.hmax);
+    self.mcus_y = (self.height + 8*self.vmax - 1) / (8*self.vmax);
+    for c in self.comps.iter_mut() {
+      c.bwidth = self.mcus_x * c.h;
+      c.bheight = self.mcus_y * c.v;
+      c.coefs = vec![0; c.bwidth * c.bheight * 64];
+    }
+    Ok(())
+  }
+
+  fn parse_dht(&mut self, data: &[u8]) -> Result<(), String> {
+    let mut pos = 0;
+    while pos + 17 <= data.len() {
+      let class = data[pos] >> 4;
+      let id = (data[pos] & 0xf) as usize;
+      let counts = &data[pos+1..pos+17];
+      let total = counts.iter().map(|&c| c as usize).sum::<usize>();
+      if id > 3 || pos + 17 + total > data.len() {
+        return Err("JPEG: invalid huffman table".to_string())
+      }
+      let table = DctHuffman::new(counts, &data[pos+17..pos+17+total])?;
+      if class == 0 {
+        self.dc_tables[id] = Some(table);
+      } else {
+        self.ac_tables[id] = Some(table);
+      }
+      pos += 17 + total;
+    }
+    Ok(())
+  }
+
+  fn parse_dqt(&mut self, data: &[u8]) -> Result<(), String> {
+    let mut pos = 0;
+    while pos < data.len() {
+      let precision = data[pos] >> 4;
+      let id = (data[pos] & 0xf) as usize;
+      let size = if precision == 0 { 64 } else { 128 };
+      if id > 3 || pos + 1 + size > data.len() {
+        return Err("JPEG: invalid quantization table".to_string())
+      }
+      for i in 0..64 {
+        self.qtables[id][ZIGZAG[i]] = if precision == 0 {
+          data[pos+1+i] as i32
+        } else {
+          BEu16(data, pos+1+i*2) as i32
+        };
+      }
+      pos += 1 + size;
+    }
+    Ok(())
+  }
+
+  fn parse_sos(&mut self, data: &[u8]) -> Result<DctScan, String> {
+    if self.comps.is_empty() {
+      return Err("JPEG: scan before frame header".to_string())
+    }
+    let ns = data[0] as usize;
+    if ns == 0 || ns > self.comps.len() || data.len() < 4 + ns*2 {
+      return Err("JPEG: invalid scan header".to_string())
+    }
+    let mut comps = Vec::new();
+    for i in 0..ns {
+      let id = data[1+i*2];
+      let tables = data[2+i*2];
+      let idx = self.comps.iter().position(|c| c.id == id)
+        .ok_or("JPEG: scan references unknown component".to_string())?;
+      self.comps[idx].dc_table = (tables >> 4) as usize & 3;
+      self.comps[idx].ac_table = (tables & 0xf) as usize & 3;
+      comps.push(idx);
+    }
+    let p = 1 + ns*2;
+    let scan = DctScan {
+      comps: comps,
+      ss: data[p] as usize,
+      se: data[p+1] as usize,
+      ah: (data[p+2] >> 4) as u32,
+      al: (data[p+2] & 0xf) as u32,
+    };
+    if scan.ss > scan.se || scan.se > 63 || (!self.progressive && (scan.ss != 0 || scan.se != 63)) {
+      return Err("JPEG: invalid spectral selection".to_string())
+    }
+    Ok(scan)
+  }
+
+  fn decode_scan(&mut self, scan: &DctScan, pos: usize) -> Result<usize, String> {
+    let src = self.buffer;
+    let mut bits = DctBits::new(src, pos);
+    for c in self.comps.iter_mut() {
+      c.pred = 0;
+    }
+    self.eobrun = 0;
+
+    // Non interleaved scans go over the blocks that actually cover the component
+    // instead of the padded MCUs
+    let (mcus_x, mcus_y) = if scan.comps.len() == 1 {
+      let c = &self.comps[scan.comps[0]];
+      let width = (self.width * c.h + self.hmax - 1) / self.hmax;
+      let height = (self.height * c.v + self.vmax - 1) / self.vmax;
+      ((width + 7) / 8, (height + 7) / 8)
+    } else {
+      (self.mcus_x, self.mcus_y)
+    };
+
+    let mut todo = if self.restart > 0 { self.restart } else { usize::max_value() };
+    for my in 0..mcus_y {
+      for mx in 0..mcus_x {
+        if todo == 0 {
+          bits.restart();
+          for c in self.comps.iter_mut() {
+            c.pred = 0;
+          }
+          self.eobrun = 0;
+          todo = self.restart;
+        }
+        todo -= 1;
+
+        for &ci in scan.comps.iter() {
+          let (h, v) = if scan.comps.len() == 1 { (1, 1) } else { (self.comps[ci].h, self.comps[ci].v) };
+          for by in 0..v {
+            for bx in 0..h {
+              let (row, col) = if scan.comps.len() == 1 { (my, mx) } else { (my*v + by, mx*h + bx) };
+              self.decode_block(&mut bits, scan, ci, row, col)?;
+            }
+          }
+        }
+      }
+    }
+
+    Ok(bits.end())
+  }
+
+  fn decode_block(&mut self, bits: &mut DctBits, scan: &DctScan, ci: usize, row: usize, col: usize) -> Result<(), String> {
+    let comp = &self.comps[ci];
+    let start = (row * comp.bwidth + col) * 64;
+    let dc_table = comp.dc_table;
+    let ac_table = comp.ac_table;
+
+    if scan.ss == 0 {
+      if scan.ah == 0 {
+        let table = self.dc_tables[dc_table].as_ref().ok_or("JPEG: missing DC table".to_string())?;
+        let t = table.decode(bits)? as u32;
+        let diff = bits.get_extended(t);
+        let comp = &mut self.comps[ci];
+        comp.pred += diff;
+        comp.coefs[start] = comp.pred << scan.al;
+      } else if bits.get(1) == 1 {
+        self.comps[ci].coefs[start] |= 1 << scan.al;
+      }
+      if scan.se == 0 {
+        return Ok(())
+      }
+    }
+
+    let table = self.ac_tables[ac_table].as_ref().ok_or("JPEG: missing AC table".to_string())?;
+    let coefs = &mut self.comps[ci].coefs[start..start+64];
+    let mut k = cmp::max(scan.ss, 1);
+
+    if scan.ah == 0 {
+      if self.eobrun > 0 {
+        self.eobrun -= 1;
+        return Ok(())
+      }
+      while k <= scan.se {
+        let rs = table.decode(bits)?;
+        let r = (rs >> 4) as usize;
+        let s = (rs & 0xf) as u32;
+        if s == 0 {
+          if r < 15 {
+            self.eobrun = (1 << r) - 1 + bits.get(r as u32);
+            break
+          }
+          k += 16;
+          continue
+        }
+        k += r;
+        if k > 63 {
+          return Err("JPEG: AC coefficient out of range".to_string())
+        }
+        coefs[ZIGZAG[k]] = bits.get_extended(s) << scan.al;
+        k += 1;
+      }
+    } else {
+      // Successive approximation refinement of the AC coefficients
+      let p1 = 1 << scan.al;
+      let m1 = -1 << scan.al;
+      let refine = |bits: &mut DctBits, coef: &mut i32| {
+        if bits.get(1) == 1 && (*coef & p1) == 0 {
+          *coef += if *coef >= 0 { p1 } else { m1 };
+        }
+      };
+
+      if self.eobrun == 0 {
+        while k <= scan.se {
+          let rs = table.decode(bits)?;
+          let mut r = (rs >> 4) as i32;
+          let s = rs & 0xf;
+          let mut val = 0;
+          if s != 0 {
+            val = if bits.get(1) == 1 { p1 } else { m1 };
+          } else if r != 15 {
+            self.eobrun = (1 << r) + bits.get(r as u32);
+            break
+          }
+          while k <= scan.se {
+            let coef = &mut coefs[ZIGZAG[k]];
+            if *coef != 0 {
+              refine(bits, coef);
+            } else {
+              if r == 0 {
+                break
+              }
+              r -= 1;
+            }
+            k += 1;
+          }
+          if val != 0 && k <= scan.se {
+            coefs[ZIGZAG[k]] = val;
+          }
+          k += 1;
+        }
+      }
+      if self.eobrun > 0 {
+        while k <= scan.se {
+          let coef = &mut coefs[ZIGZAG[k]];
+          if *coef != 0 {
+            refine(bits, coef);
+          }
+          k += 1;
+        }
+        self.eobrun -= 1;
+      }
+    }
+    Ok(())
+  }
+
+  // Dequantize and inverse transform every block into a plane per component
+  fn component_plane(&self, comp: &DctComponent, cosines: &[[f32;8];8]) -> Vec<u8> {
+    let pwidth = comp.bwidth * 8;
+    let mut plane = vec![0 as u8; pwidth * comp.bheight * 8];
+    let qt = &self.qtables[comp.tq];
+    for brow in 0..comp.bheight {
+      for bcol in 0..comp.bwidth {
+        let coefs = &comp.coefs[(brow*comp.bwidth+bcol)*64..];
+        let mut tmp = [[0 as f32;8];8];
+        for v in 0..8 {
+          for x in 0..8 {
+            let mut sum = 0.0;
+            for u in 0..8 {
+              sum += cosines[x][u] * (coefs[v*8+u] * qt[v*8+u]) as f32;
+            }
+            tmp[v][x] = sum;
+          }
+        }
+        for y in 0..8 {
+          let line = &mut plane[(brow*8+y)*pwidth + bcol*8..];
+          for x in 0..8 {
+            let mut sum = 0.0;
+            for v in 0..8 {
+              sum += cosines[y][v] * tmp[v][x];
+            }
+            line[x] = (sum + 128.5).max(0.0).min(255.0) as u8;
+          }
+        }
+      }
+    }
+    plane
+  }
+
+  fn output(&self) -> DctImage {
+    let mut cosines = [[0 as f32;8];8];
+    for x in 0..8 {
+      for u in 0..8 {
+        let cu = if u == 0 { 1.0 / (2.0 as f32).sqrt() } else { 1.0 };
+        cosines[x][u] = cu / 2.0 * (((2*x+1) * u) as f32 * PI / 16.0).cos();
+      }
+    }
+
+    let planes: Vec<Vec<u8>> = self.comps.iter().map(|c| self.component_plane(c, &cosines)).collect();
+    let cpp = self.comps.len();
+    let mut data = vec![0 as u8; self.width * self.height * cpp];
+
+    // Upsample any subsampled components by replication
+    for (ci, comp) in self.comps.iter().enumerate() {
+      let pwidth = comp.bwidth * 8;
+      let xscale = self.hmax / comp.h;
+      let yscale = self.vmax / comp.v;
+      for row in 0..self.height {
+        let line = &planes[ci][(row / yscale) * pwidth..];
+        for col in 0..self.width {
+          data[(row*self.width+col)*cpp + ci] = line[col / xscale];
+        }
+      }
+    }
+
+    // Three component images are YCbCr unless marked otherwise
+    let rgb = match self.adobe_transform {
+      Some(transform) => transform == 0,
+      None => cpp == 3 && self.comps[0].id == b'R' && self.comps[1].id == b'G' && self.comps[2].id == b'B',
+    };
+    if cpp == 3 && !rgb {
+      for pix in data.chunks_exact_mut(3) {
+        let y = pix[0] as f32;
+        let cb = pix[1] as f32 - 128.0;
+        let cr = pix[2] as f32 - 128.0;
+        pix[0] = (y + 1.402 * cr + 0.5).max(0.0).min(255.0) as u8;
+        pix[1] = (y - 0.344136 * cb - 0.714136 * cr + 0.5).max(0.0).min(255.0) as u8;
+        pix[2] = (y + 1.772 * cb + 0.5).max(0.0).min(255.0) as u8;
+      }
+    }
+
+    DctImage {
+      width: self.width,
+      height: self.height,
+      cpp: cpp,
+      data: data,
+    }
+  }
+}
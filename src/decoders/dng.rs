@@ -1,11 +1,15 @@
 use std::f32::NAN;
 use std::cmp;
+use std::ptr;
+use rayon::prelude::*;
 
 use crate::decoders::*;
 use crate::decoders::tiff::*;
 use crate::decoders::basics::*;
 use crate::decoders::ljpeg::*;
 use crate::decoders::cfa::*;
+use crate::decoders::inflate::*;
+use crate::decoders::dctjpeg::*;
 
 #[derive(Debug, Clone)]
 pub struct DngDecoder<'a> {
@@ -26,25 +30,17 @@ impl<'a> DngDecoder<'a> {
 
 impl<'a> Decoder for DngDecoder<'a> {
   fn image(&self, dummy: bool) -> Result<RawImage,String> {
-    let ifds = self.tiff.find_ifds_with_tag(Tag::Compression).into_iter().filter(|ifd| {
-      let compression = (**ifd).find_entry(Tag::Compression).unwrap().get_u32(0);
-      let subsampled = match (**ifd).find_entry(Tag::NewSubFileType) {
-        Some(e) => e.get_u32(0) & 1 != 0,
-        None => false,
-      };
-      !subsampled && (compression == 7 || compression == 1 || compression == 0x884c)
-    }).collect::<Vec<&TiffIFD>>();
-    let raw = ifds[0];
+    let raw = self.raw_ifd()?;
     let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
     let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
     let cpp = fetch_tag!(raw, Tag::SamplesPerPixel).get_usize(0);
     let linear = fetch_tag!(raw, Tag::PhotometricInt).get_usize(0) == 34892;
-
-    let image = match fetch_tag!(raw, Tag::Compression).get_u32(0) {
-      1 => self.decode_uncompressed(raw, width*cpp, height, dummy)?,
-      7 => self.decode_compressed(raw, width*cpp, height, cpp, dummy)?,
-      c => return Err(format!("Don't know how to read DNGs with compression {}", c).to_string()),
-    };
+    let float = self.is_float(raw);
+    let image = self.decode_data(raw, width, height, cpp, float, dummy)?;
+    let cfa = if linear {CFA::new("")} else {self.get_cfa(raw)?};
+    let levels = self.get_levels(raw, width, height, cpp, float)?;
+    let (blacklevels, whitelevels) = levels_simplified(&levels, &cfa);
+    let (xyz_to_cam, dng_color) = self.get_color()?;
 
     let (make, model, clean_make, clean_model, orientation) = {
       match self.rawhide.check_supported(&self.tiff) {
@@ -71,44 +67,212 @@ impl<'a> Decoder for DngDecoder<'a> {
       height: height,
       cpp: cpp,
       wb_coeffs: self.get_wb()?,
-      data: RawImageData::Integer(image),
-      blacklevels: self.get_blacklevels(raw)?,
-      whitelevels: self.get_whitelevels(raw)?,
-      xyz_to_cam: self.get_color_matrix()?,
-      cfa: if linear {CFA::new("")} else {self.get_cfa(raw)?},
+      data: image,
+      blacklevels: levels_to_u16(blacklevels),
+      whitelevels: levels_to_u16(whitelevels),
+      float_levels: exact_float_levels(blacklevels, whitelevels),
+      xyz_to_cam: xyz_to_cam,
+      cfa: cfa,
       crops: self.get_crops(raw, width, height)?,
       blackareas: self.get_masked_areas(raw),
       orientation: orientation,
+      opcodes: self.get_opcodes(raw)?,
+      dng_color: dng_color,
+      dng_levels: Some(levels),
+      dng_defaults: Some(self.get_defaults(raw, width, height)),
+      semantic_masks: self.get_semantic_masks(dummy),
+      iiq_calibration: None,
+      superccd: None,
+      pixel_shift: None,
+      frame_info: self.get_frame_info(),
     })
   }
 }
 
 impl<'a> DngDecoder<'a> {
-  fn get_wb(&self) -> Result<[f32;4], String> {
-    if let Some(levels) = self.tiff.find_entry(Tag::AsShotNeutral) {
-      Ok([1.0/levels.get_f32(0),1.0/levels.get_f32(1),1.0/levels.get_f32(2),NAN])
-    } else {
-      Ok([NAN,NAN,NAN,NAN])
+  // The full resolution raw image, skipping previews and other subsampled versions
+  fn raw_ifd(&self) -> Result<&TiffIFD, String> {
+    let ifds = self.tiff.find_ifds_with_tag(Tag::Compression).into_iter().filter(|ifd| {
+      let compression = (**ifd).find_entry(Tag::Compression).unwrap().get_u32(0);
+      // Skip previews (1), transparency and semantic masks (4) and depth maps (8)
+      let auxiliary = match (**ifd).find_entry(Tag::NewSubFileType) {
+        Some(e) => e.get_u32(0) & 0xd != 0,
+        None => false,
+      };
+      !auxiliary && (compression == 7 || compression == 1 || compression == 8 || compression == 0x884c)
+    }).collect::<Vec<&TiffIFD>>();
+    match ifds.first() {
+      Some(raw) => Ok(*raw),
+      None => Err("DNG: Couldn't find the raw image".to_string()),
     }
   }
 
-  fn get_blacklevels(&self, raw: &TiffIFD) -> Result<[u16;4], String> {
-    if let Some(levels) = raw.find_entry(Tag::BlackLevels) {
-      if levels.count() < 4 {
-        let black = levels.get_f32(0) as u16;
-        Ok([black, black, black, black])
-      } else {
-        Ok([levels.get_f32(0) as u16,levels.get_f32(1) as u16,
-          levels.get_f32(2) as u16,levels.get_f32(3) as u16])
+  fn is_float(&self, raw: &TiffIFD) -> bool {
+    match raw.find_entry(Tag::SampleFormat) {
+      Some(format) => format.get_u32(0) == 3,
+      None => false,
+    }
+  }
+
+  // Decodes only the image data and none of the metadata, along with its width, height
+  // and components per pixel. Used by sequences where all frames share the metadata.
+  pub fn image_data(&self, dummy: bool) -> Result<(usize, usize, usize, RawImageData),String> {
+    let raw = self.raw_ifd()?;
+    let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
+    let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
+    let cpp = fetch_tag!(raw, Tag::SamplesPerPixel).get_usize(0);
+    let float = self.is_float(raw);
+    Ok((width, height, cpp, self.decode_data(raw, width, height, cpp, float, dummy)?))
+  }
+
+  fn decode_data(&self, raw: &TiffIFD, width: usize, height: usize, cpp: usize, float: bool, dummy: bool) -> Result<RawImageData,String> {
+    Ok(if float {
+      match fetch_tag!(raw, Tag::Compression).get_u32(0) {
+        1 => RawImageData::Float(self.decode_float_uncompressed(raw, width*cpp, height, cpp, dummy)?),
+        8 => RawImageData::Float(self.decode_float_deflate(raw, width*cpp, height, cpp, dummy)?),
+        c => return Err(format!("Don't know how to read float DNGs with compression {}", c).to_string()),
       }
     } else {
-      Ok([0,0,0,0])
+      RawImageData::Integer(match fetch_tag!(raw, Tag::Compression).get_u32(0) {
+        1 => self.decode_uncompressed(raw, width*cpp, height, dummy)?,
+        7 => self.decode_compressed(raw, width*cpp, height, cpp, dummy)?,
+        8 => self.decode_deflate(raw, width*cpp, height, cpp, dummy)?,
+        0x884c => self.decode_lossy(raw, width*cpp, height, cpp, dummy)?,
+        c => return Err(format!("Don't know how to read DNGs with compression {}", c).to_string()),
+      })
+    })
+  }
+
+  // Masks are extra data so the ones that can't be decoded are just left out
+  fn get_semantic_masks(&self, dummy: bool) -> Vec<DngSemanticMask> {
+    let mut ifds: Vec<&TiffIFD> = Vec::new();
+    for ifd in self.tiff.find_ifds_with_tag(Tag::SemanticName) {
+      if !ifds.iter().any(|i| ptr::eq(*i, ifd)) {
+        ifds.push(ifd);
+      }
+    }
+    ifds.into_iter().filter_map(|ifd| self.get_semantic_mask(ifd, dummy).ok()).collect()
+  }
+
+  fn get_semantic_mask(&self, ifd: &TiffIFD, dummy: bool) -> Result<DngSemanticMask,String> {
+    let width = fetch_tag!(ifd, Tag::ImageWidth).get_usize(0);
+    let height = fetch_tag!(ifd, Tag::ImageLength).get_usize(0);
+    let bps = fetch_tag!(ifd, Tag::BitsPerSample).get_u32(0);
+    if bps == 0 || bps > 16 {
+      return Err(format!("DNG: Don't know about {} bps semantic masks", bps).to_string())
+    }
+    Ok(DngSemanticMask {
+      name: fetch_tag!(ifd, Tag::SemanticName).get_str().to_string(),
+      instance_id: ifd.find_entry(Tag::SemanticInstanceID).map(|e| e.get_str().to_string()),
+      area: ifd.find_entry(Tag::MaskSubArea).filter(|e| e.count() >= 4).map(|e| {
+        [e.get_usize(0), e.get_usize(1), e.get_usize(2), e.get_usize(3)]
+      }),
+      width: width,
+      height: height,
+      whitelevel: ((1u32 << bps) - 1) as u16,
+      data: self.decode_mask(ifd, width, height, bps, dummy)?,
+    })
+  }
+
+  // Masks are plain grayscale images, decoded without any of the raw image's linearization
+  fn decode_mask(&self, ifd: &TiffIFD, width: usize, height: usize, bps: u32, dummy: bool) -> Result<Vec<u16>,String> {
+    match (fetch_tag!(ifd, Tag::Compression).get_u32(0), bps) {
+      (1, 8) => {
+        let offsets = fetch_tag!(ifd, Tag::StripOffsets);
+        let joined;
+        let src = if offsets.count() == 1 {
+          if offsets.get_usize(0) > self.buffer.len() {
+            return Err("DNG: semantic mask goes beyond the end of the file".to_string())
+          }
+          &self.buffer[offsets.get_usize(0)..]
+        } else {
+          joined = self.get_uncompressed(ifd)?;
+          &joined[..]
+        };
+        if src.len() < width*height {
+          return Err("DNG: semantic mask goes beyond the end of the file".to_string())
+        }
+        Ok(decode_threaded(width, height, dummy, &(|out: &mut [u16], row| {
+          for (o, i) in out.iter_mut().zip(src[row*width..].iter()) {
+            *o = *i as u16;
+          }
+        })))
+      },
+      (1, 16) => self.decode_uncompressed(ifd, width, height, dummy),
+      // 8 bit masks are baseline JPEG, with the same compression value as lossless JPEG
+      (7, 8) | (0x884c, _) => self.decode_dct(ifd, width, height, 1, dummy),
+      (7, _) => self.decode_compressed(ifd, width, height, 1, dummy),
+      (c, _) => Err(format!("DNG: Don't know how to read semantic masks with compression {}", c).to_string()),
+    }
+  }
+
+  // Timing of CinemaDNG frames, None for still images
+  pub fn get_frame_info(&self) -> Option<FrameInfo> {
+    let fps = self.tiff.find_entry(Tag::FrameRate).map(|e| e.get_f32(0));
+    let timecode = self.tiff.find_entry(Tag::TimeCodes).filter(|e| e.count() >= 8).map(|e| {
+      TimeCode::from_smpte(e.get_data())
+    });
+    if fps.is_none() && timecode.is_none() {
+      return None
+    }
+    let fps = fps.unwrap_or(NAN);
+
+    Some(FrameInfo {
+      number: 0,
+      timestamp: timecode.map(|tc| tc.to_seconds(fps)).unwrap_or(std::f64::NAN),
+      exposure_time: self.tiff.find_entry(Tag::ExposureTime).map(|e| e.get_f32(0)).unwrap_or(NAN),
+      iso: self.tiff.find_entry(Tag::ISOSpeedRatings).map(|e| e.get_u32(0)).unwrap_or(0),
+      fps: fps,
+      timecode: timecode,
+      utc_time: None,
+    })
+  }
+
+  pub fn get_wb(&self) -> Result<[f32;4], String> {
+    if let Some(levels) = self.tiff.find_entry(Tag::AsShotNeutral) {
+      Ok([1.0/levels.get_f32(0),1.0/levels.get_f32(1),1.0/levels.get_f32(2),NAN])
+    } else {
+      Ok([NAN,NAN,NAN,NAN])
     }
   }
 
-  fn get_whitelevels(&self, raw: &TiffIFD) -> Result<[u16;4], String> {
-    let level = fetch_tag!(raw, Tag::WhiteLevel).get_u32(0) as u16;
-    Ok([level,level,level,level])
+  fn get_levels(&self, raw: &TiffIFD, width: usize, height: usize, cpp: usize, float: bool) -> Result<DngLevels, String> {
+    let origin = match raw.find_entry(Tag::ActiveArea) {
+      Some(area) => (area.get_usize(0), area.get_usize(1)),
+      None => (0, 0),
+    };
+    let (repeat_rows, repeat_cols) = match raw.find_entry(Tag::BlackLevelRepeatDim) {
+      Some(dim) => (cmp::max(dim.get_usize(0), 1), cmp::max(dim.get_usize(1), 1)),
+      None => (1, 1),
+    };
+    let total = repeat_rows * repeat_cols * cpp;
+    let black = match raw.find_entry(Tag::BlackLevels) {
+      Some(levels) if levels.count() >= total => (0..total).map(|i| levels.get_f32(i)).collect(),
+      // A single value for everything
+      Some(levels) => vec![levels.get_f32(0); total],
+      None => vec![0.0; total],
+    };
+    let deltas = |tag, count: usize| match raw.find_entry(tag) {
+      Some(deltas) => (0..cmp::min(deltas.count(), count)).map(|i| deltas.get_f32(i)).collect(),
+      None => Vec::new(),
+    };
+    let white = match raw.find_entry(Tag::WhiteLevel) {
+      Some(levels) => (0..cmp::max(cmp::min(levels.count(), cpp), 1)).map(|i| levels.get_f32(i)).collect(),
+      // Float images are normalized to 1.0 when there's no explicit level
+      None if float => vec![1.0],
+      None => return Err("Couldn't find tag Tag::WhiteLevel".to_string()),
+    };
+
+    Ok(DngLevels {
+      origin: origin,
+      repeat_rows: repeat_rows,
+      repeat_cols: repeat_cols,
+      cpp: cpp,
+      black: black,
+      black_delta_h: deltas(Tag::BlackLevelDeltaH, width.saturating_sub(origin.1)),
+      black_delta_v: deltas(Tag::BlackLevelDeltaV, height.saturating_sub(origin.0)),
+      white: white,
+    })
   }
 
   fn get_cfa(&self, raw: &TiffIFD) -> Result<CFA,String> {
@@ -126,6 +290,43 @@ impl<'a> DngDecoder<'a> {
     }
   }
 
+  fn get_opcodes(&self, raw: &TiffIFD) -> Result<DngOpcodes,String> {
+    let list = |tag| match raw.find_entry(tag) {
+      Some(entry) => parse_opcodes(entry.get_data()),
+      None => Ok(Vec::new()),
+    };
+    Ok(DngOpcodes {
+      list1: list(Tag::OpcodeList1)?,
+      list2: list(Tag::OpcodeList2)?,
+      list3: list(Tag::OpcodeList3)?,
+      active_area: raw.find_entry(Tag::ActiveArea).map(|area| {
+        [area.get_usize(0), area.get_usize(1), area.get_usize(2), area.get_usize(3)]
+      }),
+    })
+  }
+
+  fn get_defaults(&self, raw: &TiffIFD, width: usize, height: usize) -> DngDefaults {
+    // These are usually in the raw IFD but some are only allowed in the main one
+    let find = |tag| raw.find_entry(tag).or_else(|| self.tiff.find_entry(tag));
+    let pair = |tag, default: (f32, f32)| match find(tag) {
+      Some(entry) if entry.count() >= 2 => (entry.get_f32(0), entry.get_f32(1)),
+      _ => default,
+    };
+    let [top, right, bottom, left] = self.get_crops(raw, width, height).unwrap_or([0,0,0,0]);
+    let active = ((width - left - right) as f32, (height - top - bottom) as f32);
+
+    DngDefaults {
+      crop_origin: pair(Tag::DefaultCropOrigin, (0.0, 0.0)),
+      crop_size: pair(Tag::DefaultCropSize, active),
+      scale: pair(Tag::DefaultScale, (1.0, 1.0)),
+      best_quality_scale: find(Tag::BestQualityScale).map(|e| e.get_f32(0)).unwrap_or(1.0),
+      user_crop: find(Tag::DefaultUserCrop).filter(|e| e.count() >= 4).map(|e| {
+        (e.get_f32(0), e.get_f32(1), e.get_f32(2), e.get_f32(3))
+      }),
+      baseline_exposure: find(Tag::BaselineExposure).map(|e| e.get_f32(0)).unwrap_or(0.0),
+    }
+  }
+
   fn get_masked_areas(&self, raw: &TiffIFD) -> Vec<(u64, u64, u64, u64)> {
     let mut areas = Vec::new();
 
@@ -143,36 +344,133 @@ impl<'a> DngDecoder<'a> {
     areas
   }
 
-  fn get_color_matrix(&self) -> Result<[[f32;3];4],String> {
-    let mut matrix: [[f32;3];4] = [[0.0;3];4];
-    let cmatrix = {
-      if let Some(c) = self.tiff.find_entry(Tag::ColorMatrix2) {
-        c
-      } else if let Some(c) = self.tiff.find_entry(Tag::ColorMatrix1) {
-        c
-      } else {
-        return Ok([
+  fn get_color(&self) -> Result<([[f32;3];4], Option<DngColorData>),String> {
+    let matrices = [self.get_matrix(Tag::ColorMatrix1, 3)?, self.get_matrix(Tag::ColorMatrix2, 3)?];
+    let colors = match (&matrices[0], &matrices[1]) {
+      (_, Some((_, n))) | (Some((_, n)), None) => *n,
+      (None, None) => {
+        return Ok(([
           // sRGB D65
           [ 0.412453, 0.357580, 0.180423 ],
           [ 0.212671, 0.715160, 0.072169 ],
           [ 0.019334, 0.119193, 0.950227 ],
           [ 0.0, 0.0, 0.0],
-        ])
+        ], None))
+      },
+    };
+
+    let illuminants = [
+      self.tiff.find_entry(Tag::CalibrationIlluminant1).map(|e| e.get_u32(0) as u16).unwrap_or(0),
+      self.tiff.find_entry(Tag::CalibrationIlluminant2).map(|e| e.get_u32(0) as u16).unwrap_or(0),
+    ];
+    let mut calibrations = [None, None];
+    for (i, tag) in [Tag::CameraCalibration1, Tag::CameraCalibration2].iter().enumerate() {
+      if let Some((matrix, n)) = self.get_matrix(*tag, colors)? {
+        if n == colors {
+          let mut cal = [[0.0;4];4];
+          for row in 0..colors {
+            cal[row][..colors].copy_from_slice(&matrix[row*colors..(row+1)*colors]);
+          }
+          calibrations[i] = Some(cal);
+        }
       }
+    }
+    let mut forward = [None, None];
+    for (i, tag) in [Tag::ForwardMatrix1, Tag::ForwardMatrix2].iter().enumerate() {
+      if let Some((matrix, 3)) = self.get_matrix(*tag, colors)? {
+        let mut fwd = [[0.0;4];3];
+        for row in 0..3 {
+          fwd[row][..colors].copy_from_slice(&matrix[row*colors..(row+1)*colors]);
+        }
+        forward[i] = Some(fwd);
+      }
+    }
+    let mut analog_balance = [1.0;4];
+    if let Some(ab) = self.tiff.find_entry(Tag::AnalogBalance) {
+      for i in 0..cmp::min(ab.count(), colors) {
+        analog_balance[i] = ab.get_f32(i);
+      }
+    }
+
+    let to_matrix = |m: &Option<(Vec<f32>, usize)>| m.as_ref().map(|&(ref vals, _)| {
+      let mut matrix = [[0.0;3];4];
+      for (i, val) in vals.iter().enumerate() {
+        matrix[i/3][i%3] = *val;
+      }
+      matrix
+    });
+    let mut color = DngColorData {
+      illuminants: illuminants,
+      color_matrices: [to_matrix(&matrices[0]), to_matrix(&matrices[1])],
+      camera_calibrations: calibrations,
+      forward_matrices: forward,
+      analog_balance: analog_balance,
+      white_xy: D50_XY,
     };
-    if cmatrix.count() > 12 {
-      Err(format!("color matrix supposedly has {} components",cmatrix.count()).to_string())
+
+    // Find the white point of the image, iterating from the neutral as the matrix
+    // used to convert it depends on the white point itself
+    color.white_xy = if let Some(xy) = self.tiff.find_entry(Tag::AsShotWhiteXY) {
+      (xy.get_f32(0), xy.get_f32(1))
+    } else if let Some(neutral) = self.tiff.find_entry(Tag::AsShotNeutral) {
+      let neutral: Vec<f32> = (0..cmp::min(neutral.count(), colors)).map(|i| neutral.get_f32(i)).collect();
+      let mut last = D50_XY;
+      for pass in 0..30 {
+        let next = xyz_to_xy(solve_xyz(&xyz_to_camera(&color, colors, last), &neutral));
+        if (next.0 - last.0).abs() + (next.1 - last.1).abs() < 1e-7 {
+          last = next;
+          break
+        }
+        // Make sure we converge even if the solution oscillates
+        last = if pass == 29 { ((last.0 + next.0) / 2.0, (last.1 + next.1) / 2.0) } else { next };
+      }
+      last
     } else {
-      for i in 0..cmatrix.count() {
-        matrix[i/3][i%3] = cmatrix.get_f32(i);
+      D50_XY
+    };
+
+    Ok((xyz_to_camera(&color, colors, color.white_xy), Some(color)))
+  }
+
+  // Read a matrix with `cols` columns, returning the values and the number of rows
+  fn get_matrix(&self, tag: Tag, cols: usize) -> Result<Option<(Vec<f32>, usize)>,String> {
+    match self.tiff.find_entry(tag) {
+      Some(m) => {
+        if m.count() > 4*cols || m.count() % cols != 0 {
+          return Err(format!("DNG: matrix {:?} supposedly has {} components", tag, m.count()).to_string())
+        }
+        Ok(Some(((0..m.count()).map(|i| m.get_f32(i)).collect(), m.count() / cols)))
+      },
+      None => Ok(None),
+    }
+  }
+
+  // Multiple strips of uncompressed data don't need to be contiguous in the file so
+  // join them together to be able to use the normal decoders
+  fn get_uncompressed(&self, raw: &TiffIFD) -> Result<Vec<u8>,String> {
+    let offsets = fetch_tag!(raw, Tag::StripOffsets);
+    let counts = fetch_tag!(raw, Tag::StripByteCounts);
+    let mut data = Vec::new();
+    for i in 0..offsets.count() {
+      let offset = offsets.get_usize(i);
+      let end = offset + counts.get_usize(i);
+      if end > self.buffer.len() {
+        return Err("DNG: strip goes beyond the end of the file".to_string())
       }
-      Ok(matrix)
+      data.extend_from_slice(&self.buffer[offset..end]);
     }
+    Ok(data)
   }
 
   pub fn decode_uncompressed(&self, raw: &TiffIFD, width: usize, height: usize, dummy: bool) -> Result<Vec<u16>,String> {
-    let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-    let src = &self.buffer[offset..];
+    let offsets = fetch_tag!(raw, Tag::StripOffsets);
+    let joined;
+    let src = if offsets.count() == 1 {
+      &self.buffer[offsets.get_usize(0)..]
+    } else {
+      joined = self.get_uncompressed(raw)?;
+      &joined[..]
+    };
 
     match fetch_tag!(raw, Tag::BitsPerSample).get_u32(0) {
       16  => Ok(decode_16le(src, width, height, dummy)),
@@ -194,42 +492,476 @@ impl<'a> DngDecoder<'a> {
     }
   }
 
-  pub fn decode_compressed(&self, raw: &TiffIFD, width: usize, height: usize, cpp: usize, dummy: bool) -> Result<Vec<u16>,String> {
-    if let Some(offsets) = raw.find_entry(Tag::StripOffsets) { // We're in a normal offset situation
-      if offsets.count() != 1 {
-        return Err("DNG: files with more than one slice not supported yet".to_string())
-      }
-      let offset = offsets.get_usize(0);
-      let src = &self.buffer[offset..];
-      let mut out = alloc_image_ok!(width, height, dummy);
-      let decompressor = LjpegDecompressor::new(src)?;
-      decompressor.decode(&mut out, 0, width, width, height, dummy)?;
-      Ok(out)
+  // Strips are handled as tiles that are as wide as the image
+  fn get_tiles(&self, raw: &TiffIFD, width: usize, height: usize, cpp: usize) -> Result<DngTiles,String> {
+    let (offsets, counts, twidth, tlength) = if let Some(offsets) = raw.find_entry(Tag::StripOffsets) {
+      let rows = match raw.find_entry(Tag::RowsPerStrip) {
+        Some(rows) => cmp::min(rows.get_usize(0), height),
+        None => height,
+      };
+      (offsets, raw.find_entry(Tag::StripByteCounts), width, rows)
     } else if let Some(offsets) = raw.find_entry(Tag::TileOffsets) {
-      // They've gone with tiling
-      let twidth = fetch_tag!(raw, Tag::TileWidth).get_usize(0) * cpp;
-      let tlength = fetch_tag!(raw, Tag::TileLength).get_usize(0);
-      let coltiles = (width-1)/twidth + 1;
-      let rowtiles = (height-1)/tlength + 1;
-      if coltiles*rowtiles != offsets.count() {
-        return Err(format!("DNG: trying to decode {} tiles from {} offsets",
-                           coltiles*rowtiles, offsets.count()).to_string())
-      }
-
-      Ok(decode_threaded_multiline(width, height, tlength, dummy, &(|strip: &mut [u16], row| {
-        let row = row / tlength;
-        for col in 0..coltiles {
-          let offset = offsets.get_usize(row*coltiles+col);
-          let src = &self.buffer[offset..];
-          let decompressor = LjpegDecompressor::new(src).unwrap();
-          let bwidth = cmp::min(width, (col+1)*twidth) - col*twidth;
-          let blength = cmp::min(height, (row+1)*tlength) - row*tlength;
-          // FIXME: instead of unwrap() we need to propagate the error
-          decompressor.decode(strip, col*twidth, width, bwidth, blength, dummy).unwrap();
+      (offsets, raw.find_entry(Tag::TileByteCounts),
+       fetch_tag!(raw, Tag::TileWidth).get_usize(0) * cpp,
+       fetch_tag!(raw, Tag::TileLength).get_usize(0))
+    } else {
+      return Err("DNG: didn't find tiles or strips".to_string())
+    };
+    if twidth == 0 || tlength == 0 {
+      return Err("DNG: invalid tile size".to_string())
+    }
+
+    let coltiles = (width-1)/twidth + 1;
+    let rowtiles = (height-1)/tlength + 1;
+    if coltiles*rowtiles != offsets.count() {
+      return Err(format!("DNG: trying to decode {} tiles from {} offsets",
+                         coltiles*rowtiles, offsets.count()).to_string())
+    }
+
+    let mut tiles = Vec::new();
+    for i in 0..offsets.count() {
+      let offset = offsets.get_usize(i);
+      if offset > self.buffer.len() {
+        return Err("DNG: tile starts beyond the end of the file".to_string())
+      }
+      // Without byte counts a tile can extend until the end of the file
+      let end = match counts {
+        Some(ref counts) => cmp::min(offset + counts.get_usize(i), self.buffer.len()),
+        None => self.buffer.len(),
+      };
+      tiles.push((offset, end));
+    }
+
+    Ok(DngTiles {
+      tiles: tiles,
+      twidth: twidth,
+      tlength: tlength,
+      coltiles: coltiles,
+    })
+  }
+
+  pub fn decode_compressed(&self, raw: &TiffIFD, width: usize, height: usize, cpp: usize, dummy: bool) -> Result<Vec<u16>,String> {
+    let layout = self.get_tiles(raw, width, height, cpp)?;
+    let twidth = layout.twidth;
+    let tlength = layout.tlength;
+    let coltiles = layout.coltiles;
+
+    decode_threaded_multiline_result(width, height, tlength, dummy, &(|strip: &mut [u16], row| {
+      let row = row / tlength;
+      for col in 0..coltiles {
+        let (offset, _) = layout.tiles[row*coltiles+col];
+        let src = &self.buffer[offset..];
+        let decompressor = LjpegDecompressor::new(src)?;
+        let bwidth = cmp::min(width, (col+1)*twidth) - col*twidth;
+        let blength = cmp::min(height, (row+1)*tlength) - row*tlength;
+        decompressor.decode(strip, col*twidth, width, bwidth, blength, dummy)?;
+      }
+      Ok(())
+    }))
+  }
+
+  pub fn decode_deflate(&self, raw: &TiffIFD, width: usize, height: usize, cpp: usize, dummy: bool) -> Result<Vec<u16>,String> {
+    let layout = self.get_tiles(raw, width, height, cpp)?;
+    let bps = fetch_tag!(raw, Tag::BitsPerSample).get_usize(0);
+    if bps != 8 && bps != 16 {
+      return Err(format!("DNG: Don't know about {} bps deflate images", bps).to_string())
+    }
+    let stride = match raw.find_entry(Tag::Predictor).map(|p| p.get_u32(0)).unwrap_or(1) {
+      1 => 0,
+      2 => cpp,
+      34892 => cpp * 2,
+      34893 => cpp * 4,
+      p => return Err(format!("DNG: Don't know about predictor {}", p).to_string()),
+    };
+    let little = self.tiff.little_endian();
+
+    let mut out = alloc_image_ok!(width, height, dummy);
+    let tiles = layout.decode(self.buffer, bps, |data, twidth, rows| {
+      let mut vals: Vec<u16> = if bps == 8 {
+        data.iter().map(|&v| v as u16).collect()
+      } else if little {
+        data.chunks_exact(2).map(|v| LEu16(v, 0)).collect()
+      } else {
+        data.chunks_exact(2).map(|v| BEu16(v, 0)).collect()
+      };
+      // Undo the horizontal differencing, wrapping around at the sample size
+      if stride > 0 {
+        let mask = ((1u32 << bps) - 1) as u16;
+        for row in vals.chunks_exact_mut(twidth).take(rows) {
+          for i in stride..twidth {
+            row[i] = row[i].wrapping_add(row[i-stride]) & mask;
+          }
+        }
+      }
+      Ok(vals)
+    })?;
+    layout.copy_tiles(&tiles, &mut out, width, height);
+
+    if bps == 8 {
+      self.linearize_8bit(&mut out);
+    }
+    Ok(out)
+  }
+
+  // Lossy DNGs store each tile as a baseline or progressive JPEG with 8 bit samples
+  pub fn decode_lossy(&self, raw: &TiffIFD, width: usize, height: usize, cpp: usize, dummy: bool) -> Result<Vec<u16>,String> {
+    let mut out = self.decode_dct(raw, width, height, cpp, dummy)?;
+    self.linearize_8bit(&mut out);
+    Ok(out)
+  }
+
+  fn decode_dct(&self, raw: &TiffIFD, width: usize, height: usize, cpp: usize, dummy: bool) -> Result<Vec<u16>,String> {
+    let layout = self.get_tiles(raw, width, height, cpp)?;
+    let mut out = alloc_image_ok!(width, height, dummy);
+    let tiles = layout.map_tiles(self.buffer, |src| {
+      let image = DctDecompressor::new(src).decode()?;
+      if image.cpp != cpp {
+        return Err(format!("DNG: JPEG tile has {} components, expected {}", image.cpp, cpp).to_string())
+      }
+      // Tiles should be encoded at the full tile size but don't rely on it
+      let twidth = layout.twidth;
+      let iwidth = cmp::min(image.width * cpp, twidth);
+      let rows = cmp::min(image.height, layout.tlength);
+      let mut vals = vec![0 as u16; twidth * rows];
+      for (row, line) in vals.chunks_exact_mut(twidth).enumerate() {
+        let src = &image.data[row*image.width*cpp..];
+        for (o, &v) in line[..iwidth].iter_mut().zip(src.iter()) {
+          *o = v as u16;
+        }
+      }
+      Ok(vals)
+    })?;
+    layout.copy_tiles(&tiles, &mut out, width, height);
+    Ok(out)
+  }
+
+  fn linearize_8bit(&self, out: &mut [u16]) {
+    if let Some(linearization) = self.tiff.find_entry(Tag::Linearization) {
+      if linearization.count() < 256 {
+        return
+      }
+      let curve: Vec<u16> = (0..256).map(|i| linearization.get_u32(i) as u16).collect();
+      for val in out.iter_mut() {
+        *val = curve[*val as usize & 0xff];
+      }
+    }
+  }
+
+  pub fn decode_float_deflate(&self, raw: &TiffIFD, width: usize, height: usize, cpp: usize, dummy: bool) -> Result<Vec<f32>,String> {
+    let layout = self.get_tiles(raw, width, height, cpp)?;
+    let bps = fetch_tag!(raw, Tag::BitsPerSample).get_usize(0);
+    let predictor = match raw.find_entry(Tag::Predictor) {
+      Some(p) => p.get_u32(0),
+      None => 1,
+    };
+    let little = self.tiff.little_endian();
+
+    // Check the parameters before decoding anything
+    decode_float_rows(&[], 0, 0, cpp, bps, predictor, little, true)?;
+    if dummy {
+      return Ok(vec![0.0])
+    }
+    let mut out = vec![0.0 as f32; width*height];
+    let tiles = layout.decode(self.buffer, bps, |data, twidth, rows| {
+      decode_float_rows(data, twidth, rows, cpp, bps, predictor, little, false)
+    })?;
+    layout.copy_tiles(&tiles, &mut out, width, height);
+    Ok(out)
+  }
+
+  pub fn decode_float_uncompressed(&self, raw: &TiffIFD, width: usize, height: usize, cpp: usize, dummy: bool) -> Result<Vec<f32>,String> {
+    let offsets = fetch_tag!(raw, Tag::StripOffsets);
+    let joined;
+    let src = if offsets.count() == 1 {
+      &self.buffer[offsets.get_usize(0)..]
+    } else {
+      joined = self.get_uncompressed(raw)?;
+      &joined[..]
+    };
+    let bps = fetch_tag!(raw, Tag::BitsPerSample).get_usize(0);
+    let predictor = match raw.find_entry(Tag::Predictor) {
+      Some(p) => p.get_u32(0),
+      None => 1,
+    };
+    if src.len() < width*height*bps/8 {
+      return Err("DNG: float image data is truncated".to_string())
+    }
+    decode_float_rows(src, width, height, cpp, bps, predictor, self.tiff.little_endian(), dummy)
+  }
+}
+
+#[derive(Debug, Clone)]
+struct DngTiles {
+  tiles: Vec<(usize, usize)>,
+  twidth: usize,
+  tlength: usize,
+  coltiles: usize,
+}
+
+impl DngTiles {
+  // Inflate and decode each tile in parallel, tiles at the bottom of the image may
+  // have less rows than the full tile length
+  fn decode<T, F>(&self, buffer: &[u8], bps: usize, closure: F) -> Result<Vec<Vec<T>>,String>
+    where T: Send, F: Fn(&[u8], usize, usize) -> Result<Vec<T>,String>+Sync {
+    let rowbytes = self.twidth * bps / 8;
+    self.map_tiles(buffer, |src| {
+      let data = inflate_zlib(src, rowbytes * self.tlength)?;
+      let rows = cmp::min(data.len() / rowbytes, self.tlength);
+      closure(&data[..rows*rowbytes], self.twidth, rows)
+    })
+  }
+
+  // Run the closure over the raw bytes of each tile in parallel
+  fn map_tiles<T, F>(&self, buffer: &[u8], closure: F) -> Result<Vec<Vec<T>>,String>
+    where T: Send, F: Fn(&[u8]) -> Result<Vec<T>,String>+Sync {
+    self.tiles.par_iter().map(|&(start, end)| closure(&buffer[start..end])).collect()
+  }
+
+  fn copy_tiles<T: Copy>(&self, tiles: &[Vec<T>], out: &mut [T], width: usize, height: usize) {
+    for (i, tile) in tiles.iter().enumerate() {
+      let x = (i % self.coltiles) * self.twidth;
+      let y = (i / self.coltiles) * self.tlength;
+      let bwidth = cmp::min(width, x + self.twidth) - x;
+      for (row, line) in tile.chunks_exact(self.twidth).enumerate().take(height - y) {
+        let start = (y + row) * width + x;
+        out[start..start+bwidth].copy_from_slice(&line[..bwidth]);
+      }
+    }
+  }
+}
+
+// Decode rows of floating point samples, undoing the floating point predictors first
+// if needed. Predicted data is always big endian as the bytes get split into planes.
+pub fn decode_float_rows(src: &[u8], width: usize, height: usize, cpp: usize, bps: usize,
+                         predictor: u32, little_endian: bool, dummy: bool) -> Result<Vec<f32>,String> {
+  let bytes = match bps {
+    16 | 24 | 32 => bps / 8,
+    _ => return Err(format!("DNG: Don't know about {} bps float images", bps).to_string()),
+  };
+  let stride = match predictor {
+    1 => 0,
+    3 => cpp,
+    34894 => cpp * 2,
+    34895 => cpp * 4,
+    p => return Err(format!("DNG: Don't know about predictor {} for float images", p).to_string()),
+  };
+
+  if dummy {
+    return Ok(vec![0.0])
+  }
+  let mut out = vec![0.0 as f32; width*height];
+  out.par_chunks_mut(width).enumerate().for_each(|(row, line)| {
+    let inb = &src[row*width*bytes..(row+1)*width*bytes];
+    if stride > 0 {
+      let mut diffs = inb.to_vec();
+      for i in stride..diffs.len() {
+        diffs[i] = diffs[i].wrapping_add(diffs[i-stride]);
+      }
+      // The bytes of each sample are stored in separate planes, most significant first
+      let mut sample = [0 as u8; 4];
+      for (col, out) in line.iter_mut().enumerate() {
+        for b in 0..bytes {
+          sample[b] = diffs[b*width + col];
         }
-      })))
+        *out = float_from_bytes(&sample, bytes, false);
+      }
     } else {
-      Err("DNG: didn't find tiles or strips".to_string())
+      for (out, sample) in line.iter_mut().zip(inb.chunks_exact(bytes)) {
+        *out = float_from_bytes(sample, bytes, little_endian);
+      }
+    }
+  });
+  Ok(out)
+}
+
+fn float_from_bytes(sample: &[u8], bytes: usize, little_endian: bool) -> f32 {
+  match (bytes, little_endian) {
+    (2, true)  => fp16_to_f32(LEu16(sample, 0)),
+    (2, false) => fp16_to_f32(BEu16(sample, 0)),
+    (3, true)  => fp24_to_f32((sample[2] as u32) << 16 | (sample[1] as u32) << 8 | sample[0] as u32),
+    (3, false) => fp24_to_f32((sample[0] as u32) << 16 | (sample[1] as u32) << 8 | sample[2] as u32),
+    (_, true)  => fp32_to_f32(LEu32(sample, 0)),
+    (_, false) => fp32_to_f32(BEu32(sample, 0)),
+  }
+}
+
+const D50_XY: (f32, f32) = (0.3457, 0.3585);
+
+// Correlated color temperature of the EXIF LightSource values, 0 if unknown
+fn illuminant_temperature(illuminant: u16) -> f32 {
+  match illuminant {
+    3 | 17 => 2850.0,  // Tungsten, Standard light A
+    24 => 3200.0,      // ISO studio tungsten
+    23 => 5000.0,      // D50
+    1 | 4 | 9 | 18 | 20 => 5500.0, // Daylight, Flash, Fine weather, Standard light B, D55
+    10 | 19 | 21 => 6500.0, // Cloudy, Standard light C, D65
+    11 | 22 => 7500.0, // Shade, D75
+    12 => 6430.0,      // Daylight fluorescent
+    13 => 5000.0,      // Day white fluorescent
+    2 | 14 => 4150.0,  // Fluorescent, Cool white fluorescent
+    15 => 3450.0,      // White fluorescent
+    16 => 2940.0,      // Warm white fluorescent
+    _ => 0.0,
+  }
+}
+
+// Robertson's isotemperature lines as (reciprocal megakelvin, u, v, slope)
+const ROBERTSON: [(f32,f32,f32,f32);31] = [
+  (  0.0, 0.18006, 0.26352,  -0.24341), ( 10.0, 0.18066, 0.26589,  -0.25479),
+  ( 20.0, 0.18133, 0.26846,  -0.26876), ( 30.0, 0.18208, 0.27119,  -0.28539),
+  ( 40.0, 0.18293, 0.27407,  -0.30470), ( 50.0, 0.18388, 0.27709,  -0.32675),
+  ( 60.0, 0.18494, 0.28021,  -0.35156), ( 70.0, 0.18611, 0.28342,  -0.37915),
+  ( 80.0, 0.18740, 0.28668,  -0.40955), ( 90.0, 0.18880, 0.28997,  -0.44278),
+  (100.0, 0.19032, 0.29326,  -0.47888), (125.0, 0.19462, 0.30141,  -0.58204),
+  (150.0, 0.19962, 0.30921,  -0.70471), (175.0, 0.20525, 0.31647,  -0.84901),
+  (200.0, 0.21142, 0.32312,  -1.0182 ), (225.0, 0.21807, 0.32909,  -1.2168 ),
+  (250.0, 0.22511, 0.33439,  -1.4512 ), (275.0, 0.23247, 0.33904,  -1.7298 ),
+  (300.0, 0.24010, 0.34308,  -2.0637 ), (325.0, 0.24702, 0.34655,  -2.4681 ),
+  (350.0, 0.25591, 0.34951,  -2.9641 ), (375.0, 0.26400, 0.35200,  -3.5814 ),
+  (400.0, 0.27218, 0.35407,  -4.3633 ), (425.0, 0.28039, 0.35577,  -5.3762 ),
+  (450.0, 0.28863, 0.35714,  -6.7262 ), (475.0, 0.29685, 0.35823,  -8.5955 ),
+  (500.0, 0.30505, 0.35907, -11.324  ), (525.0, 0.31320, 0.35968, -15.628  ),
+  (550.0, 0.32129, 0.36011, -23.325  ), (575.0, 0.32931, 0.36038, -40.770  ),
+  (600.0, 0.33724, 0.36051, -116.45  ),
+];
+
+// Correlated color temperature of a CIE xy white point using Robertson's method
+fn xy_to_temperature(xy: (f32, f32)) -> f32 {
+  let denom = 1.5 - xy.0 + 6.0 * xy.1;
+  let (u, v) = (2.0 * xy.0 / denom, 3.0 * xy.1 / denom);
+  let mut last_dt = 0.0;
+  for i in 1..ROBERTSON.len() {
+    let (_, lu, lv, slope) = ROBERTSON[i];
+    let len = (1.0 + slope * slope).sqrt();
+    let dt = -(u - lu) * slope / len + (v - lv) / len;
+    if dt <= 0.0 || i == ROBERTSON.len() - 1 {
+      let dt = -dt.min(0.0);
+      let f = if i == 1 { 0.0 } else { dt / (last_dt + dt) };
+      return 1.0e6 / (ROBERTSON[i-1].0 * f + ROBERTSON[i].0 * (1.0 - f))
     }
+    last_dt = dt;
+  }
+  unreachable!()
+}
+
+fn xyz_to_xy(xyz: [f32;3]) -> (f32, f32) {
+  let sum = xyz[0] + xyz[1] + xyz[2];
+  if sum > 0.0 {
+    (xyz[0] / sum, xyz[1] / sum)
+  } else {
+    D50_XY
+  }
+}
+
+// Build the XYZ to camera matrix for a white point by interpolating between the two
+// calibrations in inverse temperature and applying the camera calibration and analog
+// balance as described in the DNG spec
+fn xyz_to_camera(color: &DngColorData, colors: usize, xy: (f32, f32)) -> [[f32;3];4] {
+  let temps = [illuminant_temperature(color.illuminants[0]), illuminant_temperature(color.illuminants[1])];
+  let weight = match (&color.color_matrices[0], &color.color_matrices[1]) {
+    (Some(_), None) => 1.0,
+    (None, Some(_)) => 0.0,
+    _ if temps[0] <= 0.0 || temps[1] <= 0.0 || temps[0] == temps[1] => 0.0,
+    _ => {
+      let temp = xy_to_temperature(xy);
+      let w = (1.0 / temp - 1.0 / temps[1]) / (1.0 / temps[0] - 1.0 / temps[1]);
+      w.max(0.0).min(1.0)
+    },
+  };
+
+  let identity = {
+    let mut m = [[0.0;4];4];
+    for i in 0..4 { m[i][i] = 1.0; }
+    m
+  };
+  let matrix = |i: usize| color.color_matrices[i].unwrap_or([[0.0;3];4]);
+  let calibration = |i: usize| color.camera_calibrations[i].unwrap_or(identity);
+  let (cm1, cm2) = (matrix(0), matrix(1));
+  let (cc1, cc2) = (calibration(0), calibration(1));
+
+  let mut out = [[0.0;3];4];
+  for row in 0..colors {
+    for col in 0..3 {
+      let mut sum = 0.0;
+      for k in 0..colors {
+        let cc = cc1[row][k] * weight + cc2[row][k] * (1.0 - weight);
+        let cm = cm1[k][col] * weight + cm2[k][col] * (1.0 - weight);
+        sum += cc * cm;
+      }
+      out[row][col] = color.analog_balance[row] * sum;
+    }
+  }
+  out
+}
+
+// Least squares solution of matrix * xyz = neutral
+fn solve_xyz(matrix: &[[f32;3];4], neutral: &[f32]) -> [f32;3] {
+  let mut ata = [[0.0 as f64;3];3];
+  let mut atb = [0.0 as f64;3];
+  for (row, &n) in matrix.iter().zip(neutral.iter()) {
+    for i in 0..3 {
+      atb[i] += row[i] as f64 * n as f64;
+      for j in 0..3 {
+        ata[i][j] += row[i] as f64 * row[j] as f64;
+      }
+    }
+  }
+  let det = ata[0][0] * (ata[1][1] * ata[2][2] - ata[1][2] * ata[2][1])
+          - ata[0][1] * (ata[1][0] * ata[2][2] - ata[1][2] * ata[2][0])
+          + ata[0][2] * (ata[1][0] * ata[2][1] - ata[1][1] * ata[2][0]);
+  if det.abs() < 1e-20 {
+    return [0.0, 0.0, 0.0]
+  }
+  // Cramer's rule
+  let mut out = [0.0;3];
+  for i in 0..3 {
+    let mut m = ata;
+    for j in 0..3 {
+      m[j][i] = atb[j];
+    }
+    let d = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+          - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+          + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    out[i] = (d / det) as f32;
+  }
+  out
+}
+
+// Reduce the full level model to per RGBE color levels by averaging the black level
+// pattern for each color and adding the average deltas
+fn levels_simplified(levels: &DngLevels, cfa: &CFA) -> ([f32;4], [f32;4]) {
+  let average = |vals: &[f32]| if vals.is_empty() { 0.0 } else { vals.iter().sum::<f32>() / vals.len() as f32 };
+  let deltas = average(&levels.black_delta_h) + average(&levels.black_delta_v);
+
+  let mut sums = [0.0 as f32;4];
+  let mut counts = [0 as usize;4];
+  if levels.cpp == 1 && cfa.is_valid() {
+    // Go over enough of the image to cover both the black and CFA patterns
+    let rows = levels.repeat_rows * cfa.height;
+    let cols = levels.repeat_cols * cfa.width;
+    for row in 0..rows {
+      for col in 0..cols {
+        let pattern = (row % levels.repeat_rows) * levels.repeat_cols + col % levels.repeat_cols;
+        let color = cfa.color_at(row + levels.origin.0, col + levels.origin.1);
+        sums[color] += levels.black[pattern];
+        counts[color] += 1;
+      }
+    }
+  } else {
+    for (i, &black) in levels.black.iter().enumerate() {
+      let sample = cmp::min(i % levels.cpp, 3);
+      sums[sample] += black;
+      counts[sample] += 1;
+    }
+  }
+  let first = (0..4).find(|&i| counts[i] > 0).unwrap_or(0);
+  let mut black = [0.0;4];
+  let mut white = [0.0;4];
+  for i in 0..4 {
+    // Colors that don't show up in the pattern get the values of the first one
+    let idx = if counts[i] > 0 { i } else { first };
+    black[i] = if counts[idx] > 0 { sums[idx] / counts[idx] as f32 } else { 0.0 } + deltas;
+    white[i] = levels.white_at(if levels.cpp > 1 { i } else { 0 });
   }
+  (black, white)
 }
@@ -0,0 +1,215 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::f32::NAN;
+
+use crate::decoders::*;
+use crate::decoders::basics::*;
+use crate::decoders::cfa::*;
+
+// Headers are made of 80 character cards in 2880 byte blocks, the data starts at the
+// first block after the END card
+const FITS_BLOCK: usize = 2880;
+const FITS_CARD: usize = 80;
+
+pub fn is_fits(buf: &[u8]) -> bool {
+  buf.len() >= FITS_BLOCK && match parse_card(&buf[0..FITS_CARD]) {
+    Some((key, val)) => key == "SIMPLE" && val == "T",
+    None => false,
+  }
+}
+
+// Returns the keyword and value of a card, None for cards without a value like comments
+fn parse_card(card: &[u8]) -> Option<(String, String)> {
+  if card[8..10] != b"= "[..] {
+    return None
+  }
+  let key = String::from_utf8_lossy(&card[0..8]).trim().to_string();
+  let rest = String::from_utf8_lossy(&card[10..]);
+  let rest = rest.trim_start();
+  let value = if rest.starts_with('\'') {
+    // Strings are quoted with '' standing for a quote and trailing spaces not significant
+    let mut value = String::new();
+    let mut chars = rest[1..].chars().peekable();
+    while let Some(c) = chars.next() {
+      if c == '\'' {
+        if chars.peek() == Some(&'\'') {
+          chars.next();
+        } else {
+          break
+        }
+      }
+      value.push(c);
+    }
+    value.trim_end().to_string()
+  } else {
+    rest.split('/').next().unwrap_or("").trim().to_string()
+  };
+  Some((key, value))
+}
+
+#[derive(Debug, Clone)]
+pub struct FitsDecoder<'a> {
+  buffer: &'a [u8],
+  cards: HashMap<String, String>,
+  data_offset: usize,
+}
+
+impl<'a> FitsDecoder<'a> {
+  pub fn new(buf: &'a [u8]) -> Result<FitsDecoder<'a>, String> {
+    let mut cards = HashMap::new();
+    let mut pos = 0;
+    loop {
+      if pos + FITS_CARD > buf.len() {
+        return Err("FITS: Couldn't find the end of the header".to_string())
+      }
+      let card = &buf[pos..pos+FITS_CARD];
+      pos += FITS_CARD;
+      if card[0..8] == b"END     "[..] {
+        break
+      }
+      if let Some((key, value)) = parse_card(card) {
+        cards.entry(key).or_insert(value);
+      }
+    }
+
+    Ok(FitsDecoder {
+      buffer: buf,
+      cards: cards,
+      data_offset: (pos + FITS_BLOCK - 1) / FITS_BLOCK * FITS_BLOCK,
+    })
+  }
+
+  fn get_str(&self, key: &str) -> Option<&str> {
+    self.cards.get(key).map(|v| v.as_str())
+  }
+
+  fn get_int(&self, key: &str) -> Result<i64, String> {
+    match self.get_str(key).map(|v| v.parse::<i64>()) {
+      Some(Ok(val)) => Ok(val),
+      Some(Err(_)) => Err(format!("FITS: Invalid value for {}", key)),
+      None => Err(format!("FITS: Couldn't find {}", key)),
+    }
+  }
+
+  fn get_float(&self, key: &str, default: f64) -> f64 {
+    // Fortran style exponents are allowed too
+    self.get_str(key).and_then(|v| v.replace('D', "E").parse::<f64>().ok()).unwrap_or(default)
+  }
+}
+
+impl<'a> Decoder for FitsDecoder<'a> {
+  fn image(&self, dummy: bool) -> Result<RawImage,String> {
+    let bitpix = self.get_int("BITPIX")?;
+    let naxis = self.get_int("NAXIS")?;
+    let width = self.get_int("NAXIS1")? as usize;
+    let height = self.get_int("NAXIS2")? as usize;
+    let planes = if naxis > 2 { self.get_int("NAXIS3")? as usize } else { 1 };
+    if naxis < 2 || naxis > 3 || (planes != 1 && planes != 3) {
+      return Err(format!("FITS: Don't know how to decode {} axis images with {} planes", naxis, planes))
+    }
+    let bytes = match bitpix {
+      8 | 16 | 32 | -32 | -64 => (bitpix.abs() / 8) as usize,
+      _ => return Err(format!("FITS: Don't know about BITPIX {}", bitpix)),
+    };
+    let size = width * height;
+    if self.data_offset + size * planes * bytes > self.buffer.len() {
+      return Err("FITS: image data is truncated".to_string())
+    }
+    let src = &self.buffer[self.data_offset..];
+    let bzero = self.get_float("BZERO", 0.0);
+    let bscale = self.get_float("BSCALE", 1.0);
+
+    let mut camera = Camera::new();
+    // There's no make and model in FITS, the closest is the instrument and telescope
+    let make = self.get_str("INSTRUME").unwrap_or("").to_string();
+    let model = self.get_str("TELESCOP").unwrap_or("").to_string();
+    camera.make = make.clone();
+    camera.clean_make = make;
+    camera.model = model.clone();
+    camera.clean_model = model;
+    camera.orientation = match self.get_str("ROWORDER") {
+      Some("TOP-DOWN") => Orientation::Normal,
+      Some("BOTTOM-UP") => Orientation::VerticalFlip,
+      _ => Orientation::Unknown,
+    };
+    if planes == 1 {
+      // Some software writes BAYERPAT = 'TRUE' or similar so only use actual patterns
+      if let Some(pattern) = self.get_str("BAYERPAT") {
+        if pattern.len() == 4 && pattern.bytes().all(|c| c == b'R' || c == b'G' || c == b'B') {
+          let cfa = CFA::new(pattern);
+          let xoff = cmp::max(self.get_float("XBAYROFF", 0.0) as i64, 0) as usize;
+          let yoff = cmp::max(self.get_float("YBAYROFF", 0.0) as i64, 0) as usize;
+          camera.cfa = cfa.shift(xoff, yoff);
+        }
+      }
+    }
+
+    // Planes are stored one after the other, interleave them into pixels
+    let index = |row: usize, col: usize, plane: usize| plane*size + row*width + col;
+
+    // Unsigned 8 bit and (signed or offset unsigned) 16 bit data can be used as is, the
+    // rest gets scaled into floats. Signed 16 bit data gets shifted up by 32768 so that
+    // negative values survive, with the black level moved along with it.
+    let integer = bscale == 1.0 && match bitpix {
+      8 => bzero == 0.0,
+      16 => bzero == 0.0 || bzero == 32768.0,
+      _ => false,
+    };
+    if integer {
+      let white = if bitpix == 8 { 255 } else { 65535 };
+      let black = if bitpix == 16 && bzero == 0.0 { 32768 } else { 0 };
+      camera.whitelevels = [white, white, white, white];
+      camera.blacklevels = [black, black, black, black];
+      let image = decode_threaded(width*planes, height, dummy, &(|out: &mut [u16], row| {
+        for col in 0..width {
+          for plane in 0..planes {
+            let pos = index(row, col, plane);
+            out[col*planes+plane] = if bitpix == 8 {
+              src[pos] as u16
+            } else {
+              (BEu16(src, pos*2) as i16 as i32 + 32768) as u16
+            };
+          }
+        }
+      }));
+      let mut img = RawImage::new(camera, width, height, [NAN,NAN,NAN,NAN], image, dummy);
+      img.cpp = planes;
+      return Ok(img)
+    }
+
+    let stored = |pos: usize| -> f64 {
+      match bitpix {
+        8 => src[pos] as f64,
+        16 => BEu16(src, pos*2) as i16 as f64,
+        32 => BEi32(src, pos*4) as f64,
+        -32 => BEf32(src, pos*4) as f64,
+        _ => BEf64(src, pos*8),
+      }
+    };
+    let mut data = vec![0.0 as f32; if dummy { 1 } else { size*planes }];
+    if !dummy {
+      for row in 0..height {
+        for col in 0..width {
+          for plane in 0..planes {
+            data[(row*width+col)*planes+plane] = (bzero + bscale * stored(index(row, col, plane))) as f32;
+          }
+        }
+      }
+    }
+    // Use DATAMAX when there is one, otherwise the full range of the stored integers.
+    // Floating point data is normalized to 1.0 by the common astro software.
+    let white = match bitpix {
+      8 => bzero + bscale * 255.0,
+      16 => bzero + bscale * 32767.0,
+      32 => bzero + bscale * 2147483647.0,
+      _ => bzero + bscale * 1.0,
+    };
+    let white = self.get_float("DATAMAX", white) as f32;
+
+    let mut img = RawImage::new(camera, width, height, [NAN,NAN,NAN,NAN], Vec::new(), dummy);
+    img.cpp = planes;
+    img.data = RawImageData::Float(data);
+    img.set_float_levels([0.0, 0.0, 0.0, 0.0], [white, white, white, white]);
+    Ok(img)
+  }
+}
@@ -1,9 +1,93 @@
+use std::cmp;
 use std::f32::NAN;
 
 use crate::decoders::*;
 use crate::decoders::tiff::*;
 use crate::decoders::basics::*;
 
+/// Sensor calibration shipped inside Phase One IIQ files. All values are for 16 bit
+/// data so image data needs to be shifted by `data_shift` before using them.
+#[derive(Debug, Clone, Default)]
+pub struct IiqCalibration {
+  /// IIQ raw format the image was stored in (1 and 2 uncompressed, 3 IIQ L, 5 IIQ S,
+  /// 8 IIQ L 16bit)
+  pub format: u32,
+  /// left shift that brings the decoded image data to 16 bits
+  pub data_shift: u32,
+  /// sensor temperature in degrees Celsius, NAN if unknown
+  pub sensor_temperature: f32,
+  /// column where the sensor is split into left and right halves
+  pub split_col: usize,
+  /// row where the sensor is split into top and bottom halves
+  pub split_row: usize,
+  /// black offsets for each row, left and right of `split_col`, already applied to the
+  /// image data
+  pub col_black: Vec<[i16;2]>,
+  /// black offsets for each column, above and below `split_row`, already applied to the
+  /// image data
+  pub row_black: Vec<[i16;2]>,
+  /// defective pixels and columns
+  pub defects: Vec<IiqDefect>,
+  /// gain to multiply each sensor quadrant by, indexed by [bottom][right]
+  pub quadrant_multipliers: Option<[[f32;2];2]>,
+  /// (input, output) points of a linearization curve for each sensor quadrant, indexed
+  /// by [bottom][right], interpolated with a natural cubic spline
+  pub quadrant_curves: Option<[[Vec<(u32,u32)>;2];2]>,
+  /// 65536 entry linearization curve, empty if there is none
+  pub curve: Vec<u16>,
+  /// first column `curve` applies to
+  pub curve_start_col: usize,
+  /// flat field corrections
+  pub flat_fields: Vec<IiqFlatField>,
+}
+
+/// A defect from the IIQ sensor calibration
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IiqDefect {
+  /// row of the defect, meaningless for column defects
+  pub row: usize,
+  /// column of the defect
+  pub col: usize,
+  /// type of the defect
+  pub kind: IiqDefectKind,
+}
+
+/// The types of defects found in IIQ calibration data
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum IiqDefectKind {
+  /// a single bad pixel
+  Pixel,
+  /// a whole bad column
+  Column,
+  /// some other defect type that isn't handled
+  Other(u16),
+}
+
+/// Grid of gains from the IIQ sensor calibration, interpolated over the sensor
+#[derive(Debug, Clone, PartialEq)]
+pub struct IiqFlatField {
+  /// first column of the area the grid covers
+  pub left: usize,
+  /// first row of the area the grid covers
+  pub top: usize,
+  /// width of the area the grid covers
+  pub width: usize,
+  /// height of the area the grid covers
+  pub height: usize,
+  /// horizontal spacing between grid points
+  pub step_h: usize,
+  /// vertical spacing between grid points
+  pub step_v: usize,
+  /// number of grid columns
+  pub points_h: usize,
+  /// number of grid rows
+  pub points_v: usize,
+  /// number of planes, 1 for all colors or 2 for red and blue only
+  pub planes: usize,
+  /// the gains, indexed by row, then column, then plane
+  pub gains: Vec<f32>,
+}
+
 #[derive(Debug, Clone)]
 pub struct IiqDecoder<'a> {
   buffer: &'a [u8],
@@ -34,29 +118,82 @@ impl<'a> Decoder for IiqDecoder<'a> {
     let mut height: usize = 0;
     let mut data_offset: usize = 0;
     let mut strip_offset: usize = 0;
-    let mut black: u16 = 0;
+    let mut black: u32 = 0;
+    let mut format: u32 = 3;
+    let mut key_offset: usize = 0;
+    let mut meta_offset: usize = 0;
+    let mut black_col: usize = 0;
+    let mut black_row: usize = 0;
+    let mut cal = IiqCalibration {
+      sensor_temperature: NAN,
+      ..Default::default()
+    };
     for _ in 0..entries {
       let tag = LEu32(self.buffer, off+pos);
       let data = LEu32(self.buffer, off+pos+12) as usize;
-      pos += 16;
       match tag {
         0x107 => wb_offset = data+8,
         0x108 => width = data,
         0x109 => height = data,
+        0x10e => format = data as u32,
         0x10f => data_offset = data+8,
+        0x110 => meta_offset = data+8,
+        0x112 => key_offset = off+pos+12,
+        0x210 => cal.sensor_temperature = f32::from_bits(data as u32),
         0x21c => strip_offset = data+8,
-        0x21d => black = (data>>2) as u16,
+        0x21d => black = data as u32,
+        0x222 => cal.split_col = data,
+        0x223 => black_col = data+8,
+        0x224 => cal.split_row = data,
+        0x225 => black_row = data+8,
         _ => {},
       }
+      pos += 16;
     }
 
     if width <= 0 || height <= 0 {
       return Err("IIQ: couldn't find width and height".to_string())
     }
 
-    let image = Self::decode_compressed(self.buffer, data_offset, strip_offset, width, height, dummy);
+    cal.format = format;
+    let mut image = match format {
+      1 | 2 => {
+        cal.data_shift = 0;
+        if key_offset == 0 {
+          return Err("IIQ: couldn't find the key for uncompressed data".to_string())
+        }
+        let key = LEu32(self.buffer, key_offset);
+        Self::decode_uncompressed(&self.buffer[data_offset..], key, format, width, height, dummy)
+      },
+      3 | 5 | 8 => {
+        cal.data_shift = if format == 8 { 0 } else { 2 };
+        let image = Self::decode_compressed(self.buffer, data_offset, strip_offset, width, height, dummy);
+        if format == 5 { Self::apply_iiq_s_curve(image) } else { image }
+      },
+      _ => return Err(format!("IIQ: Don't know how to decode raw format {}", format)),
+    };
+    let black = (black >> cal.data_shift) as u16;
 
-    ok_image_with_blacklevels(camera, width, height, self.get_wb(wb_offset)?, [black, black, black, black], image)
+    if black_col != 0 && black_col + height*4 <= self.buffer.len() {
+      cal.col_black = (0..height).map(|row| {
+        [LEu16(self.buffer, black_col+row*4) as i16, LEu16(self.buffer, black_col+row*4+2) as i16]
+      }).collect();
+    }
+    if black_row != 0 && black_row + width*4 <= self.buffer.len() {
+      cal.row_black = (0..width).map(|col| {
+        [LEu16(self.buffer, black_row+col*4) as i16, LEu16(self.buffer, black_row+col*4+2) as i16]
+      }).collect();
+    }
+    if !dummy {
+      Self::apply_black_offsets(&cal, &mut image, width, height);
+    }
+    if meta_offset != 0 {
+      self.parse_calibration(meta_offset, &mut cal);
+    }
+
+    let mut img = ok_image_with_blacklevels(camera, width, height, self.get_wb(wb_offset)?, [black, black, black, black], image)?;
+    img.iiq_calibration = Some(cal);
+    Ok(img)
   }
 }
 
@@ -67,6 +204,188 @@ impl<'a> IiqDecoder<'a> {
         LEf32(self.buffer, wb_offset+8), NAN])
   }
 
+  // The calibration is optional so anything that doesn't make sense is just skipped
+  fn parse_calibration(&self, meta_offset: usize, cal: &mut IiqCalibration) {
+    let buf = self.buffer;
+    if meta_offset + 12 > buf.len() {
+      return
+    }
+    let off = meta_offset + LEu32(buf, meta_offset+8) as usize;
+    if off + 8 > buf.len() {
+      return
+    }
+    let entries = LEu32(buf, off) as usize;
+    let mut pos = off + 8;
+    for _ in 0..entries {
+      if pos + 12 > buf.len() {
+        break
+      }
+      let tag = LEu32(buf, pos);
+      let len = LEu32(buf, pos+4) as usize;
+      let data = meta_offset + LEu32(buf, pos+8) as usize;
+      pos += 12;
+      if data + len > buf.len() {
+        continue
+      }
+      let data = &buf[data..data+len];
+      match tag {
+        0x400 => {
+          for d in data.chunks_exact(8) {
+            let kind = match LEu16(d, 4) {
+              129 => IiqDefectKind::Pixel,
+              131 | 137 => IiqDefectKind::Column,
+              v => IiqDefectKind::Other(v),
+            };
+            cal.defects.push(IiqDefect {
+              col: LEu16(d, 0) as usize,
+              row: LEu16(d, 2) as usize,
+              kind: kind,
+            });
+          }
+        },
+        0x401 => if let Some(ff) = Self::parse_flat_field(data, true, 1) { cal.flat_fields.push(ff) },
+        0x410 | 0x416 => if let Some(ff) = Self::parse_flat_field(data, false, 1) { cal.flat_fields.push(ff) },
+        0x40b => if let Some(ff) = Self::parse_flat_field(data, false, 2) { cal.flat_fields.push(ff) },
+        0x419 if len >= 36 => {
+          let poly: Vec<f64> = (0..8).map(|i| LEf32(data, 4+i*4) as f64).collect();
+          let temp = if cal.sensor_temperature.is_nan() { 0.0 } else { cal.sensor_temperature as f64 };
+          let offset = poly[3] + (temp - poly[7]) * poly[6] + 1.0;
+          cal.curve = (0..65536).map(|i| {
+            let i = i as f64;
+            ((poly[5]*i + offset)*i + poly[1]).max(0.0).min(65535.0) as u16
+          }).collect();
+          cal.curve_start_col = cal.split_col;
+        },
+        0x41a if len >= 16 => {
+          let poly: Vec<f64> = (0..4).map(|i| LEf32(data, i*4) as f64).collect();
+          cal.curve = (0..65536).map(|i| {
+            let i = i as f64;
+            let val = poly.iter().rev().fold(0.0, |acc, p| acc*i + p);
+            (val + i).max(0.0).min(65535.0) as u16
+          }).collect();
+          cal.curve_start_col = 0;
+        },
+        0x41e if len >= 76 && cal.quadrant_multipliers.is_none() => {
+          cal.quadrant_multipliers = Some([
+            [1.0 + LEf32(data, 16), 1.0 + LEf32(data, 40)],
+            [1.0 + LEf32(data, 56), 1.0 + LEf32(data, 72)],
+          ]);
+        },
+        0x41f if len >= 256 && cal.quadrant_curves.is_none() => {
+          let lc = |q: usize, i: usize| LEu32(data, (q*16+i)*4);
+          let refs: Vec<u32> = (0..16).map(|i| {
+            ((0..4).map(|q| lc(q, i)).sum::<u32>() + 2) >> 2
+          }).collect();
+          let quadrant = |q: usize| {
+            let mut points = vec![(0, 0)];
+            points.extend((0..16).map(|i| (lc(q, i), refs[i])));
+            if lc(q, 15) != 0 {
+              let last = ((refs[15] as u64 * 65535) / lc(q, 15) as u64) as u32;
+              points.push((last, last));
+            }
+            points.push((65535, 65535));
+            points
+          };
+          cal.quadrant_curves = Some([[quadrant(0), quadrant(1)], [quadrant(2), quadrant(3)]]);
+        },
+        0x431 if len >= 140 && cal.quadrant_curves.is_none() => {
+          let refs: Vec<u32> = (0..7).map(|i| LEu32(data, i*4)).collect();
+          let quadrant = |q: usize| {
+            let mut points = vec![(0, 0)];
+            points.extend((0..7).map(|i| {
+              (refs[i], ((refs[i] as u64 * LEu32(data, (7+q*7+i)*4) as u64) / 10000) as u32)
+            }));
+            points.push((65535, 65535));
+            points
+          };
+          cal.quadrant_curves = Some([[quadrant(0), quadrant(1)], [quadrant(2), quadrant(3)]]);
+        },
+        _ => {},
+      }
+    }
+  }
+
+  fn parse_flat_field(data: &[u8], is_float: bool, planes: usize) -> Option<IiqFlatField> {
+    if data.len() < 16 {
+      return None
+    }
+    let head: Vec<usize> = (0..8).map(|i| LEu16(data, i*2) as usize).collect();
+    if head[2] * head[3] * head[4] * head[5] == 0 {
+      return None
+    }
+    let points_h = (head[2] + head[4] - 1) / head[4];
+    let points_v = (head[3] + head[5] - 1) / head[5];
+    let count = points_h * points_v * planes;
+    let size = if is_float { 4 } else { 2 };
+    if data.len() < 16 + count * size {
+      return None
+    }
+    let gains = (0..count).map(|i| {
+      if is_float {
+        LEf32(data, 16+i*4)
+      } else {
+        LEu16(data, 16+i*2) as f32 / 32768.0
+      }
+    }).collect();
+    Some(IiqFlatField {
+      left: head[0],
+      top: head[1],
+      width: head[2],
+      height: head[3],
+      step_h: head[4],
+      step_v: head[5],
+      points_h: points_h,
+      points_v: points_v,
+      planes: planes,
+      gains: gains,
+    })
+  }
+
+  fn apply_black_offsets(cal: &IiqCalibration, image: &mut [u16], width: usize, height: usize) {
+    if cal.col_black.is_empty() && cal.row_black.is_empty() {
+      return
+    }
+    for row in 0..height {
+      for col in 0..width {
+        let mut offset = 0 as i32;
+        if let Some(cb) = cal.col_black.get(row) {
+          offset += cb[(col >= cal.split_col) as usize] as i32;
+        }
+        if let Some(rb) = cal.row_black.get(col) {
+          offset += rb[(row >= cal.split_row) as usize] as i32;
+        }
+        let pix = &mut image[row*width+col];
+        let val = ((*pix as i32) << cal.data_shift) + offset;
+        *pix = (cmp::max(0, val) >> cal.data_shift) as u16;
+      }
+    }
+  }
+
+  fn apply_iiq_s_curve(mut image: Vec<u16>) -> Vec<u16> {
+    for pix in image.iter_mut() {
+      if *pix < 256 {
+        *pix = ((*pix as f32) * (*pix as f32) / 3.969 + 0.5) as u16;
+      }
+    }
+    image
+  }
+
+  fn decode_uncompressed(buf: &[u8], key: u32, format: u32, width: usize, height: usize, dummy: bool) -> Vec<u16> {
+    let akey = key as u16;
+    let bkey = (key >> 16) as u16;
+    let mask: u16 = if format == 1 { 0x5555 } else { 0x1354 };
+
+    decode_threaded(width, height, dummy, &(|out: &mut [u16], row| {
+      let inb = &buf[row*width*2..];
+      for (i, pixout) in out.chunks_exact_mut(2).enumerate() {
+        let a = LEu16(inb, i*4) ^ akey;
+        let b = LEu16(inb, i*4+2) ^ bkey;
+        pixout[0] = (a & mask) | (b & !mask);
+        pixout[1] = (b & mask) | (a & !mask);
+      }
+    }))
+  }
+
   pub(crate) fn decode_compressed(buffer: &[u8], data_offset: usize, strip_offset: usize, width: usize, height: usize, dummy: bool) -> Vec<u16>{
     let lens: [u32; 10] = [8,7,6,9,11,10,5,12,14,13];
 
@@ -98,4 +417,209 @@ impl<'a> IiqDecoder<'a> {
       }
     }))
   }
-}
\ No newline at end of file
+}
+
+impl IiqCalibration {
+  /// Applies the sensor linearization, quadrant, defect and flat field corrections to
+  /// the image data
+  pub fn apply(&self, image: &mut RawImage) {
+    let width = image.width;
+    let height = image.height;
+    let cfa = image.cfa.clone();
+    let shift = self.data_shift;
+    let maxval = 65535 >> shift;
+    let data = match image.data {
+      RawImageData::Integer(ref mut data) => data,
+      RawImageData::Float(_) => return,
+    };
+    let get = |data: &[u16], row: isize, col: isize| -> i32 {
+      if row < 0 || col < 0 || row as usize >= height || col as usize >= width {
+        0
+      } else {
+        (data[row as usize*width + col as usize] as i32) << shift
+      }
+    };
+    let put = |data: &mut [u16], row: usize, col: usize, val: f32| {
+      data[row*width+col] = (val.max(0.0).min(65535.0) as u32 >> shift) as u16;
+    };
+
+    if !self.curve.is_empty() {
+      for row in 0..height {
+        for col in self.curve_start_col..width {
+          let pix = &mut data[row*width+col];
+          *pix = self.curve[((*pix as usize) << shift) & 0xffff] >> shift;
+        }
+      }
+    }
+
+    if let Some(ref curves) = self.quadrant_curves {
+      let luts = [[cubic_spline(&curves[0][0]), cubic_spline(&curves[0][1])],
+                  [cubic_spline(&curves[1][0]), cubic_spline(&curves[1][1])]];
+      for row in 0..height {
+        for col in 0..width {
+          let lut = &luts[(row >= self.split_row) as usize][(col >= self.split_col) as usize];
+          let pix = &mut data[row*width+col];
+          *pix = lut[((*pix as usize) << shift) & 0xffff] >> shift;
+        }
+      }
+    }
+
+    if let Some(qmult) = self.quadrant_multipliers {
+      for row in 0..height {
+        for col in 0..width {
+          let mult = qmult[(row >= self.split_row) as usize][(col >= self.split_col) as usize];
+          let val = ((data[row*width+col] as u32) << shift) as f32;
+          put(data, row, col, val * mult);
+        }
+      }
+    }
+
+    const DIR: [(isize,isize);12] = [(-1,-1),(-1,1),(1,-1),(1,1),(-2,0),(0,-2),(0,2),(2,0),
+                                     (-2,-2),(-2,2),(2,-2),(2,2)];
+    for defect in self.defects.iter() {
+      let col = defect.col;
+      if col >= width {
+        continue
+      }
+      match defect.kind {
+        IiqDefectKind::Column => {
+          for row in 0..height {
+            let (r, c) = (row as isize, col as isize);
+            let val = if cfa.color_at(row, col) == 1 {
+              let vals: Vec<i32> = DIR[0..4].iter().map(|&(dr,dc)| get(data, r+dr, c+dc)).collect();
+              let sum: i32 = vals.iter().sum();
+              let mut max = 0;
+              for i in 1..4 {
+                if ((vals[max] << 2) - sum).abs() < ((vals[i] << 2) - sum).abs() {
+                  max = i;
+                }
+              }
+              (sum - vals[max]) as f32 / 3.0 + 0.5
+            } else {
+              let sum: i32 = DIR[8..12].iter().map(|&(dr,dc)| get(data, r+dr, c+dc)).sum();
+              0.5 + sum as f32 * 0.0732233 + (get(data, r, c-2) + get(data, r, c+2)) as f32 * 0.3535534
+            };
+            put(data, row, col, val);
+          }
+        },
+        IiqDefectKind::Pixel => {
+          let row = defect.row;
+          if row >= height {
+            continue
+          }
+          let (r, c) = (row as isize, col as isize);
+          let start = if cfa.color_at(row, col) != 1 { 4 } else { 0 };
+          let sum: i32 = DIR[start..start+8].iter().map(|&(dr,dc)| get(data, r+dr, c+dc)).sum();
+          put(data, row, col, ((sum + 4) >> 3) as f32);
+        },
+        IiqDefectKind::Other(_) => {},
+      }
+    }
+
+    for ff in self.flat_fields.iter() {
+      for row in ff.top..cmp::min(height, ff.top + ff.height) {
+        for col in ff.left..cmp::min(width, ff.left + ff.width) {
+          let plane = if ff.planes == 1 {
+            0
+          } else {
+            match cfa.color_at(row, col) {
+              0 => 0,
+              2 => 1,
+              _ => continue,
+            }
+          };
+          let gain = ff.gain_at(row, col, plane);
+          let val = data[row*width+col];
+          data[row*width+col] = cmp::min(maxval as u32, (val as f32 * gain) as u32) as u16;
+        }
+      }
+    }
+  }
+}
+
+impl IiqFlatField {
+  /// Gain for a position of the image, bilinearly interpolated between grid points
+  pub fn gain_at(&self, row: usize, col: usize, plane: usize) -> f32 {
+    let v = (row.saturating_sub(self.top)) as f32 / self.step_v as f32;
+    let h = (col.saturating_sub(self.left)) as f32 / self.step_h as f32;
+    let v0 = cmp::min(v as usize, self.points_v - 1);
+    let h0 = cmp::min(h as usize, self.points_h - 1);
+    let v1 = cmp::min(v0 + 1, self.points_v - 1);
+    let h1 = cmp::min(h0 + 1, self.points_h - 1);
+    let fv = (v - v0 as f32).min(1.0);
+    let fh = (h - h0 as f32).min(1.0);
+    let g = |v: usize, h: usize| self.gains[(v*self.points_h + h)*self.planes + plane];
+    let top = g(v0,h0) * (1.0 - fh) + g(v0,h1) * fh;
+    let bottom = g(v1,h0) * (1.0 - fh) + g(v1,h1) * fh;
+    top * (1.0 - fv) + bottom * fv
+  }
+}
+
+// Build a 65536 entry curve from a natural cubic spline through the points, the same
+// way dcraw does for the Phase One quadrant linearization
+fn cubic_spline(points: &[(u32,u32)]) -> Vec<u16> {
+  // The spline needs strictly increasing inputs
+  let mut x: Vec<f32> = Vec::new();
+  let mut y: Vec<f32> = Vec::new();
+  for &(px, py) in points {
+    let px = px as f32 / 65535.0;
+    if x.last().map_or(true, |&last| px > last) {
+      x.push(px);
+      y.push(py as f32 / 65535.0);
+    }
+  }
+  let len = x.len();
+  if len < 2 {
+    return (0..65536).map(|i| i as u16).collect()
+  }
+
+  // Solve the tridiagonal system for the second derivatives, the last column of
+  // the matrix holds the right hand side
+  let mut a = vec![vec![0.0 as f32; len]; len];
+  let mut b = vec![0.0 as f32; len];
+  let mut c = vec![0.0 as f32; len];
+  let mut d = vec![0.0 as f32; len];
+  for i in (1..len).rev() {
+    b[i] = (y[i] - y[i-1]) / (x[i] - x[i-1]);
+    d[i-1] = x[i] - x[i-1];
+  }
+  for i in 1..len-1 {
+    a[i][i] = 2.0 * (d[i-1] + d[i]);
+    if i > 1 {
+      a[i][i-1] = d[i-1];
+      a[i-1][i] = d[i-1];
+    }
+    a[i][len-1] = 6.0 * (b[i+1] - b[i]);
+  }
+  for i in 1..len.saturating_sub(2) {
+    let v = a[i+1][i] / a[i][i];
+    for j in 1..len {
+      a[i+1][j] -= v * a[i][j];
+    }
+  }
+  for i in (1..len-1).rev() {
+    let acc: f32 = (i..len-1).map(|j| a[i][j] * c[j]).sum();
+    c[i] = (a[i][len-1] - acc) / a[i][i];
+  }
+
+  (0..65536).map(|i| {
+    let xo = i as f32 / 65535.0;
+    let mut yo = 0.0;
+    for j in 0..len-1 {
+      if x[j] <= xo && xo <= x[j+1] {
+        let v = xo - x[j];
+        yo = y[j] +
+          ((y[j+1] - y[j]) / d[j] - (2.0 * d[j] * c[j] + c[j+1] * d[j]) / 6.0) * v +
+          (c[j] * 0.5) * v * v +
+          ((c[j+1] - c[j]) / (6.0 * d[j])) * v * v * v;
+      }
+    }
+    if yo < 0.0 {
+      0
+    } else if yo >= 1.0 {
+      65535
+    } else {
+      (yo * 65535.0 + 0.5) as u16
+    }
+  }).collect()
+}
@@ -1,3 +1,5 @@
+use std::cmp;
+
 use crate::decoders::*;
 use crate::decoders::cfa::*;
 
@@ -25,6 +27,10 @@ pub struct RawImage {
   pub whitelevels: [u16;4],
   /// image blacklevels in RGBE order
   pub blacklevels: [u16;4],
+  /// exact black and white levels in RGBE order when `blacklevels` and `whitelevels` can't
+  /// represent them, as in `RawImageData::Float` images or fractional DNG black levels.
+  /// Use `float_blacklevels()` and `float_whitelevels()` to get the levels of any image.
+  pub float_levels: Option<([f32;4],[f32;4])>,
   /// matrix to convert XYZ to camera RGBE
   pub xyz_to_cam: [[f32;3];4],
   /// color filter array
@@ -36,6 +42,27 @@ pub struct RawImage {
   pub blackareas: Vec<(u64,u64,u64,u64)>,
   /// orientation of the image as indicated by the image metadata
   pub orientation: Orientation,
+  /// DNG opcode lists, empty for all other formats
+  pub opcodes: DngOpcodes,
+  /// DNG color calibration data the `xyz_to_cam` matrix was calculated from
+  pub dng_color: Option<DngColorData>,
+  /// full DNG black and white level description that `blacklevels` and `whitelevels`
+  /// are a simplified view of
+  pub dng_levels: Option<DngLevels>,
+  /// DNG default crop, scale and exposure the image is meant to be rendered with
+  pub dng_defaults: Option<DngDefaults>,
+  /// DNG 1.6 semantic masks (e.g., the skin, sky and hair masks of Apple ProRAW files)
+  pub semantic_masks: Vec<DngSemanticMask>,
+  /// Phase One sensor calibration for IIQ files
+  pub iiq_calibration: Option<IiqCalibration>,
+  /// geometry of Fuji SuperCCD images decoded in the native sensor layout
+  pub superccd: Option<SuperCcdLayout>,
+  /// (row, column) offset of this frame in a pixel shift file, the pixel at (row, col)
+  /// of the data sampled the scene where the pixel at (row+offset.0, col+offset.1) of a
+  /// frame with no offset did
+  pub pixel_shift: Option<(usize, usize)>,
+  /// timing and exposure of this frame for formats that record video
+  pub frame_info: Option<FrameInfo>,
   /// image data itself, has `width`\*`height`\*`cpp` elements
   pub data: RawImageData,
 }
@@ -49,6 +76,279 @@ pub enum RawImageData {
   Float(Vec<f32>),
 }
 
+/// Color calibration data from a DNG, for color pipelines that want to do their own
+/// interpolation between the two illuminants or use the forward matrices
+#[derive(Debug, Clone, PartialEq)]
+pub struct DngColorData {
+  /// CalibrationIlluminant1 and CalibrationIlluminant2 as EXIF LightSource values
+  pub illuminants: [u16;2],
+  /// ColorMatrix1 and ColorMatrix2, converting XYZ to camera RGBE
+  pub color_matrices: [Option<[[f32;3];4]>;2],
+  /// CameraCalibration1 and CameraCalibration2, per camera calibration of the color matrices
+  pub camera_calibrations: [Option<[[f32;4];4]>;2],
+  /// ForwardMatrix1 and ForwardMatrix2, converting white balanced camera RGBE to XYZ D50
+  pub forward_matrices: [Option<[[f32;4];3]>;2],
+  /// AnalogBalance, the gains that were applied to each channel before quantization
+  pub analog_balance: [f32;4],
+  /// white point of the image as CIE xy, used to interpolate between the illuminants
+  pub white_xy: (f32, f32),
+}
+
+/// Black and white levels following the DNG model, where the black level of a sample is
+/// a repeating pattern plus per row and per column deltas
+#[derive(Debug, Clone, PartialEq)]
+pub struct DngLevels {
+  /// position (top, left) in the image where the black level pattern and deltas start
+  pub origin: (usize, usize),
+  /// rows in the repeating black level pattern
+  pub repeat_rows: usize,
+  /// columns in the repeating black level pattern
+  pub repeat_cols: usize,
+  /// number of samples per pixel
+  pub cpp: usize,
+  /// black level pattern indexed by row, then column, then sample
+  pub black: Vec<f32>,
+  /// black level delta for each column starting at the origin
+  pub black_delta_h: Vec<f32>,
+  /// black level delta for each row starting at the origin
+  pub black_delta_v: Vec<f32>,
+  /// white level for each sample
+  pub white: Vec<f32>,
+}
+
+impl DngLevels {
+  /// Black level of a sample at a position of the full image
+  pub fn black_at(&self, row: usize, col: usize, sample: usize) -> f32 {
+    let row = row.saturating_sub(self.origin.0);
+    let col = col.saturating_sub(self.origin.1);
+    let pattern = ((row % self.repeat_rows) * self.repeat_cols + col % self.repeat_cols) * self.cpp + sample;
+    self.black[pattern]
+      + self.black_delta_v.get(row).cloned().unwrap_or(0.0)
+      + self.black_delta_h.get(col).cloned().unwrap_or(0.0)
+  }
+
+  /// White level of a sample
+  pub fn white_at(&self, sample: usize) -> f32 {
+    self.white[cmp::min(sample, self.white.len() - 1)]
+  }
+}
+
+/// Default rendering parameters from a DNG. Crops are relative to the top left of the
+/// active area, which is what `crops` already removes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DngDefaults {
+  /// DefaultCropOrigin as (x, y)
+  pub crop_origin: (f32, f32),
+  /// DefaultCropSize as (width, height)
+  pub crop_size: (f32, f32),
+  /// DefaultScale as (horizontal, vertical), different from each other for sensors with
+  /// non-square pixels
+  pub scale: (f32, f32),
+  /// BestQualityScale, extra scale to apply to get the best quality output
+  pub best_quality_scale: f32,
+  /// DefaultUserCrop as (top, left, bottom, right) fractions of the default crop
+  pub user_crop: Option<(f32, f32, f32, f32)>,
+  /// BaselineExposure, in EV, to apply to get a normal exposure
+  pub baseline_exposure: f32,
+}
+
+impl DngDefaults {
+  /// Crops of the full image (top, right, bottom, left) that apply both the active area
+  /// crops and the default crop, rounded to whole pixels
+  pub fn crops(&self, image: &RawImage) -> [usize;4] {
+    let top = image.crops[0] + self.crop_origin.1.max(0.0).round() as usize;
+    let left = image.crops[3] + self.crop_origin.0.max(0.0).round() as usize;
+    let bottom = cmp::min(top + self.crop_size.1.max(0.0).round() as usize, image.height - image.crops[2]);
+    let right = cmp::min(left + self.crop_size.0.max(0.0).round() as usize, image.width - image.crops[1]);
+    [top, image.width - cmp::max(right, left), image.height - cmp::max(bottom, top), left]
+  }
+
+  /// Size (width, height) the default crop should be rendered at so that it has the
+  /// correct aspect ratio
+  pub fn output_size(&self) -> (f32, f32) {
+    (self.crop_size.0 * self.scale.0 * self.best_quality_scale,
+     self.crop_size.1 * self.scale.1 * self.best_quality_scale)
+  }
+}
+
+/// Semantic mask from a DNG 1.6 file, an auxiliary image marking how much each pixel
+/// belongs to some kind of content. Masks are usually smaller than the main image and
+/// cover all of it unless `area` says otherwise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DngSemanticMask {
+  /// SemanticName, the kind of content the mask marks (e.g., "Skin" or "Sky")
+  pub name: String,
+  /// SemanticInstanceID, which instance of the content the mask is for if there are several
+  pub instance_id: Option<String>,
+  /// MaskSubArea, the (top, left, bottom, right) area the mask covers as stored in the file
+  pub area: Option<[usize;4]>,
+  /// width of the mask
+  pub width: usize,
+  /// height of the mask
+  pub height: usize,
+  /// value of a pixel that fully belongs to the content, 0 being not at all
+  pub whitelevel: u16,
+  /// mask values, `width`\*`height` of them
+  pub data: Vec<u16>,
+}
+
+/// Per-frame metadata of a raw video frame
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FrameInfo {
+  /// frame number within the recording
+  pub number: usize,
+  /// time the frame was captured in seconds since the start of the recording, since
+  /// midnight for a single CinemaDNG frame as its timecode is all there is
+  pub timestamp: f64,
+  /// exposure time in seconds, NAN if not recorded
+  pub exposure_time: f32,
+  /// ISO speed, 0 if not recorded
+  pub iso: u32,
+  /// frame rate of the recording, NAN if not recorded
+  pub fps: f32,
+  /// SMPTE timecode of the frame if the format records one
+  pub timecode: Option<TimeCode>,
+  /// UTC time the frame was captured in seconds since the Unix epoch if the format records it
+  pub utc_time: Option<f64>,
+}
+
+/// SMPTE timecode of a video frame
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TimeCode {
+  /// hours, 0 to 23
+  pub hours: u8,
+  /// minutes, 0 to 59
+  pub minutes: u8,
+  /// seconds, 0 to 59
+  pub seconds: u8,
+  /// frame number within the second
+  pub frames: u8,
+  /// whether the frame count skips numbers to keep NTSC rates in sync with clock time
+  pub drop_frame: bool,
+}
+
+impl TimeCode {
+  // Parses the 8 byte packed BCD form (SMPTE 12M) used by the DNG TimeCodes tag
+  #[doc(hidden)] pub fn from_smpte(data: &[u8]) -> TimeCode {
+    let bcd = |val: u8, tens_mask: u8| (((val >> 4) & tens_mask) * 10 + (val & 0x0f)) as u8;
+    TimeCode {
+      hours: bcd(data[3], 0x03),
+      minutes: bcd(data[2], 0x07),
+      seconds: bcd(data[1], 0x07),
+      frames: bcd(data[0], 0x03),
+      drop_frame: data[0] & 0x40 != 0,
+    }
+  }
+
+  /// Time in seconds since midnight, the frames are converted with the nominal (rounded)
+  /// frame rate and ignored if it's not known. Drop frame timecodes are treated as nominal time.
+  pub fn to_seconds(&self, fps: f32) -> f64 {
+    let secs = (self.hours as u32 * 3600 + self.minutes as u32 * 60 + self.seconds as u32) as f64;
+    if fps.is_finite() && fps > 0.0 {
+      secs + self.frames as f64 / (fps.round() as f64)
+    } else {
+      secs
+    }
+  }
+}
+
+/// Geometry of a Fuji SuperCCD image in the native sensor layout. The photosites sit on
+/// a grid that is rotated by 45 degrees from the scene so each native row is a diagonal
+/// of the rotated image, with every other row offset by half a pixel.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SuperCcdLayout {
+  /// the sensor uses the alternate layout of the S3 Pro/S5 Pro generation
+  pub alt: bool,
+  /// area of the native image with sensor data, as top, left, width, height
+  pub area: [usize;4],
+  /// width of the image once rotated
+  pub rotated_width: usize,
+  /// height of the image once rotated
+  pub rotated_height: usize,
+}
+
+impl SuperCcdLayout {
+  #[doc(hidden)] pub fn new(alt: bool, crops: [usize;4], width: usize, height: usize) -> SuperCcdLayout {
+    let areawidth = width - crops[1] - crops[3];
+    let areaheight = height - crops[2] - crops[0];
+    let rotated_width = if alt {
+      areaheight + areawidth/2
+    } else {
+      areawidth + areaheight/2
+    };
+    SuperCcdLayout {
+      alt: alt,
+      area: [crops[0], crops[3], areawidth, areaheight],
+      rotated_width: rotated_width,
+      rotated_height: rotated_width - 1,
+    }
+  }
+
+  /// Position (row, column) a pixel of the native image ends up in once rotated, None
+  /// for pixels outside the sensor area
+  pub fn rotated_position(&self, row: usize, col: usize) -> Option<(usize, usize)> {
+    let [top, left, width, height] = self.area;
+    if row < top || col < left || row >= top + height || col >= left + width {
+      return None
+    }
+    let (row, col) = (row - top, col - left);
+    let (out_row, out_col) = if self.alt {
+      (self.rotated_width as isize - (height + 1 + (col >> 1)) as isize + row as isize,
+       (((col+1) >> 1) + row) as isize)
+    } else {
+      ((width - 1 - col + (row >> 1)) as isize, (((row+1) >> 1) + col) as isize)
+    };
+    if out_row < 0 || out_row as usize >= self.rotated_height || out_col as usize >= self.rotated_width {
+      None
+    } else {
+      Some((out_row as usize, out_col as usize))
+    }
+  }
+
+  /// Color filter pattern of the native image given the one of the rotated image
+  pub fn native_cfa(&self, rotated: &CFA) -> CFA {
+    if !rotated.is_valid() {
+      return rotated.clone()
+    }
+    // Both layouts repeat every 4 pixels in one direction and 2 in the other so a
+    // 12x12 pattern covers them
+    let [top, left, _, _] = self.area;
+    let mut pattern = String::new();
+    for prow in 0..12 {
+      for pcol in 0..12 {
+        // find the pixel in the sensor area at that position of the pattern
+        let row = top + (prow + 12 - top % 12) % 12;
+        let col = left + (pcol + 12 - left % 12) % 12;
+        let (r, c) = self.rotated_position(row, col).unwrap_or((0, 0));
+        pattern.push(match rotated.color_at(r, c) {
+          0 => 'R',
+          1 => 'G',
+          2 => 'B',
+          _ => 'E',
+        });
+      }
+    }
+    CFA::new(&pattern)
+  }
+}
+
+pub fn levels_to_f32(levels: [u16;4]) -> [f32;4] {
+  [levels[0] as f32, levels[1] as f32, levels[2] as f32, levels[3] as f32]
+}
+
+pub fn levels_to_u16(levels: [f32;4]) -> [u16;4] {
+  [levels[0] as u16, levels[1] as u16, levels[2] as u16, levels[3] as u16]
+}
+
+// Only keep float levels around when the integer ones would lose something
+pub fn exact_float_levels(black: [f32;4], white: [f32;4]) -> Option<([f32;4],[f32;4])> {
+  if levels_to_f32(levels_to_u16(black)) == black && levels_to_f32(levels_to_u16(white)) == white {
+    None
+  } else {
+    Some((black, white))
+  }
+}
+
 impl RawImage {
   #[doc(hidden)] pub fn new(camera: Camera, width: usize, height: usize, wb_coeffs: [f32;4], image: Vec<u16>, dummy: bool) -> RawImage {
     let blacks = if !dummy && (camera.blackareah.1 != 0 || camera.blackareav.1 != 0) {
@@ -101,14 +401,47 @@ impl RawImage {
       data: RawImageData::Integer(image),
       blacklevels: blacks,
       whitelevels: camera.whitelevels,
+      float_levels: None,
       xyz_to_cam: camera.xyz_to_cam,
       cfa: camera.cfa.clone(),
       crops: camera.crops,
       blackareas: blackareas,
       orientation: camera.orientation,
+      opcodes: DngOpcodes::default(),
+      dng_color: None,
+      dng_levels: None,
+      dng_defaults: None,
+      semantic_masks: Vec::new(),
+      iiq_calibration: None,
+      superccd: None,
+      pixel_shift: None,
+      frame_info: None,
     }
   }
 
+  /// Blacklevels in RGBE order as floats, the exact ones for images that have them
+  pub fn float_blacklevels(&self) -> [f32;4] {
+    match self.float_levels {
+      Some((black, _)) => black,
+      None => levels_to_f32(self.blacklevels),
+    }
+  }
+
+  /// Whitelevels in RGBE order as floats, the exact ones for images that have them
+  pub fn float_whitelevels(&self) -> [f32;4] {
+    match self.float_levels {
+      Some((_, white)) => white,
+      None => levels_to_f32(self.whitelevels),
+    }
+  }
+
+  /// Sets the levels from floats, keeping `blacklevels` and `whitelevels` in sync
+  pub fn set_float_levels(&mut self, black: [f32;4], white: [f32;4]) {
+    self.blacklevels = levels_to_u16(black);
+    self.whitelevels = levels_to_u16(white);
+    self.float_levels = exact_float_levels(black, white);
+  }
+
   /// Outputs the inverted matrix that converts pixels in the camera colorspace into
   /// XYZ components.
   pub fn cam_to_xyz(&self) -> [[f32;4];3] {
@@ -221,6 +554,142 @@ impl RawImage {
     self.cfa.shift(self.crops[3], self.crops[0])
   }
 
+  /// Applies the DNG opcodes that work on the raw data (OpcodeList1 and OpcodeList2)
+  /// and removes them from `opcodes`. OpcodeList3 needs to be applied after demosaic.
+  pub fn apply_dng_opcodes(&mut self) {
+    apply_opcodes(self);
+  }
+
+  /// Applies the Phase One sensor calibration corrections for IIQ files and removes the
+  /// calibration from `iiq_calibration`. Does nothing for all other formats.
+  pub fn apply_iiq_calibration(&mut self) {
+    if let Some(cal) = self.iiq_calibration.take() {
+      cal.apply(self);
+    }
+  }
+
+  /// Combines this image with a darker exposure of the same scene captured at the same
+  /// time, such as the second frame of Fuji SuperCCD SR files, into a higher dynamic
+  /// range image. Where this image is close to clipping the values are taken from the
+  /// darker one, scaled by the exposure difference measured from the pixels that are
+  /// well exposed in both. The result is a `RawImageData::Float` image with the
+  /// whitelevels raised accordingly.
+  pub fn merge_exposures(&self, dark: &RawImage) -> Result<RawImage, String> {
+    if self.width != dark.width || self.height != dark.height || self.cpp != dark.cpp {
+      return Err("Can't merge exposures with different sizes".to_string())
+    }
+    let (bright_data, dark_data) = match (&self.data, &dark.data) {
+      (RawImageData::Integer(b), RawImageData::Integer(d)) => (b, d),
+      _ => return Err("Can only merge exposures of integer images".to_string()),
+    };
+
+    let cpp = self.cpp;
+    let width = self.width;
+    let color = |pos: usize| -> usize {
+      if cpp == 1 {
+        self.cfa.color_at(pos / width, pos % width)
+      } else {
+        pos % cpp
+      }
+    };
+    let bb = self.float_blacklevels();
+    let bw = self.float_whitelevels();
+    let db = dark.float_blacklevels();
+    let dw = dark.float_whitelevels();
+
+    // Measure the exposure difference from the midtones of the bright image
+    let mut bright_sum = 0.0 as f64;
+    let mut dark_sum = 0.0 as f64;
+    for (pos, (b, d)) in bright_data.iter().zip(dark_data.iter()).enumerate() {
+      let c = color(pos);
+      let b = *b as f32 - bb[c];
+      let d = *d as f32 - db[c];
+      let range = bw[c] - bb[c];
+      if b > range * 0.25 && b < range * 0.75 && d > 0.0 {
+        bright_sum += b as f64;
+        dark_sum += d as f64;
+      }
+    }
+    if dark_sum <= 0.0 {
+      return Err("Not enough well exposed pixels to merge exposures".to_string())
+    }
+    let ratio = (bright_sum / dark_sum) as f32;
+
+    // Blend into the dark image as the bright one approaches clipping so there's no seam
+    let data: Vec<f32> = bright_data.iter().zip(dark_data.iter()).enumerate().map(|(pos, (b, d))| {
+      let c = color(pos);
+      let range = bw[c] - bb[c];
+      let b = *b as f32 - bb[c];
+      let d = (*d as f32 - db[c]) * ratio;
+      let weight = ((b - range * 0.8) / (range * 0.15)).max(0.0).min(1.0);
+      bb[c] + b * (1.0 - weight) + d * weight
+    }).collect();
+
+    let mut whites = [0.0 as f32; 4];
+    for c in 0..4 {
+      whites[c] = bb[c] + (dw[c] - db[c]) * ratio;
+    }
+
+    let mut out = self.clone();
+    out.data = RawImageData::Float(data);
+    out.set_float_levels(bb, whites);
+    Ok(out)
+  }
+
+  /// Combines the frames of a pixel shift file into a full color image with 3 components
+  /// per pixel, with no demosaic needed. Each pixel is the average of the samples of
+  /// each color the frames have for that position of the scene.
+  pub fn combine_pixel_shift(frames: &[RawImage]) -> Result<RawImage, String> {
+    let first = match frames.first() {
+      Some(first) => first,
+      None => return Err("Need at least one frame to combine".to_string()),
+    };
+    let width = first.width;
+    let height = first.height;
+    let mut sums = vec![0 as u32; width*height*3];
+    let mut counts = vec![0 as u16; width*height*3];
+    for frame in frames {
+      if frame.width != width || frame.height != height || frame.cpp != 1 {
+        return Err("Pixel shift frames need to be bayer images of the same size".to_string())
+      }
+      let (offrow, offcol) = match frame.pixel_shift {
+        Some(offset) => offset,
+        None => return Err("Frame is not part of a pixel shift image".to_string()),
+      };
+      let data = match frame.data {
+        RawImageData::Integer(ref data) => data,
+        RawImageData::Float(_) => return Err("Can only combine integer frames".to_string()),
+      };
+      for row in 0..(height - cmp::min(height, offrow)) {
+        for col in 0..(width - cmp::min(width, offcol)) {
+          let color = match frame.cfa.color_at(row, col) {
+            3 => 1,
+            c => c,
+          };
+          let pos = ((row+offrow)*width + col+offcol)*3 + color;
+          sums[pos] += data[row*width+col] as u32;
+          counts[pos] += 1;
+        }
+      }
+    }
+
+    let blacks = first.blacklevels;
+    let data = sums.iter().zip(counts.iter()).enumerate().map(|(pos, (sum, count))| {
+      if *count == 0 {
+        blacks[pos % 3]
+      } else {
+        (*sum / *count as u32) as u16
+      }
+    }).collect();
+
+    let mut out = first.clone();
+    out.data = RawImageData::Integer(data);
+    out.cpp = 3;
+    out.cfa = CFA::new("");
+    out.pixel_shift = None;
+    Ok(out)
+  }
+
   /// Checks if the image is monochrome
   pub fn is_monochrome(&self) -> bool {
     self.cpp == 1 && !self.cfa.is_valid()
@@ -0,0 +1,252 @@
+// Small zlib/deflate decoder (RFC 1950/1951) so Deflate compressed DNGs can be read
+// without pulling in an extra dependency
+
+const LENGTH_BASE: [u16;29] = [3,4,5,6,7,8,9,10,11,13,15,17,19,23,27,31,35,43,51,59,67,83,
+                               99,115,131,163,195,227,258];
+const LENGTH_EXTRA: [u8;29] = [0,0,0,0,0,0,0,0,1,1,1,1,2,2,2,2,3,3,3,3,4,4,4,4,5,5,5,5,0];
+const DIST_BASE: [u16;30] = [1,2,3,4,5,7,9,13,17,25,33,49,65,97,129,193,257,385,513,769,
+                             1025,1537,2049,3073,4097,6145,8193,12289,16385,24577];
+const DIST_EXTRA: [u8;30] = [0,0,0,0,1,1,2,2,3,3,4,4,5,5,6,6,7,7,8,8,9,9,10,10,11,11,12,12,13,13];
+const CLEN_ORDER: [usize;19] = [16,17,18,0,8,7,9,6,10,5,11,4,12,3,13,2,14,1,15];
+
+#[derive(Debug, Copy, Clone)]
+struct InflateBits<'a> {
+  buffer: &'a [u8],
+  pos: usize,
+  bits: u64,
+  nbits: u32,
+}
+
+impl<'a> InflateBits<'a> {
+  fn new(src: &'a [u8]) -> InflateBits {
+    InflateBits {
+      buffer: src,
+      pos: 0,
+      bits: 0,
+      nbits: 0,
+    }
+  }
+
+  #[inline(always)]
+  fn peek(&mut self, num: u32) -> u32 {
+    while self.nbits < num {
+      // Past the end we feed zeroes and let overrun() catch it
+      let byte = if self.pos < self.buffer.len() { self.buffer[self.pos] } else { 0 };
+      self.bits |= (byte as u64) << self.nbits;
+      self.pos += 1;
+      self.nbits += 8;
+    }
+    (self.bits & ((1 << num) - 1)) as u32
+  }
+
+  #[inline(always)]
+  fn consume(&mut self, num: u32) {
+    self.bits >>= num;
+    self.nbits -= num;
+  }
+
+  #[inline(always)]
+  fn get(&mut self, num: u32) -> u32 {
+    let val = self.peek(num);
+    self.consume(num);
+    val
+  }
+
+  fn align(&mut self) {
+    let extra = self.nbits % 8;
+    self.consume(extra);
+  }
+
+  fn overrun(&self) -> bool {
+    self.pos > self.buffer.len() + 8
+  }
+}
+
+// Canonical huffman code decoded through a single table indexed by the next
+// (bit reversed) max length bits, each entry being (symbol << 4) | length
+#[derive(Debug, Clone)]
+struct InflateHuffman {
+  table: Vec<u16>,
+  bits: u32,
+}
+
+impl InflateHuffman {
+  fn new(lengths: &[u8]) -> Result<InflateHuffman, String> {
+    let bits = *lengths.iter().max().unwrap_or(&0) as u32;
+    let mut counts = [0 as u32; 16];
+    for &len in lengths {
+      counts[len as usize] += 1;
+    }
+    counts[0] = 0;
+    let mut next = [0 as u32; 16];
+    let mut code = 0;
+    for len in 1..16 {
+      code = (code + counts[len-1]) << 1;
+      next[len] = code;
+    }
+
+    let mut table = vec![0 as u16; 1 << bits];
+    for (symbol, &len) in lengths.iter().enumerate() {
+      if len == 0 {
+        continue
+      }
+      let len = len as u32;
+      let code = next[len as usize];
+      next[len as usize] += 1;
+      if code >= (1 << len) {
+        return Err("inflate: oversubscribed huffman code".to_string())
+      }
+      let reversed = (code.reverse_bits() >> (32 - len)) as usize;
+      let mut pos = reversed;
+      while pos < table.len() {
+        table[pos] = ((symbol as u16) << 4) | len as u16;
+        pos += 1 << len;
+      }
+    }
+
+    Ok(InflateHuffman {
+      table: table,
+      bits: bits,
+    })
+  }
+
+  #[inline(always)]
+  fn decode(&self, pump: &mut InflateBits) -> Result<usize, String> {
+    let entry = self.table[pump.peek(self.bits) as usize];
+    let len = (entry & 0xf) as u32;
+    if len == 0 {
+      return Err("inflate: invalid huffman code".to_string())
+    }
+    pump.consume(len);
+    Ok((entry >> 4) as usize)
+  }
+}
+
+/// Decompress a zlib stream, `size` being the expected output length
+pub fn inflate_zlib(src: &[u8], size: usize) -> Result<Vec<u8>, String> {
+  if src.len() < 2 {
+    return Err("inflate: stream is too short".to_string())
+  }
+  let cmf = src[0] as u32;
+  let flg = src[1] as u32;
+  if cmf & 0x0f != 8 || (cmf * 256 + flg) % 31 != 0 || flg & 0x20 != 0 {
+    return Err("inflate: invalid zlib header".to_string())
+  }
+  inflate(&src[2..], size)
+}
+
+/// Decompress a raw deflate stream, `size` being the expected output length
+pub fn inflate(src: &[u8], size: usize) -> Result<Vec<u8>, String> {
+  let mut out: Vec<u8> = Vec::with_capacity(size);
+  let mut pump = InflateBits::new(src);
+
+  loop {
+    let last = pump.get(1) == 1;
+    match pump.get(2) {
+      0 => {
+        pump.align();
+        let len = pump.get(16);
+        let nlen = pump.get(16);
+        if len != !nlen & 0xffff {
+          return Err("inflate: corrupt stored block".to_string())
+        }
+        for _ in 0..len {
+          out.push(pump.get(8) as u8);
+        }
+      },
+      1 => {
+        let mut lengths = [0 as u8; 288];
+        for (i, len) in lengths.iter_mut().enumerate() {
+          *len = match i {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+          };
+        }
+        let litlen = InflateHuffman::new(&lengths)?;
+        let dist = InflateHuffman::new(&[5;30])?;
+        inflate_block(&mut pump, &mut out, &litlen, &dist)?;
+      },
+      2 => {
+        let hlit = pump.get(5) as usize + 257;
+        let hdist = pump.get(5) as usize + 1;
+        let hclen = pump.get(4) as usize + 4;
+        let mut clens = [0 as u8; 19];
+        for i in 0..hclen {
+          clens[CLEN_ORDER[i]] = pump.get(3) as u8;
+        }
+        let clen = InflateHuffman::new(&clens)?;
+
+        let mut lengths = vec![0 as u8; hlit + hdist];
+        let mut i = 0;
+        while i < hlit + hdist {
+          let sym = clen.decode(&mut pump)?;
+          let (val, count) = match sym {
+            0..=15 => (sym as u8, 1),
+            16 => {
+              if i == 0 {
+                return Err("inflate: repeat with no previous length".to_string())
+              }
+              (lengths[i-1], 3 + pump.get(2) as usize)
+            },
+            17 => (0, 3 + pump.get(3) as usize),
+            _ => (0, 11 + pump.get(7) as usize),
+          };
+          if i + count > hlit + hdist {
+            return Err("inflate: too many code lengths".to_string())
+          }
+          for len in lengths[i..i+count].iter_mut() {
+            *len = val;
+          }
+          i += count;
+        }
+
+        let litlen = InflateHuffman::new(&lengths[0..hlit])?;
+        let dist = InflateHuffman::new(&lengths[hlit..])?;
+        inflate_block(&mut pump, &mut out, &litlen, &dist)?;
+      },
+      _ => return Err("inflate: invalid block type".to_string()),
+    }
+
+    if pump.overrun() {
+      return Err("inflate: stream is truncated".to_string())
+    }
+    if last {
+      return Ok(out)
+    }
+  }
+}
+
+fn inflate_block(pump: &mut InflateBits, out: &mut Vec<u8>, litlen: &InflateHuffman, dist: &InflateHuffman) -> Result<(), String> {
+  loop {
+    let sym = litlen.decode(pump)?;
+    if sym < 256 {
+      out.push(sym as u8);
+    } else if sym == 256 {
+      return Ok(())
+    } else {
+      let sym = sym - 257;
+      if sym >= 29 {
+        return Err("inflate: invalid length code".to_string())
+      }
+      let len = LENGTH_BASE[sym] as usize + pump.get(LENGTH_EXTRA[sym] as u32) as usize;
+      let dsym = dist.decode(pump)?;
+      if dsym >= 30 {
+        return Err("inflate: invalid distance code".to_string())
+      }
+      let distance = DIST_BASE[dsym] as usize + pump.get(DIST_EXTRA[dsym] as u32) as usize;
+      if distance > out.len() {
+        return Err("inflate: distance too far back".to_string())
+      }
+      let start = out.len() - distance;
+      for i in 0..len {
+        let val = out[start+i];
+        out.push(val);
+      }
+    }
+    if pump.overrun() {
+      return Err("inflate: stream is truncated".to_string())
+    }
+  }
+}
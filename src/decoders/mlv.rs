@@ -0,0 +1,213 @@
+use std::f32::NAN;
+
+use crate::decoders::*;
+use crate::decoders::basics::*;
+use crate::decoders::cfa::*;
+use crate::decoders::ljpeg::*;
+
+const MLV_VIDEO_CLASS_RAW: u16 = 0x01;
+const MLV_VIDEO_CLASS_FLAG_LZMA: u16 = 0x20;
+const MLV_VIDEO_CLASS_FLAG_DELTA: u16 = 0x40;
+const MLV_VIDEO_CLASS_FLAG_LJ92: u16 = 0x80;
+
+// Sizes of the block structs, shorter blocks are corrupt and get ignored
+const MLV_IDNT_SIZE: usize = 84;
+const MLV_RAWI_SIZE: usize = 180;
+const MLV_WBAL_SIZE: usize = 44;
+const MLV_EXPO_SIZE: usize = 40;
+const MLV_VIDF_SIZE: usize = 32;
+
+pub fn is_mlv(buf: &[u8]) -> bool {
+  buf[0..4] == b"MLVI"[..]
+}
+
+// A VIDF block together with the metadata blocks that were in effect when it was written
+#[derive(Debug, Clone, Copy)]
+struct MlvFrame {
+  number: u32,
+  offset: usize,
+  rawi: usize,
+  wbal: Option<usize>,
+  expo: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MlvDecoder<'a> {
+  buffer: &'a [u8],
+  rawhide: &'a RawHide,
+  video_class: u16,
+  fps: f32,
+  idnt: Option<usize>,
+  frames: Vec<MlvFrame>,
+}
+
+impl<'a> MlvDecoder<'a> {
+  pub fn new(buf: &'a [u8], rawhide: &'a RawHide) -> Result<MlvDecoder<'a>, String> {
+    if buf.len() < 52 {
+      return Err("MLV: file too short".to_string())
+    }
+    let video_class = LEu16(buf, 32);
+    let fps_denom = LEu32(buf, 48);
+    let fps = if fps_denom != 0 { LEu32(buf, 44) as f32 / fps_denom as f32 } else { NAN };
+
+    // Walk all the blocks keeping track of the latest metadata for each frame
+    let mut idnt = None;
+    let mut rawi = None;
+    let mut wbal = None;
+    let mut expo = None;
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    while pos + 16 <= buf.len() {
+      let size = LEu32(buf, pos+4) as usize;
+      if size < 16 || pos + size > buf.len() {
+        break
+      }
+      match &buf[pos..pos+4] {
+        b"IDNT" if size >= MLV_IDNT_SIZE => idnt = Some(pos),
+        b"RAWI" if size >= MLV_RAWI_SIZE => rawi = Some(pos),
+        b"WBAL" if size >= MLV_WBAL_SIZE => wbal = Some(pos),
+        b"EXPO" if size >= MLV_EXPO_SIZE => expo = Some(pos),
+        b"VIDF" if size >= MLV_VIDF_SIZE => {
+          if let Some(rawi) = rawi {
+            frames.push(MlvFrame {
+              number: LEu32(buf, pos+16),
+              offset: pos,
+              rawi: rawi,
+              wbal: wbal,
+              expo: expo,
+            });
+          }
+        },
+        _ => {},
+      }
+      pos += size;
+    }
+
+    // Frames are not necessarily written in order
+    frames.sort_by_key(|f| f.number);
+
+    Ok(MlvDecoder {
+      buffer: buf,
+      rawhide: rawhide,
+      video_class: video_class,
+      fps: fps,
+      idnt: idnt,
+      frames: frames,
+    })
+  }
+}
+
+impl<'a> Decoder for MlvDecoder<'a> {
+  fn image(&self, dummy: bool) -> Result<RawImage,String> {
+    self.frame(0, dummy)
+  }
+
+  fn frame_count(&self) -> usize {
+    self.frames.len()
+  }
+
+  fn frame(&self, frame: usize, dummy: bool) -> Result<RawImage,String> {
+    let buf = self.buffer;
+    let info = match self.frames.get(frame) {
+      Some(info) => info,
+      None => return Err(format!("MLV: Couldn't find frame {}", frame)),
+    };
+    if self.video_class & 0x0f != MLV_VIDEO_CLASS_RAW {
+      return Err(format!("MLV: Don't know how to decode video class 0x{:x}", self.video_class))
+    }
+    if self.video_class & (MLV_VIDEO_CLASS_FLAG_LZMA | MLV_VIDEO_CLASS_FLAG_DELTA) != 0 {
+      return Err("MLV: LZMA and delta compressed frames are not supported".to_string())
+    }
+
+    let model = match self.idnt {
+      Some(idnt) => String::from_utf8_lossy(&buf[idnt+16..idnt+48]).split_terminator("\0").next().unwrap_or("").to_string(),
+      None => return Err("MLV: Couldn't find the camera identification".to_string()),
+    };
+    let mut camera = self.rawhide.check_supported_with_everything("Canon", &model, "")?;
+
+    let rawi = info.rawi;
+    let width = LEu16(buf, rawi+16) as usize;
+    let height = LEu16(buf, rawi+18) as usize;
+    let bps = LEu32(buf, rawi+44) as usize;
+    let black = LEu32(buf, rawi+48) as u16;
+    let white = LEu32(buf, rawi+52) as u16;
+    camera.blacklevels = [black, black, black, black];
+    camera.whitelevels = [white, white, white, white];
+    // Frames are already cropped to the recorded area so the sensor's masked areas are gone
+    camera.crops = [0,0,0,0];
+    camera.blackareah = (0,0);
+    camera.blackareav = (0,0);
+    let cfa = LEu32(buf, rawi+96);
+    if cfa != 0 {
+      let pattern: String = (0..4).map(|i| match (cfa >> (i*8)) & 0xff {
+        0 => 'R',
+        1 => 'G',
+        _ => 'B',
+      }).collect();
+      camera.cfa = CFA::new(&pattern);
+    }
+    // ColorMatrix1 as in a DNG, 9 rationals
+    if (0..9).all(|i| LEi32(buf, rawi+104+i*8+4) != 0) {
+      for i in 0..9 {
+        let num = LEi32(buf, rawi+104+i*8) as f32;
+        let den = LEi32(buf, rawi+104+i*8+4) as f32;
+        camera.xyz_to_cam[i/3][i%3] = num / den * 10000.0;
+      }
+    }
+
+    let size = LEu32(buf, info.offset+4) as usize;
+    let space = LEu32(buf, info.offset+28) as usize;
+    let start = info.offset + 32 + space;
+    if start > info.offset + size {
+      return Err("MLV: frame data out of bounds".to_string())
+    }
+    let src = &buf[start..info.offset+size];
+
+    let image = if self.video_class & MLV_VIDEO_CLASS_FLAG_LJ92 != 0 {
+      let decompressor = LjpegDecompressor::new(src)?;
+      let ljpegwidth = decompressor.width();
+      let ljpegheight = decompressor.height();
+      if ljpegwidth * ljpegheight != width * height {
+        return Err(format!("MLV: LJ92 frame is {}x{} but the image is {}x{}", ljpegwidth, ljpegheight, width, height))
+      }
+      let mut out = alloc_image_plain!(width, height, dummy);
+      decompressor.decode(&mut out, 0, ljpegwidth, ljpegwidth, ljpegheight, dummy)?;
+      out
+    } else {
+      if src.len() < width*height*bps/8 {
+        return Err("MLV: frame data is too short".to_string())
+      }
+      match bps {
+        10 => decode_10le_lsb16(src, width, height, dummy),
+        12 => decode_12le_lsb16(src, width, height, dummy),
+        14 => decode_14le_lsb16(src, width, height, dummy),
+        16 => decode_16le(src, width, height, dummy),
+        _ => return Err(format!("MLV: Don't know how to decode bps {}", bps)),
+      }
+    };
+
+    let wb = match info.wbal {
+      Some(wbal) if LEu32(buf, wbal+28) != 0 => {
+        [LEu32(buf, wbal+24) as f32, LEu32(buf, wbal+28) as f32, LEu32(buf, wbal+32) as f32, NAN]
+      },
+      _ => [NAN, NAN, NAN, NAN],
+    };
+
+    let (exposure_time, iso) = match info.expo {
+      Some(expo) => (LEu64(buf, expo+32) as f32 / 1000000.0, LEu32(buf, expo+20)),
+      None => (NAN, 0),
+    };
+
+    let mut img = RawImage::new(camera, width, height, wb, image, dummy);
+    img.frame_info = Some(FrameInfo {
+      number: info.number as usize,
+      timestamp: LEu64(buf, info.offset+8) as f64 / 1000000.0,
+      exposure_time: exposure_time,
+      iso: iso,
+      fps: self.fps,
+      timecode: None,
+      utc_time: None,
+    });
+    Ok(img)
+  }
+}
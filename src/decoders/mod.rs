@@ -66,6 +66,8 @@ mod basics;
 mod packed;
 mod pumps;
 mod ljpeg;
+mod dctjpeg;
+mod inflate;
 pub mod cfa;
 mod tiff;
 mod ciff;
@@ -81,19 +83,29 @@ mod rw2;
 mod raf;
 mod dcr;
 mod dng;
+mod cdng;
+pub use self::cdng::DngSequence;
 mod pef;
 mod crw;
 mod nkd;
 mod mos;
 mod iiq;
+pub use self::iiq::{IiqCalibration, IiqDefect, IiqDefectKind, IiqFlatField};
 mod tfr;
 mod nef;
 mod nrw;
 mod cr2;
+mod cr3;
 mod ari;
 mod x3f;
+mod mlv;
+mod brcm;
+mod fits;
+mod ser;
 use self::tiff::*;
 pub use self::image::*;
+mod opcodes;
+pub use self::opcodes::*;
 mod unwrapped;
 
 pub static CAMERAS_TOML: &'static str = include_str!(concat!(env!("OUT_DIR"), "/all.toml"));
@@ -102,6 +114,33 @@ pub static BUG: &'static str = "\nPlease file a bug with a sample file at https:
 
 pub trait Decoder {
   fn image(&self, dummy: bool) -> Result<RawImage, String>;
+
+  // Files that store several aligned frames of the same scene (e.g., Canon Dual Pixel
+  // RAW) override these two, for everything else the image is the only frame
+  fn frame_count(&self) -> usize {
+    1
+  }
+
+  fn frame(&self, frame: usize, dummy: bool) -> Result<RawImage, String> {
+    if frame == 0 {
+      self.image(dummy)
+    } else {
+      Err(format!("Couldn't find frame {}", frame))
+    }
+  }
+
+  // Decoders that support any of the DecodeOptions override this one
+  fn frame_with_options(&self, frame: usize, _options: &DecodeOptions, dummy: bool) -> Result<RawImage, String> {
+    self.frame(frame, dummy)
+  }
+}
+
+/// Options to change how images get decoded, the defaults are what `decode()` uses
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DecodeOptions {
+  /// Return Fuji SuperCCD images in the native sensor layout instead of rotating them
+  /// by 45 degrees, with the geometry described in `RawImage::superccd`
+  pub superccd_native: bool,
 }
 
 /// Buffer to hold an image in memory with enough extra space at the end for speed optimizations
@@ -140,6 +179,7 @@ pub struct Camera {
   pub raw_height: usize,
   pub orientation: Orientation,
   whitelevels: [u16;4],
+  second_whitelevels: Option<[u16;4]>,
   blacklevels: [u16;4],
   blackareah: (usize, usize),
   blackareav: (usize, usize),
@@ -166,6 +206,7 @@ impl Camera {
         "clean_make" => {self.clean_make = val.as_str().unwrap().to_string().clone();},
         "clean_model" => {self.clean_model = val.as_str().unwrap().to_string().clone();},
         "whitepoint" => {let white = val.as_integer().unwrap() as u16; self.whitelevels = [white, white, white, white];},
+        "whitepoint_second" => {let white = val.as_integer().unwrap() as u16; self.second_whitelevels = Some([white, white, white, white]);},
         "blackpoint" => {let black = val.as_integer().unwrap() as u16; self.blacklevels = [black, black, black, black];},
         "blackareah" => {
           let vals = val.as_array().unwrap();
@@ -218,6 +259,7 @@ impl Camera {
       raw_width: 0,
       raw_height: 0,
       whitelevels: [0;4],
+      second_whitelevels: None,
       blacklevels: [0;4],
       blackareah: (0,0),
       blackareav: (0,0),
@@ -434,6 +476,31 @@ impl RawHide {
       return Ok(dec as Box<dyn Decoder>);
     }
 
+    if cr3::is_cr3(buffer) {
+      let dec = Box::new(cr3::Cr3Decoder::new(buffer, &self)?);
+      return Ok(dec as Box<dyn Decoder>);
+    }
+
+    if let Some(offset) = brcm::find_brcm(&buffer[..buf.size]) {
+      let dec = Box::new(brcm::BrcmDecoder::new(&buffer[..buf.size], offset, &self));
+      return Ok(dec as Box<dyn Decoder>);
+    }
+
+    if fits::is_fits(buffer) {
+      let dec = Box::new(fits::FitsDecoder::new(buffer)?);
+      return Ok(dec as Box<dyn Decoder>);
+    }
+
+    if ser::is_ser(&buffer[..buf.size]) {
+      let dec = Box::new(ser::SerDecoder::new(&buffer[..buf.size])?);
+      return Ok(dec as Box<dyn Decoder>);
+    }
+
+    if mlv::is_mlv(buffer) {
+      let dec = Box::new(mlv::MlvDecoder::new(buffer, &self)?);
+      return Ok(dec as Box<dyn Decoder>);
+    }
+
     if let Ok(tiff) = TiffIFD::new_file(buffer) {
       if tiff.has_entry(Tag::DNGVersion) {
         return Ok(Box::new(dng::DngDecoder::new(buffer, tiff, self)))
@@ -469,6 +536,7 @@ impl RawHide {
           "PENTAX"                      => use_decoder!(pef::PefDecoder, buffer, tiff, self),
           "Leaf"                        => use_decoder!(iiq::IiqDecoder, buffer, tiff, self),
           "Hasselblad"                  => use_decoder!(tfr::TfrDecoder, buffer, tiff, self),
+          "Imacon"                      => use_decoder!(tfr::TfrDecoder, buffer, tiff, self),
           "NIKON CORPORATION"           => use_decoder!(nef::NefDecoder, buffer, tiff, self),
           "NIKON"                       => use_decoder!(nrw::NrwDecoder, buffer, tiff, self),
           "Canon"                       => use_decoder!(cr2::Cr2Decoder, buffer, tiff, self),
@@ -515,17 +583,27 @@ impl RawHide {
     self.check_supported_with_mode(tiff, "")
   }
 
-  fn decode_unsafe(&self, buffer: &Buffer, dummy: bool) -> Result<RawImage,String> {
+  fn decode_unsafe(&self, buffer: &Buffer, options: &DecodeOptions, dummy: bool) -> Result<RawImage,String> {
+    let decoder = self.get_decoder(&buffer)?;
+    decoder.frame_with_options(0, options, dummy)
+  }
+
+  fn decode_frames_unsafe(&self, buffer: &Buffer, options: &DecodeOptions, dummy: bool) -> Result<Vec<RawImage>,String> {
     let decoder = self.get_decoder(&buffer)?;
-    decoder.image(dummy)
+    (0..decoder.frame_count()).map(|frame| decoder.frame_with_options(frame, options, dummy)).collect()
   }
 
    /// Decodes an input into a RawImage
    pub fn decode(&self, reader: &mut dyn Read, dummy: bool) -> Result<RawImage,String> {
+    self.decode_with_options(reader, &DecodeOptions::default(), dummy)
+  }
+
+  /// Decodes an input into a RawImage using non-default options
+  pub fn decode_with_options(&self, reader: &mut dyn Read, options: &DecodeOptions, dummy: bool) -> Result<RawImage,String> {
     let buffer = Buffer::new(reader)?;
 
     match panic::catch_unwind(|| {
-      self.decode_unsafe(&buffer, dummy)
+      self.decode_unsafe(&buffer, options, dummy)
     }) {
       Ok(val) => val,
       Err(_) => Err(format!("Caught a panic while decoding.{}", BUG).to_string()),
@@ -534,12 +612,76 @@ impl RawHide {
 
   /// Decodes a file into a RawImage
   pub fn decode_file(&self, path: &Path) -> Result<RawImage,String> {
+    self.decode_file_with_options(path, &DecodeOptions::default())
+  }
+
+  /// Decodes a file into a RawImage using non-default options
+  pub fn decode_file_with_options(&self, path: &Path, options: &DecodeOptions) -> Result<RawImage,String> {
+    let file = match File::open(path) {
+      Ok(val) => val,
+      Err(e) => {return Err(e.to_string().to_string())},
+    };
+    let mut buffered_file = BufReader::new(file);
+    self.decode_with_options(&mut buffered_file, options, false)
+  }
+
+  /// Decodes all the frames in an input, for most formats that's just the one image
+  pub fn decode_frames(&self, reader: &mut dyn Read, dummy: bool) -> Result<Vec<RawImage>,String> {
+    self.decode_frames_with_options(reader, &DecodeOptions::default(), dummy)
+  }
+
+  /// Decodes all the frames in an input using non-default options
+  pub fn decode_frames_with_options(&self, reader: &mut dyn Read, options: &DecodeOptions, dummy: bool) -> Result<Vec<RawImage>,String> {
+    let buffer = Buffer::new(reader)?;
+
+    match panic::catch_unwind(|| {
+      self.decode_frames_unsafe(&buffer, options, dummy)
+    }) {
+      Ok(val) => val,
+      Err(_) => Err(format!("Caught a panic while decoding.{}", BUG).to_string()),
+    }
+  }
+
+  /// Decodes all the frames in a file
+  pub fn decode_file_frames(&self, path: &Path) -> Result<Vec<RawImage>,String> {
     let file = match File::open(path) {
       Ok(val) => val,
       Err(e) => {return Err(e.to_string().to_string())},
     };
     let mut buffered_file = BufReader::new(file);
-    self.decode(&mut buffered_file, false)
+    self.decode_frames(&mut buffered_file, false)
+  }
+
+  /// Returns the number of frames in a buffer, to be used with `decode_frame` for
+  /// formats like MLV video that have too many frames to decode all at once
+  pub fn frame_count(&self, buffer: &Buffer) -> Result<usize,String> {
+    match panic::catch_unwind(|| {
+      self.get_decoder(buffer).map(|decoder| decoder.frame_count())
+    }) {
+      Ok(val) => val,
+      Err(_) => Err(format!("Caught a panic while decoding.{}", BUG).to_string()),
+    }
+  }
+
+  /// Decodes a single frame of a buffer
+  pub fn decode_frame(&self, buffer: &Buffer, frame: usize, options: &DecodeOptions, dummy: bool) -> Result<RawImage,String> {
+    match panic::catch_unwind(|| {
+      let decoder = self.get_decoder(buffer)?;
+      decoder.frame_with_options(frame, options, dummy)
+    }) {
+      Ok(val) => val,
+      Err(_) => Err(format!("Caught a panic while decoding.{}", BUG).to_string()),
+    }
+  }
+
+  /// Opens a directory of CinemaDNG frames as a sequence
+  pub fn open_dng_sequence(&self, path: &Path) -> Result<DngSequence,String> {
+    match panic::catch_unwind(|| {
+      DngSequence::new(path, self)
+    }) {
+      Ok(val) => val,
+      Err(_) => Err(format!("Caught a panic while decoding.{}", BUG).to_string()),
+    }
   }
 
   // Decodes an unwraped input (just the image data with minimal metadata) into a RawImage
@@ -28,6 +28,14 @@ impl<'a> Decoder for NakedDecoder<'a> {
 
     let image = if self.camera.find_hint("12le_16bitaligned") {
       decode_12le_16bitaligned(self.buffer, width, height, dummy)
+    } else if self.camera.find_hint("csi2") {
+      // Raw CSI-2 frames as dumped by libcamera, with each row padded
+      let stride = size / height;
+      match bits {
+        10 => decode_10_csi2(self.buffer, width, height, stride, dummy),
+        12 => decode_12_csi2(self.buffer, width, height, stride, dummy),
+        _  => return Err(format!("Naked: Don't know about {} bps CSI-2 images", bits).to_string()),
+      }
     } else {
       match bits {
         10 => decode_10le_lsb16(self.buffer, width, height, dummy),
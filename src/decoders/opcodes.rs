@@ -0,0 +1,644 @@
+// DNG opcode lists (OpcodeList1/2/3), parsed into typed structs and optionally applied
+// to the raw data for the two lists that come before demosaic
+
+use std::cmp;
+use std::mem;
+
+use crate::decoders::*;
+use crate::decoders::basics::*;
+use crate::decoders::cfa::*;
+
+/// All the opcodes found in a DNG file
+#[derive(Debug, Clone, Default)]
+pub struct DngOpcodes {
+  /// opcodes to apply to the raw data as read from the file
+  pub list1: Vec<DngOpcode>,
+  /// opcodes to apply after the raw data has been mapped to linear values
+  pub list2: Vec<DngOpcode>,
+  /// opcodes to apply after demosaic
+  pub list3: Vec<DngOpcode>,
+  /// area of the image (top, left, bottom, right) that OpcodeList2 coordinates are
+  /// relative to, the full image when not set
+  pub active_area: Option<[usize;4]>,
+}
+
+/// A single DNG opcode
+#[derive(Debug, Clone)]
+pub struct DngOpcode {
+  /// DNG version the opcode was specified in
+  pub version: u32,
+  /// the opcode can be skipped if not supported
+  pub optional: bool,
+  /// the opcode can be skipped when rendering the final image and not a preview
+  pub preview_only: bool,
+  /// the actual operation and its parameters
+  pub operation: DngOperation,
+}
+
+/// Rectangle of the image and selection of planes, rows and columns an opcode applies to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DngOpcodeArea {
+  /// first row of the area
+  pub top: usize,
+  /// first column of the area
+  pub left: usize,
+  /// row after the last one of the area
+  pub bottom: usize,
+  /// column after the last one of the area
+  pub right: usize,
+  /// first plane the opcode applies to
+  pub plane: usize,
+  /// number of planes the opcode applies to
+  pub planes: usize,
+  /// only every `row_pitch` rows are affected
+  pub row_pitch: usize,
+  /// only every `col_pitch` columns are affected
+  pub col_pitch: usize,
+}
+
+/// Grid of gains to multiply the image by, usually to correct lens shading
+#[derive(Debug, Clone, PartialEq)]
+pub struct DngGainMap {
+  /// area of the image the gains are applied to
+  pub area: DngOpcodeArea,
+  /// number of rows in the map
+  pub points_v: usize,
+  /// number of columns in the map
+  pub points_h: usize,
+  /// vertical spacing between map rows as a fraction of the image height
+  pub spacing_v: f64,
+  /// horizontal spacing between map columns as a fraction of the image width
+  pub spacing_h: f64,
+  /// vertical position of the first map row as a fraction of the image height
+  pub origin_v: f64,
+  /// horizontal position of the first map column as a fraction of the image width
+  pub origin_h: f64,
+  /// number of planes in the map
+  pub map_planes: usize,
+  /// the gains, indexed by row, then column, then plane
+  pub gains: Vec<f32>,
+}
+
+/// The operations that can be found in DNG opcode lists
+#[derive(Debug, Clone, PartialEq)]
+pub enum DngOperation {
+  /// Lens distortion and lateral chromatic aberration correction
+  WarpRectilinear {
+    /// radial (kr0 to kr3) and tangential (kt0, kt1) coefficients for each plane
+    coefficients: Vec<[f64;6]>,
+    /// optical center as a fraction of the image width and height
+    center: (f64, f64),
+  },
+  /// Interpolate over pixels that have a specific value
+  FixBadPixelsConstant {
+    /// value that marks a pixel as bad
+    constant: u32,
+    /// position of the top left pixel in the bayer pattern (0 red, 1 green on a red row,
+    /// 2 green on a blue row, 3 blue)
+    bayer_phase: u32,
+  },
+  /// Interpolate over a list of pixels and rectangles
+  FixBadPixelsList {
+    /// position of the top left pixel in the bayer pattern
+    bayer_phase: u32,
+    /// bad pixels as (row, column)
+    points: Vec<(usize, usize)>,
+    /// bad rectangles as [top, left, bottom, right]
+    rects: Vec<[usize;4]>,
+  },
+  /// Trim the image to a rectangle
+  TrimBounds {
+    /// rectangle to keep as [top, left, bottom, right]
+    bounds: [usize;4],
+  },
+  /// Map values through a lookup table
+  MapTable {
+    /// area to apply the table to
+    area: DngOpcodeArea,
+    /// 16 bit table, input values past the end use the last entry
+    table: Vec<u16>,
+  },
+  /// Map values through a polynomial
+  MapPolynomial {
+    /// area to apply the polynomial to
+    area: DngOpcodeArea,
+    /// coefficients starting at the constant term
+    coefficients: Vec<f64>,
+  },
+  /// Multiply the image by an interpolated map of gains
+  GainMap(DngGainMap),
+  /// Any other opcode, kept with its raw big endian parameters
+  Unknown {
+    /// opcode id
+    id: u32,
+    /// opcode parameters
+    data: Vec<u8>,
+  },
+}
+
+impl DngGainMap {
+  /// Gain for a position given as a fraction of the image height and width, bilinearly
+  /// interpolated between the map points
+  pub fn gain(&self, v: f64, h: f64, plane: usize) -> f32 {
+    let plane = cmp::min(plane, self.map_planes - 1);
+    let pos = |val: f64, origin: f64, spacing: f64, points: usize| {
+      if points == 1 || spacing <= 0.0 {
+        0.0
+      } else {
+        ((val - origin) / spacing).max(0.0).min((points - 1) as f64)
+      }
+    };
+    let mv = pos(v, self.origin_v, self.spacing_v, self.points_v);
+    let mh = pos(h, self.origin_h, self.spacing_h, self.points_h);
+    let (r0, c0) = (mv as usize, mh as usize);
+    let r1 = cmp::min(r0 + 1, self.points_v - 1);
+    let c1 = cmp::min(c0 + 1, self.points_h - 1);
+    let (fv, fh) = ((mv - r0 as f64) as f32, (mh - c0 as f64) as f32);
+    let g = |r: usize, c: usize| self.gains[(r*self.points_h + c)*self.map_planes + plane];
+    let top = g(r0, c0) * (1.0 - fh) + g(r0, c1) * fh;
+    let bottom = g(r1, c0) * (1.0 - fh) + g(r1, c1) * fh;
+    top * (1.0 - fv) + bottom * fv
+  }
+}
+
+// Bounds checked big endian reader for the opcode parameters
+#[derive(Debug, Copy, Clone)]
+struct OpcodeReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> OpcodeReader<'a> {
+  fn check(&self, bytes: usize) -> Result<(), String> {
+    if self.pos + bytes > self.data.len() {
+      Err("DNG: opcode list is truncated".to_string())
+    } else {
+      Ok(())
+    }
+  }
+
+  fn u16(&mut self) -> Result<u16, String> {
+    self.check(2)?;
+    self.pos += 2;
+    Ok(BEu16(self.data, self.pos-2))
+  }
+
+  fn u32(&mut self) -> Result<u32, String> {
+    self.check(4)?;
+    self.pos += 4;
+    Ok(BEu32(self.data, self.pos-4))
+  }
+
+  fn usize(&mut self) -> Result<usize, String> {
+    Ok(self.u32()? as usize)
+  }
+
+  fn f32(&mut self) -> Result<f32, String> {
+    self.check(4)?;
+    self.pos += 4;
+    Ok(BEf32(self.data, self.pos-4))
+  }
+
+  fn f64(&mut self) -> Result<f64, String> {
+    self.check(8)?;
+    self.pos += 8;
+    Ok(BEf64(self.data, self.pos-8))
+  }
+
+  fn area(&mut self) -> Result<DngOpcodeArea, String> {
+    Ok(DngOpcodeArea {
+      top: self.usize()?,
+      left: self.usize()?,
+      bottom: self.usize()?,
+      right: self.usize()?,
+      plane: self.usize()?,
+      planes: self.usize()?,
+      row_pitch: cmp::max(self.usize()?, 1),
+      col_pitch: cmp::max(self.usize()?, 1),
+    })
+  }
+}
+
+/// Parse the contents of an OpcodeList tag, which is always big endian. Opcodes that
+/// can be skipped are dropped if they're unknown or broken, only broken mandatory
+/// opcodes are an error.
+pub fn parse_opcodes(data: &[u8]) -> Result<Vec<DngOpcode>, String> {
+  let mut reader = OpcodeReader { data: data, pos: 0 };
+  let mut opcodes = Vec::new();
+  let count = match reader.u32() {
+    Ok(count) => count,
+    Err(_) => return Ok(opcodes),
+  };
+  for _ in 0..count {
+    // Without a full header there's no way to know if what's missing mattered
+    let (id, version, flags, bytes) = match (reader.u32(), reader.u32(), reader.u32(), reader.usize()) {
+      (Ok(id), Ok(version), Ok(flags), Ok(bytes)) => (id, version, flags, bytes),
+      _ => break,
+    };
+    let optional = flags & 1 != 0;
+    let preview_only = flags & 2 != 0;
+    let skippable = optional || preview_only;
+    let bytes = if reader.check(bytes).is_ok() {
+      bytes
+    } else if skippable {
+      break
+    } else {
+      return Err(format!("DNG: mandatory opcode {} is truncated", id))
+    };
+    let params = &data[reader.pos..reader.pos+bytes];
+    reader.pos += bytes;
+    let operation = match parse_operation(id, params) {
+      Ok(Some(DngOperation::Unknown {..})) if skippable => continue,
+      Ok(Some(operation)) => operation,
+      Ok(None) => continue,
+      Err(_) if skippable => continue,
+      Err(err) => return Err(err),
+    };
+    opcodes.push(DngOpcode {
+      version: version,
+      optional: optional,
+      preview_only: preview_only,
+      operation: operation,
+    });
+  }
+  Ok(opcodes)
+}
+
+// Parses the parameters of an opcode, None for opcodes that do nothing
+fn parse_operation(id: u32, params: &[u8]) -> Result<Option<DngOperation>, String> {
+  let mut r = OpcodeReader { data: params, pos: 0 };
+  Ok(Some(match id {
+    1 => {
+      let planes = r.usize()?;
+      r.check(planes * 48)?;
+      let mut coefficients = Vec::new();
+      for _ in 0..planes {
+        coefficients.push([r.f64()?, r.f64()?, r.f64()?, r.f64()?, r.f64()?, r.f64()?]);
+      }
+      DngOperation::WarpRectilinear {
+        coefficients: coefficients,
+        center: (r.f64()?, r.f64()?),
+      }
+    },
+    4 => DngOperation::FixBadPixelsConstant {
+      constant: r.u32()?,
+      bayer_phase: r.u32()?,
+    },
+    5 => {
+      let bayer_phase = r.u32()?;
+      let npoints = r.usize()?;
+      let nrects = r.usize()?;
+      r.check(npoints * 8 + nrects * 16)?;
+      let mut points = Vec::new();
+      for _ in 0..npoints {
+        points.push((r.usize()?, r.usize()?));
+      }
+      let mut rects = Vec::new();
+      for _ in 0..nrects {
+        rects.push([r.usize()?, r.usize()?, r.usize()?, r.usize()?]);
+      }
+      DngOperation::FixBadPixelsList {
+        bayer_phase: bayer_phase,
+        points: points,
+        rects: rects,
+      }
+    },
+    6 => DngOperation::TrimBounds {
+      bounds: [r.usize()?, r.usize()?, r.usize()?, r.usize()?],
+    },
+    7 => {
+      let area = r.area()?;
+      let count = r.usize()?;
+      r.check(count * 2)?;
+      let mut table = Vec::new();
+      for _ in 0..count {
+        table.push(r.u16()?);
+      }
+      if table.is_empty() {
+        return Ok(None)
+      }
+      DngOperation::MapTable {
+        area: area,
+        table: table,
+      }
+    },
+    8 => {
+      let area = r.area()?;
+      let degree = r.usize()?;
+      r.check((degree + 1) * 8)?;
+      let mut coefficients = Vec::new();
+      for _ in 0..degree+1 {
+        coefficients.push(r.f64()?);
+      }
+      DngOperation::MapPolynomial {
+        area: area,
+        coefficients: coefficients,
+      }
+    },
+    9 => {
+      let area = r.area()?;
+      let points_v = r.usize()?;
+      let points_h = r.usize()?;
+      let spacing_v = r.f64()?;
+      let spacing_h = r.f64()?;
+      let origin_v = r.f64()?;
+      let origin_h = r.f64()?;
+      let map_planes = r.usize()?;
+      let total = points_v * points_h * map_planes;
+      if total == 0 {
+        return Ok(None)
+      }
+      r.check(total * 4)?;
+      let mut gains = Vec::with_capacity(total);
+      for _ in 0..total {
+        gains.push(r.f32()?);
+      }
+      DngOperation::GainMap(DngGainMap {
+        area: area,
+        points_v: points_v,
+        points_h: points_h,
+        spacing_v: spacing_v,
+        spacing_h: spacing_h,
+        origin_v: origin_v,
+        origin_h: origin_h,
+        map_planes: map_planes,
+        gains: gains,
+      })
+    },
+    _ => DngOperation::Unknown {
+      id: id,
+      data: params.to_vec(),
+    },
+  }))
+}
+
+// The image being processed by an opcode list. Opcode coordinates are relative to the
+// (top, left, bwidth, bheight) area and values are normalized to 0-1 with black and range.
+#[derive(Debug, Clone)]
+struct OpcodeTarget {
+  data: Vec<f32>,
+  width: usize,
+  height: usize,
+  cpp: usize,
+  cfa: CFA,
+  top: usize,
+  left: usize,
+  bwidth: usize,
+  bheight: usize,
+  black: [f32;4],
+  range: [f32;4],
+}
+
+impl OpcodeTarget {
+  fn index(&self, row: usize, col: usize, plane: usize) -> usize {
+    ((self.top + row) * self.width + self.left + col) * self.cpp + plane
+  }
+
+  fn color(&self, row: usize, col: usize, plane: usize) -> usize {
+    if self.cpp > 1 {
+      cmp::min(plane, 3)
+    } else if self.cfa.is_valid() {
+      self.cfa.color_at(self.top + row, self.left + col)
+    } else {
+      0
+    }
+  }
+
+  fn map_area<F>(&mut self, area: &DngOpcodeArea, closure: F)
+    where F: Fn(usize, usize, usize, f32) -> f32 {
+    let bottom = cmp::min(area.bottom, self.bheight);
+    let right = cmp::min(area.right, self.bwidth);
+    let planes = cmp::min(area.plane + area.planes, self.cpp);
+    for row in (area.top..bottom).step_by(area.row_pitch) {
+      for col in (area.left..right).step_by(area.col_pitch) {
+        for plane in area.plane..planes {
+          let idx = self.index(row, col, plane);
+          let color = self.color(row, col, plane);
+          let val = (self.data[idx] - self.black[color]) / self.range[color];
+          let val = closure(row, col, plane - area.plane, val);
+          self.data[idx] = val * self.range[color] + self.black[color];
+        }
+      }
+    }
+  }
+
+  // Replace bad samples with the average of their good neighbours of the same color
+  fn fix_bad_pixels(&mut self, bad: &[bool], bayer_phase: u32) {
+    const GREEN: [(isize,isize);8] = [(-1,-1),(-1,1),(1,-1),(1,1),(-2,0),(2,0),(0,-2),(0,2)];
+    const OTHER: [(isize,isize);8] = [(-2,0),(2,0),(0,-2),(0,2),(-2,-2),(-2,2),(2,-2),(2,2)];
+    const PLANAR: [(isize,isize);8] = [(-1,-1),(-1,0),(-1,1),(0,-1),(0,1),(1,-1),(1,0),(1,1)];
+    let bayer = self.cpp == 1 && self.cfa.is_valid();
+    let green_parity = if bayer_phase == 1 || bayer_phase == 2 { 0 } else { 1 };
+
+    for row in 0..self.bheight {
+      for col in 0..self.bwidth {
+        for plane in 0..self.cpp {
+          if !bad[(row*self.bwidth + col)*self.cpp + plane] {
+            continue
+          }
+          let offsets = if !bayer {
+            &PLANAR
+          } else if (row + col) % 2 == green_parity {
+            &GREEN
+          } else {
+            &OTHER
+          };
+          let mut sum = 0.0;
+          let mut count = 0;
+          for &(dy, dx) in offsets.iter() {
+            let (y, x) = (row as isize + dy, col as isize + dx);
+            if y < 0 || x < 0 || y >= self.bheight as isize || x >= self.bwidth as isize {
+              continue
+            }
+            let (y, x) = (y as usize, x as usize);
+            if bad[(y*self.bwidth + x)*self.cpp + plane] {
+              continue
+            }
+            sum += self.data[self.index(y, x, plane)];
+            count += 1;
+          }
+          if count > 0 {
+            let idx = self.index(row, col, plane);
+            self.data[idx] = sum / count as f32;
+          }
+        }
+      }
+    }
+  }
+
+  // Trim the whole image and not just the area, returning the offset that was removed
+  fn trim(&mut self, bounds: &[usize;4]) -> (usize, usize) {
+    let top = self.top + cmp::min(bounds[0], self.bheight);
+    let left = self.left + cmp::min(bounds[1], self.bwidth);
+    let bottom = self.top + cmp::min(cmp::max(bounds[2], bounds[0]), self.bheight);
+    let right = self.left + cmp::min(cmp::max(bounds[3], bounds[1]), self.bwidth);
+    let nwidth = right - left;
+    let mut data = Vec::with_capacity(nwidth * (bottom - top) * self.cpp);
+    for row in top..bottom {
+      let start = (row * self.width + left) * self.cpp;
+      data.extend_from_slice(&self.data[start..start + nwidth*self.cpp]);
+    }
+    self.data = data;
+    self.width = nwidth;
+    self.height = bottom - top;
+    self.cfa = self.cfa.shift(left, top);
+    self.top = 0;
+    self.left = 0;
+    self.bwidth = self.width;
+    self.bheight = self.height;
+    (top, left)
+  }
+
+  // Apply a list of opcodes, returning the total offset removed by any trims
+  fn apply(&mut self, opcodes: &[DngOpcode]) -> (usize, usize) {
+    let mut offset = (0, 0);
+    for opcode in opcodes.iter().filter(|op| !op.preview_only) {
+      match opcode.operation {
+        DngOperation::GainMap(ref map) => {
+          let (bheight, bwidth) = (self.bheight as f64, self.bwidth as f64);
+          self.map_area(&map.area, |row, col, plane, val| {
+            val * map.gain(row as f64 / bheight, col as f64 / bwidth, plane)
+          });
+        },
+        DngOperation::MapPolynomial { ref area, ref coefficients } => {
+          self.map_area(area, |_, _, _, val| {
+            let val = val.max(0.0).min(1.0) as f64;
+            let out = coefficients.iter().rev().fold(0.0, |acc, c| acc * val + c);
+            out.max(0.0).min(1.0) as f32
+          });
+        },
+        DngOperation::MapTable { ref area, ref table } => {
+          self.map_area(area, |_, _, _, val| {
+            let idx = (val.max(0.0).min(1.0) * 65535.0).round() as usize;
+            table[cmp::min(idx, table.len() - 1)] as f32 / 65535.0
+          });
+        },
+        DngOperation::FixBadPixelsConstant { constant, bayer_phase } => {
+          let mut bad = vec![false; self.bwidth * self.bheight * self.cpp];
+          for row in 0..self.bheight {
+            for col in 0..self.bwidth {
+              for plane in 0..self.cpp {
+                bad[(row*self.bwidth + col)*self.cpp + plane] =
+                  self.data[self.index(row, col, plane)] == constant as f32;
+              }
+            }
+          }
+          self.fix_bad_pixels(&bad, bayer_phase);
+        },
+        DngOperation::FixBadPixelsList { bayer_phase, ref points, ref rects } => {
+          let mut bad = vec![false; self.bwidth * self.bheight * self.cpp];
+          let mut mark = |top: usize, left: usize, bottom: usize, right: usize| {
+            for row in top..cmp::min(bottom, self.bheight) {
+              for col in left..cmp::min(right, self.bwidth) {
+                for plane in 0..self.cpp {
+                  bad[(row*self.bwidth + col)*self.cpp + plane] = true;
+                }
+              }
+            }
+          };
+          for &(row, col) in points.iter() {
+            mark(row, col, row+1, col+1);
+          }
+          for rect in rects.iter() {
+            mark(rect[0], rect[1], rect[2], rect[3]);
+          }
+          self.fix_bad_pixels(&bad, bayer_phase);
+        },
+        DngOperation::TrimBounds { ref bounds } => {
+          let (top, left) = self.trim(bounds);
+          offset = (offset.0 + top, offset.1 + left);
+        },
+        // The warps are done after demosaic so there's nothing to do in lists 1 and 2
+        DngOperation::WarpRectilinear {..} | DngOperation::Unknown {..} => {},
+      }
+    }
+    offset
+  }
+}
+
+// Move a [top, left, bottom, right] rectangle into the coordinates of an image that had
+// top rows and left columns removed, clamped to its new size
+fn trim_rect(rect: [usize;4], top: usize, left: usize, height: usize, width: usize) -> [usize;4] {
+  let ntop = cmp::min(rect[0].saturating_sub(top), height);
+  let nleft = cmp::min(rect[1].saturating_sub(left), width);
+  let nbottom = cmp::max(cmp::min(rect[2].saturating_sub(top), height), ntop);
+  let nright = cmp::max(cmp::min(rect[3].saturating_sub(left), width), nleft);
+  [ntop, nleft, nbottom, nright]
+}
+
+/// Apply OpcodeList1 and OpcodeList2 to the image data, removing them from the image
+/// so they're not applied twice. OpcodeList3 is left alone as it needs demosaiced data.
+pub fn apply_opcodes(image: &mut RawImage) {
+  let list1 = mem::replace(&mut image.opcodes.list1, Vec::new());
+  let list2 = mem::replace(&mut image.opcodes.list2, Vec::new());
+  if list1.is_empty() && list2.is_empty() {
+    return
+  }
+
+  let (data, integer) = match mem::replace(&mut image.data, RawImageData::Integer(Vec::new())) {
+    RawImageData::Integer(data) => (data.into_iter().map(|v| v as f32).collect(), true),
+    RawImageData::Float(data) => (data, false),
+  };
+  let maxval = if integer { 65535.0 } else { 1.0 };
+  let mut target = OpcodeTarget {
+    data: data,
+    width: image.width,
+    height: image.height,
+    cpp: image.cpp,
+    cfa: image.cfa.clone(),
+    top: 0,
+    left: 0,
+    bwidth: image.width,
+    bheight: image.height,
+    black: [0.0;4],
+    range: [maxval;4],
+  };
+
+  // The first list works on the raw values of the whole image
+  let (trim_top, trim_left) = target.apply(&list1);
+
+  // The second list works on the linear values inside the active area
+  let area = image.opcodes.active_area.map(|area| {
+    trim_rect(area, trim_top, trim_left, target.height, target.width)
+  }).unwrap_or([0, 0, target.height, target.width]);
+  target.top = area[0];
+  target.left = area[1];
+  target.bheight = area[2] - area[0];
+  target.bwidth = area[3] - area[1];
+  let blacks = image.float_blacklevels();
+  let whites = image.float_whitelevels();
+  for i in 0..4 {
+    target.black[i] = blacks[i];
+    target.range[i] = whites[i] - blacks[i];
+    if target.range[i] <= 0.0 {
+      target.range[i] = maxval;
+    }
+  }
+  // A trim in the second list is relative to the trimmed image so add both up
+  let (area_top, area_left) = target.apply(&list2);
+  let area = trim_rect(area, area_top, area_left, target.height, target.width);
+  let (trim_top, trim_left) = (trim_top + area_top, trim_left + area_left);
+
+  if target.width != image.width || target.height != image.height {
+    let crop = [image.crops[0], image.crops[3], image.height - image.crops[2], image.width - image.crops[1]];
+    let [top, left, bottom, right] = trim_rect(crop, trim_top, trim_left, target.height, target.width);
+    image.crops = [top, target.width - right, target.height - bottom, left];
+    image.opcodes.active_area = Some(area);
+    // Masked areas are usually trimmed away so only keep the ones that survive whole
+    let (tt, tl) = (trim_top as u64, trim_left as u64);
+    let (th, tw) = (target.height as u64, target.width as u64);
+    image.blackareas = image.blackareas.iter()
+      .filter(|a| a.0 >= tt && a.3 >= tl && a.2 <= tt + th && a.1 <= tl + tw)
+      .map(|a| (a.0 - tt, a.1 - tl, a.2 - tt, a.3 - tl))
+      .collect();
+    image.width = target.width;
+    image.height = target.height;
+    image.cfa = target.cfa;
+  }
+
+  image.data = if integer {
+    RawImageData::Integer(target.data.iter().map(|&v| v.round().max(0.0).min(65535.0) as u16).collect())
+  } else {
+    RawImageData::Float(target.data)
+  };
+}
@@ -11,6 +11,16 @@ pub fn decode_8bit_wtable(buf: &[u8], tbl: &LookupTable, width: usize, height: u
   }))
 }
 
+pub fn decode_8bit(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+  decode_threaded(width, height, dummy,&(|out: &mut [u16], row| {
+    let inb = &buf[(row*width)..];
+
+    for (o, i) in out.iter_mut().zip(inb.iter()) {
+      *o = *i as u16;
+    }
+  }))
+}
+
 pub fn decode_10le_lsb16(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
   decode_threaded(width, height, dummy,&(|out: &mut [u16], row| {
     let inb = &buf[(row*width*10/8)..];
@@ -39,6 +49,48 @@ pub fn decode_10le_lsb16(buf: &[u8], width: usize, height: usize, dummy: bool) -
   }))
 }
 
+pub fn decode_12le_lsb16(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+  decode_threaded(width, height, dummy,&(|out: &mut [u16], row| {
+    let inb = &buf[(row*width*12/8)..];
+
+    for (o, i) in out.chunks_exact_mut(4).zip(inb.chunks_exact(6)) {
+      let w1: u16 = (i[1] as u16) << 8 | i[0] as u16;
+      let w2: u16 = (i[3] as u16) << 8 | i[2] as u16;
+      let w3: u16 = (i[5] as u16) << 8 | i[4] as u16;
+
+      o[0] = w1 >> 4;
+      o[1] = (w1 & 0x0f) << 8 | w2 >> 8;
+      o[2] = (w2 & 0xff) << 4 | w3 >> 12;
+      o[3] = w3 & 0x0fff;
+    }
+  }))
+}
+
+pub fn decode_14le_lsb16(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+  decode_threaded(width, height, dummy,&(|out: &mut [u16], row| {
+    let inb = &buf[(row*width*14/8)..];
+
+    for (o, i) in out.chunks_exact_mut(8).zip(inb.chunks_exact(14)) {
+      let w1: u16 = (i[1] as u16) << 8 | i[0] as u16;
+      let w2: u16 = (i[3] as u16) << 8 | i[2] as u16;
+      let w3: u16 = (i[5] as u16) << 8 | i[4] as u16;
+      let w4: u16 = (i[7] as u16) << 8 | i[6] as u16;
+      let w5: u16 = (i[9] as u16) << 8 | i[8] as u16;
+      let w6: u16 = (i[11] as u16) << 8 | i[10] as u16;
+      let w7: u16 = (i[13] as u16) << 8 | i[12] as u16;
+
+      o[0] = w1 >> 2;
+      o[1] = (w1 & 0x0003) << 12 | w2 >> 4;
+      o[2] = (w2 & 0x000f) << 10 | w3 >> 6;
+      o[3] = (w3 & 0x003f) << 8 | w4 >> 8;
+      o[4] = (w4 & 0x00ff) << 6 | w5 >> 10;
+      o[5] = (w5 & 0x03ff) << 4 | w6 >> 12;
+      o[6] = (w6 & 0x0fff) << 2 | w7 >> 14;
+      o[7] = w7 & 0x3fff;
+    }
+  }))
+}
+
 pub fn decode_10le(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
   decode_threaded(width, height, dummy,&(|out: &mut [u16], row| {
     let inb = &buf[(row*width*10/8)..];
@@ -58,6 +110,42 @@ pub fn decode_10le(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<
   }))
 }
 
+// MIPI CSI-2 packing, the high 8 bits of each pixel in a byte followed by a byte with
+// the low bits of the group
+pub fn decode_10_csi2(buf: &[u8], width: usize, height: usize, stride: usize, dummy: bool) -> Vec<u16> {
+  decode_threaded(width, height, dummy,&(|out: &mut [u16], row| {
+    let inb = &buf[(row*stride)..];
+
+    for (o, i) in out.chunks_exact_mut(4).zip(inb.chunks_exact(5)) {
+      let g1: u16 = i[0] as u16;
+      let g2: u16 = i[1] as u16;
+      let g3: u16 = i[2] as u16;
+      let g4: u16 = i[3] as u16;
+      let g5: u16 = i[4] as u16;
+
+      o[0] = g1 << 2 | (g5 & 0x03);
+      o[1] = g2 << 2 | (g5 >> 2) & 0x03;
+      o[2] = g3 << 2 | (g5 >> 4) & 0x03;
+      o[3] = g4 << 2 | g5 >> 6;
+    }
+  }))
+}
+
+pub fn decode_12_csi2(buf: &[u8], width: usize, height: usize, stride: usize, dummy: bool) -> Vec<u16> {
+  decode_threaded(width, height, dummy,&(|out: &mut [u16], row| {
+    let inb = &buf[(row*stride)..];
+
+    for (o, i) in out.chunks_exact_mut(2).zip(inb.chunks_exact(3)) {
+      let g1: u16 = i[0] as u16;
+      let g2: u16 = i[1] as u16;
+      let g3: u16 = i[2] as u16;
+
+      o[0] = g1 << 4 | (g3 & 0x0f);
+      o[1] = g2 << 4 | g3 >> 4;
+    }
+  }))
+}
+
 pub fn decode_12be(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
   decode_threaded(width, height, dummy,&(|out: &mut [u16], row| {
     let inb = &buf[(row*width*12/8)..];
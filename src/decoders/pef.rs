@@ -1,4 +1,5 @@
 use std::f32::NAN;
+use std::ptr;
 
 use crate::decoders::*;
 use crate::decoders::tiff::*;
@@ -22,10 +23,30 @@ impl<'a> PefDecoder<'a> {
   }
 }
 
+// Pixel Shift Resolution files have four raw frames, with the sensor moved by one pixel
+// between each one going clockwise
+const PEF_PIXEL_SHIFT: [(usize,usize);4] = [(0,0), (0,1), (1,1), (1,0)];
+
 impl<'a> Decoder for PefDecoder<'a> {
   fn image(&self, dummy: bool) -> Result<RawImage,String> {
+    self.frame(0, dummy)
+  }
+
+  fn frame_count(&self) -> usize {
+    let frames = self.raw_ifds().len();
+    if frames == PEF_PIXEL_SHIFT.len() { frames } else { 1 }
+  }
+
+  fn frame(&self, frame: usize, dummy: bool) -> Result<RawImage,String> {
+    if frame >= self.frame_count() {
+      return Err(format!("PEF: Couldn't find frame {}", frame))
+    }
     let camera = self.rawhide.check_supported(&self.tiff)?;
-    let raw = fetch_ifd!(&self.tiff, Tag::StripOffsets);
+    let raw = if frame == 0 {
+      fetch_ifd!(&self.tiff, Tag::StripOffsets)
+    } else {
+      self.raw_ifds()[frame]
+    };
     let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
     let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
     let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
@@ -39,11 +60,34 @@ impl<'a> Decoder for PefDecoder<'a> {
     };
 
     let blacklevels = self.get_blacklevels().unwrap_or(camera.blacklevels);
-    ok_image_with_blacklevels(camera, width, height, self.get_wb()?, blacklevels, image)
+    let mut img = ok_image_with_blacklevels(camera, width, height, self.get_wb()?, blacklevels, image)?;
+    if self.frame_count() > 1 {
+      img.pixel_shift = Some(PEF_PIXEL_SHIFT[frame]);
+    }
+    Ok(img)
   }
 }
 
 impl<'a> PefDecoder<'a> {
+  // All the IFDs that have raw data the same size as the main one, more than one only
+  // for pixel shift files
+  fn raw_ifds(&self) -> Vec<&TiffIFD> {
+    let mut raws: Vec<&TiffIFD> = Vec::new();
+    let main = match self.tiff.find_first_ifd(Tag::StripOffsets) {
+      Some(main) => main,
+      None => return raws,
+    };
+    let size = |ifd: &TiffIFD| -> Option<(usize, usize)> {
+      Some((ifd.find_entry(Tag::ImageWidth)?.get_usize(0), ifd.find_entry(Tag::ImageLength)?.get_usize(0)))
+    };
+    for ifd in self.tiff.find_ifds_with_tag(Tag::StripOffsets) {
+      if size(ifd).is_some() && size(ifd) == size(main) && !raws.iter().any(|r| ptr::eq(*r, ifd)) {
+        raws.push(ifd);
+      }
+    }
+    raws
+  }
+
   fn get_wb(&self) -> Result<[f32;4], String> {
     let levels = fetch_tag!(self.tiff, Tag::PefWB);
     Ok([levels.get_f32(0), levels.get_f32(1), levels.get_f32(3), NAN])
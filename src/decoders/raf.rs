@@ -1,8 +1,10 @@
 use std::f32::NAN;
+use std::cmp;
 
 use crate::decoders::*;
 use crate::decoders::tiff::*;
 use crate::decoders::basics::*;
+use crate::decoders::cfa::*;
 
 #[derive(Debug, Clone)]
 pub struct RafDecoder<'a> {
@@ -23,33 +25,70 @@ impl<'a> RafDecoder<'a> {
 
 impl<'a> Decoder for RafDecoder<'a> {
   fn image(&self, dummy: bool) -> Result<RawImage,String> {
-    let camera = self.rawhide.check_supported(&self.tiff)?;
+    self.frame(0, dummy)
+  }
+
+  fn frame_count(&self) -> usize {
+    if self.has_second_frame() { 2 } else { 1 }
+  }
+
+  fn frame(&self, frame: usize, dummy: bool) -> Result<RawImage,String> {
+    self.frame_with_options(frame, &DecodeOptions::default(), dummy)
+  }
+
+  fn frame_with_options(&self, frame: usize, options: &DecodeOptions, dummy: bool) -> Result<RawImage,String> {
+    if frame >= self.frame_count() {
+      return Err(format!("RAF: Couldn't find frame {}", frame))
+    }
+    let mut camera = self.rawhide.check_supported(&self.tiff)?;
     let raw = fetch_ifd!(&self.tiff, Tag::RafOffsets);
-    let (width,height) = if raw.has_entry(Tag::RafImageWidth) {
+    let (mut width, mut height) = if raw.has_entry(Tag::RafImageWidth) {
       (fetch_tag!(raw, Tag::RafImageWidth).get_usize(0),
        fetch_tag!(raw, Tag::RafImageLength).get_usize(0))
     } else {
       let sizes = fetch_tag!(raw, Tag::ImageWidth);
       (sizes.get_usize(1), sizes.get_usize(0))
     };
-    let offset = fetch_tag!(raw, Tag::RafOffsets).get_usize(0) + raw.start_offset();
+    let mut offset = fetch_tag!(raw, Tag::RafOffsets).get_usize(0) + raw.start_offset();
     let bps = match raw.find_entry(Tag::RafBitsPerSample) {
       Some(val) => val.get_u32(0) as usize,
       None      => 16,
     };
+
+    if frame == 1 {
+      // The R photodiodes of SuperCCD SR sensors saturate earlier than the S ones
+      if let Some(whites) = camera.second_whitelevels {
+        camera.whitelevels = whites;
+      }
+      if camera.find_hint("double_width") {
+        offset += width*2;
+      } else {
+        // The second frame has its own directory and data offset in the RAF header
+        if let Ok(dir) = TiffIFD::new_fuji(self.buffer, BEu32(self.buffer, 120) as usize) {
+          if let Some(sizes) = dir.find_entry(Tag::ImageWidth) {
+            width = sizes.get_usize(1);
+            height = sizes.get_usize(0);
+          }
+        }
+        offset = BEu32(self.buffer, 128) as usize;
+      }
+    }
+    if offset >= self.buffer.len() {
+      return Err("RAF: raw data offset is beyond the end of the file".to_string())
+    }
     let src = &self.buffer[offset..];
 
     let image = if camera.find_hint("double_width") {
       // Some fuji SuperCCD cameras include a second raw image next to the first one
-      // that is identical but darker to the first. The two combined can produce
-      // a higher dynamic range image. Right now we're ignoring it.
+      // that is identical but darker to the first. It's returned as the second frame
+      // and the two can be combined with RawImage::merge_exposures()
       decode_16le_skiplines(src, width, height, dummy)
     } else if camera.find_hint("jpeg32") {
       decode_12be_msb32(src, width, height, dummy)
+    } else if src.len() < bps*width*height/8 {
+      // Too small to be uncompressed so it must be Fuji's lossless compressed format
+      RafDecoder::decode_compressed(src, width, height, &camera.cfa, dummy)?
     } else {
-      if src.len() < bps*width*height/8 {
-        return Err("RAF: Don't know how to decode compressed yet".to_string())
-      }
       match bps {
         12 => decode_12le(src, width, height, dummy),
         14 => decode_14le_unpacked(src, width, height, dummy),
@@ -64,7 +103,15 @@ impl<'a> Decoder for RafDecoder<'a> {
       }
     };
 
-    if camera.find_hint("fuji_rotation") || camera.find_hint("fuji_rotation_alt") {
+    let superccd = camera.find_hint("fuji_rotation") || camera.find_hint("fuji_rotation_alt");
+    if superccd && options.superccd_native {
+      let layout = SuperCcdLayout::new(camera.find_hint("fuji_rotation_alt"), camera.crops, width, height);
+      let cfa = layout.native_cfa(&camera.cfa);
+      let mut img = RawImage::new(camera, width, height, self.get_wb()?, image, dummy);
+      img.cfa = cfa;
+      img.superccd = Some(layout);
+      Ok(img)
+    } else if superccd {
       let (width, height, image) = RafDecoder::rotate_image(&image, &camera, width, height, dummy);
       Ok(RawImage {
         make: camera.make.clone(),
@@ -78,11 +125,21 @@ impl<'a> Decoder for RafDecoder<'a> {
         data: RawImageData::Integer(image),
         blacklevels: camera.blacklevels,
         whitelevels: camera.whitelevels,
+        float_levels: None,
         xyz_to_cam: camera.xyz_to_cam,
         cfa: camera.cfa.clone(),
         crops: [0,0,0,0],
         blackareas: Vec::new(),
         orientation: camera.orientation,
+        opcodes: DngOpcodes::default(),
+        dng_color: None,
+        dng_levels: None,
+        dng_defaults: None,
+        semantic_masks: Vec::new(),
+        iiq_calibration: None,
+        superccd: None,
+        pixel_shift: None,
+        frame_info: None,
       })
     } else {
       ok_image(camera, width, height, self.get_wb()?, image)
@@ -91,6 +148,27 @@ impl<'a> Decoder for RafDecoder<'a> {
 }
 
 impl<'a> RafDecoder<'a> {
+  // SuperCCD SR cameras store the R photodiodes as a second frame, either interleaved
+  // with the S frame or in a separate raw section listed in the RAF header
+  fn has_second_frame(&self) -> bool {
+    let camera = match self.rawhide.check_supported(&self.tiff) {
+      Ok(camera) => camera,
+      Err(_) => return false,
+    };
+    if camera.find_hint("double_width") {
+      return true
+    }
+    if !camera.find_hint("fuji_rotation") && !camera.find_hint("fuji_rotation_alt") {
+      return false
+    }
+    if self.buffer.len() < 132 {
+      return false
+    }
+    let dir = BEu32(self.buffer, 120) as usize;
+    let offset = BEu32(self.buffer, 128) as usize;
+    dir != 0 && offset != 0 && dir < self.buffer.len() && offset < self.buffer.len()
+  }
+
   fn get_wb(&self) -> Result<[f32;4], String> {
     match self.tiff.find_entry(Tag::RafWBGRB) {
       Some(levels) => Ok([levels.get_f32(1), levels.get_f32(0), levels.get_f32(2), NAN]),
@@ -102,45 +180,458 @@ impl<'a> RafDecoder<'a> {
   }
 
   fn rotate_image(src: &[u16], camera: &Camera, width: usize, height: usize, dummy: bool) -> (usize, usize, Vec<u16>) {
-    let x = camera.crops[3];
-    let y = camera.crops[0];
-    let cropwidth = width - camera.crops[1] - x;
-    let cropheight = height - camera.crops[2] - y;
-
-    if camera.find_hint("fuji_rotation_alt") {
-      let rotatedwidth = cropheight + cropwidth/2;
-      let rotatedheight = rotatedwidth-1;
-
-      let mut out: Vec<u16> = alloc_image_plain!(rotatedwidth, rotatedheight, dummy);
-      if !dummy {
-        for row in 0..cropheight {
-          let inb = &src[(row+y)*width+x..];
-          for col in 0..cropwidth {
-            let out_row = rotatedwidth - (cropheight + 1 - row + (col >> 1));
-            let out_col = ((col+1) >> 1) + row;
-            out[out_row*rotatedwidth+out_col] = inb[col];
+    let layout = SuperCcdLayout::new(camera.find_hint("fuji_rotation_alt"), camera.crops, width, height);
+    let [top, left, areawidth, areaheight] = layout.area;
+
+    let mut out: Vec<u16> = alloc_image_plain!(layout.rotated_width, layout.rotated_height, dummy);
+    if !dummy {
+      for row in top..top+areaheight {
+        for col in left..left+areawidth {
+          if let Some((out_row, out_col)) = layout.rotated_position(row, col) {
+            out[out_row*layout.rotated_width+out_col] = src[row*width+col];
           }
         }
       }
+    }
+
+    (layout.rotated_width, layout.rotated_height, out)
+  }
+}
+
+// Line buffers used by the compressed decoder, 5 red, 8 green and 5 blue lines. Each
+// 6 line group of the output decodes into R2-R4, G2-G7 and B2-B4, with the lines
+// before those holding the end of the previous group as the prediction context.
+const FUJI_R0: usize = 0;
+const FUJI_R1: usize = 1;
+const FUJI_R2: usize = 2;
+const FUJI_R3: usize = 3;
+const FUJI_R4: usize = 4;
+const FUJI_G0: usize = 5;
+const FUJI_G1: usize = 6;
+const FUJI_G2: usize = 7;
+const FUJI_G3: usize = 8;
+const FUJI_G4: usize = 9;
+const FUJI_G5: usize = 10;
+const FUJI_G6: usize = 11;
+const FUJI_G7: usize = 12;
+const FUJI_B0: usize = 13;
+const FUJI_B1: usize = 14;
+const FUJI_B2: usize = 15;
+const FUJI_B3: usize = 16;
+const FUJI_B4: usize = 17;
+const FUJI_LINES: usize = 18;
+
+#[derive(Debug, Clone)]
+struct FujiHeader {
+  xtrans: bool,
+  raw_bits: u32,
+  height: usize,
+  width: usize,
+  block_size: usize,
+  blocks: usize,
+  total_lines: usize,
+}
+
+impl FujiHeader {
+  fn new(src: &[u8]) -> Result<FujiHeader, String> {
+    if src.len() < 16 {
+      return Err("RAF: compressed header is truncated".to_string())
+    }
+    let signature = BEu16(src, 0);
+    let lossless = src[2];
+    let raw_type = src[3];
+    let raw_bits = src[4] as u32;
+    let height = BEu16(src, 5) as usize;
+    let rounded_width = BEu16(src, 7) as usize;
+    let width = BEu16(src, 9) as usize;
+    let block_size = BEu16(src, 11) as usize;
+    let blocks = src[13] as usize;
+    let total_lines = BEu16(src, 14) as usize;
+
+    if signature != 0x4953 {
+      return Err("RAF: not a compressed image".to_string())
+    }
+    if lossless != 1 {
+      return Err("RAF: lossy compressed images aren't supported".to_string())
+    }
+    if (raw_type != 0 && raw_type != 16) || (raw_bits != 12 && raw_bits != 14) {
+      return Err(format!("RAF: unknown compressed type {} with {} bits", raw_type, raw_bits).to_string())
+    }
+    if block_size != 0x300 || height < 6 || height % 6 != 0 || width < block_size || width % 24 != 0 ||
+       rounded_width % block_size != 0 || rounded_width < width || rounded_width - width >= block_size ||
+       blocks == 0 || blocks > 16 || blocks != rounded_width / block_size || total_lines != height / 6 {
+      return Err("RAF: invalid compressed header".to_string())
+    }
+
+    Ok(FujiHeader {
+      xtrans: raw_type == 16,
+      raw_bits: raw_bits,
+      height: height,
+      width: width,
+      block_size: block_size,
+      blocks: blocks,
+      total_lines: total_lines,
+    })
+  }
+}
+
+#[derive(Debug, Clone)]
+struct FujiParams {
+  q_table: Vec<i32>,
+  q_point: [i32;5],
+  max_bits: i32,
+  raw_bits: i32,
+  total_values: i32,
+  max_diff: i32,
+  min_value: i32,
+  line_width: usize,
+}
+
+impl FujiParams {
+  fn new(header: &FujiHeader) -> FujiParams {
+    let maxval = (1 << header.raw_bits) - 1;
+    let q_point = [0, 0x12, 0x43, 0x114, maxval];
+    let q_table = (-maxval..=maxval).map(|val| {
+      if val <= -q_point[3] { -4 }
+      else if val <= -q_point[2] { -3 }
+      else if val <= -q_point[1] { -2 }
+      else if val < 0 { -1 }
+      else if val == 0 { 0 }
+      else if val < q_point[1] { 1 }
+      else if val < q_point[2] { 2 }
+      else if val < q_point[3] { 3 }
+      else { 4 }
+    }).collect();
+
+    FujiParams {
+      q_table: q_table,
+      q_point: q_point,
+      max_bits: 4 * header.raw_bits as i32,
+      raw_bits: header.raw_bits as i32,
+      total_values: maxval + 1,
+      max_diff: (maxval + 1 + 32) >> 6,
+      min_value: 0x40,
+      line_width: if header.xtrans { header.block_size * 2 / 3 } else { header.block_size / 2 },
+    }
+  }
+
+  #[inline(always)]
+  fn quantize(&self, diff: i32) -> i32 {
+    self.q_table[(self.q_point[4] + diff) as usize]
+  }
+}
+
+// Decoding state for a single vertical stripe of the image
+#[derive(Debug, Clone)]
+struct FujiStripe {
+  lines: Vec<u16>,
+  pitch: usize,
+  line_width: usize,
+  grad_even: [[(i32,i32);41];3],
+  grad_odd: [[(i32,i32);41];3],
+}
+
+impl FujiStripe {
+  fn new(params: &FujiParams) -> FujiStripe {
+    let pitch = params.line_width + 2;
+    let grads = [[(params.max_diff, 1);41];3];
+    FujiStripe {
+      lines: vec![0; pitch * FUJI_LINES],
+      pitch: pitch,
+      line_width: params.line_width,
+      grad_even: grads,
+      grad_odd: grads,
+    }
+  }
+
+  #[inline(always)]
+  fn pos(&self, line: usize, pos: usize) -> usize {
+    line * self.pitch + pos + 1
+  }
+
+  fn zerobits(pump: &mut BitPumpMSB) -> u32 {
+    let mut count = 0;
+    loop {
+      let bits = pump.peek_bits(32);
+      if bits == 0 {
+        pump.consume_bits(32);
+        count += 32;
+      } else {
+        let zeros = bits.leading_zeros();
+        pump.consume_bits(zeros+1);
+        return count + zeros
+      }
+    }
+  }
 
-      (rotatedwidth, rotatedheight, out)
+  fn bitdiff(value1: i32, value2: i32) -> u32 {
+    let mut bits = 0;
+    if value2 < value1 {
+      while bits <= 14 {
+        bits += 1;
+        if (value2 << bits) >= value1 {
+          break
+        }
+      }
+    }
+    bits
+  }
+
+  fn read_code(&mut self, pump: &mut BitPumpMSB, params: &FujiParams, odd: bool, set: usize, gradient: usize) -> i32 {
+    let grad = if odd { &mut self.grad_odd[set][gradient] } else { &mut self.grad_even[set][gradient] };
+    let sample = FujiStripe::zerobits(pump);
+    let code = if (sample as i32) < params.max_bits - params.raw_bits - 1 {
+      let bits = FujiStripe::bitdiff(grad.0, grad.1);
+      (pump.get_bits(bits) + (sample << bits)) as i32
     } else {
-      let rotatedwidth = cropwidth + cropheight/2;
-      let rotatedheight = rotatedwidth-1;
-
-      let mut out: Vec<u16> = alloc_image_plain!(rotatedwidth, rotatedheight, dummy);
-      if !dummy {
-        for row in 0..cropheight {
-          let inb = &src[(row+y)*width+x..];
-          for col in 0..cropwidth {
-            let out_row = cropwidth - 1 - col + (row>>1);
-            let out_col = ((row+1) >> 1) + col;
-            out[out_row*rotatedwidth+out_col] = inb[col];
+      pump.get_bits(params.raw_bits as u32) as i32 + 1
+    };
+    let code = if (code & 1) != 0 { -1 - code/2 } else { code/2 };
+
+    grad.0 += code.abs();
+    if grad.1 == params.min_value {
+      grad.0 >>= 1;
+      grad.1 >>= 1;
+    }
+    grad.1 += 1;
+    code
+  }
+
+  fn store(&mut self, params: &FujiParams, idx: usize, val: i32) {
+    let val = if val < 0 {
+      val + params.total_values
+    } else if val > params.q_point[4] {
+      val - params.total_values
+    } else {
+      val
+    };
+    self.lines[idx] = if val < 0 { 0 } else { cmp::min(val, params.q_point[4]) as u16 };
+  }
+
+  fn interpolate(&self, idx: usize) -> i32 {
+    let rb = self.lines[idx - self.pitch] as i32;
+    let rc = self.lines[idx - self.pitch - 1] as i32;
+    let rd = self.lines[idx - self.pitch + 1] as i32;
+    let rf = self.lines[idx - 2*self.pitch] as i32;
+    let diff_rc_rb = (rc - rb).abs();
+    let diff_rf_rb = (rf - rb).abs();
+    let diff_rd_rb = (rd - rb).abs();
+    if diff_rc_rb > diff_rf_rb && diff_rc_rb > diff_rd_rb {
+      rf + rd + 2*rb
+    } else if diff_rd_rb > diff_rc_rb && diff_rd_rb > diff_rf_rb {
+      rf + rc + 2*rb
+    } else {
+      rd + rc + 2*rb
+    }
+  }
+
+  fn interpolate_even(&mut self, line: usize, pos: usize) {
+    let idx = self.pos(line, pos);
+    self.lines[idx] = (self.interpolate(idx) >> 2) as u16;
+  }
+
+  fn decode_even(&mut self, pump: &mut BitPumpMSB, params: &FujiParams, line: usize, pos: usize, set: usize) {
+    let idx = self.pos(line, pos);
+    let rb = self.lines[idx - self.pitch] as i32;
+    let rc = self.lines[idx - self.pitch - 1] as i32;
+    let rf = self.lines[idx - 2*self.pitch] as i32;
+    let grad = params.quantize(rb - rf) * 9 + params.quantize(rc - rb);
+    let interp = self.interpolate(idx) >> 2;
+    let code = self.read_code(pump, params, false, set, grad.abs() as usize);
+    self.store(params, idx, if grad < 0 { interp - code } else { interp + code });
+  }
+
+  fn decode_odd(&mut self, pump: &mut BitPumpMSB, params: &FujiParams, line: usize, pos: usize, set: usize) {
+    let idx = self.pos(line, pos);
+    let ra = self.lines[idx - 1] as i32;
+    let rg = self.lines[idx + 1] as i32;
+    let rb = self.lines[idx - self.pitch] as i32;
+    let rc = self.lines[idx - self.pitch - 1] as i32;
+    let rd = self.lines[idx - self.pitch + 1] as i32;
+    let grad = params.quantize(rb - rc) * 9 + params.quantize(rc - ra);
+    let interp = if (rb > rc && rb > rd) || (rb < rc && rb < rd) {
+      (rg + ra + 2*rb) >> 2
+    } else {
+      (ra + rg) >> 1
+    };
+    let code = self.read_code(pump, params, true, set, grad.abs() as usize);
+    self.store(params, idx, if grad < 0 { interp - code } else { interp + code });
+  }
+
+  // Decodes two lines in lockstep, the even samples running ahead of the odd ones as
+  // these are predicted from both their neighbours. The interp closures tell which
+  // even positions aren't coded at all but just interpolated from the line above.
+  fn decode_pass(&mut self, pump: &mut BitPumpMSB, params: &FujiParams, lines: [usize;2],
+                 interp: [fn(usize) -> bool;2], set: usize) {
+    let mut even = 0;
+    let mut odd = 1;
+    while even < self.line_width || odd < self.line_width {
+      if even < self.line_width {
+        for i in 0..2 {
+          if interp[i](even) {
+            self.interpolate_even(lines[i], even);
+          } else {
+            self.decode_even(pump, params, lines[i], even, set);
           }
         }
+        even += 2;
+      }
+      if even > 8 {
+        for i in 0..2 {
+          self.decode_odd(pump, params, lines[i], odd, set);
+        }
+        odd += 2;
       }
+    }
 
-      (rotatedwidth, rotatedheight, out)
+    for line in lines.iter() {
+      let (start, end) = match *line {
+        FUJI_R0..=FUJI_R4 => (FUJI_R2, FUJI_R4),
+        FUJI_G0..=FUJI_G7 => (FUJI_G2, FUJI_G7),
+        _                 => (FUJI_B2, FUJI_B4),
+      };
+      for l in start..=end {
+        self.extend(l);
+      }
     }
   }
+
+  // Fill in the padding at both ends of the line from the line above
+  fn extend(&mut self, line: usize) {
+    let start = line * self.pitch;
+    self.lines[start] = self.lines[start - self.pitch + 1];
+    self.lines[start + self.line_width + 1] = self.lines[start - self.pitch + self.line_width];
+  }
+
+  fn decode_group(&mut self, pump: &mut BitPumpMSB, params: &FujiParams, xtrans: bool) {
+    let never: fn(usize) -> bool = |_| false;
+    let always: fn(usize) -> bool = |_| true;
+    let at0: fn(usize) -> bool = |pos| (pos & 3) == 0;
+    let at2: fn(usize) -> bool = |pos| (pos & 3) == 2;
+
+    if xtrans {
+      // In X-Trans some of the red and blue positions in each line don't exist in
+      // the sensor and are just interpolated
+      self.decode_pass(pump, params, [FUJI_R2, FUJI_G2], [always, never], 0);
+      self.decode_pass(pump, params, [FUJI_G3, FUJI_B2], [never, always], 1);
+      self.decode_pass(pump, params, [FUJI_R3, FUJI_G4], [at0, never], 2);
+      self.decode_pass(pump, params, [FUJI_G5, FUJI_B3], [never, at2], 0);
+      self.decode_pass(pump, params, [FUJI_R4, FUJI_G6], [at2, never], 1);
+      self.decode_pass(pump, params, [FUJI_G7, FUJI_B4], [never, at0], 2);
+    } else {
+      self.decode_pass(pump, params, [FUJI_R2, FUJI_G2], [never, never], 0);
+      self.decode_pass(pump, params, [FUJI_G3, FUJI_B2], [never, never], 1);
+      self.decode_pass(pump, params, [FUJI_R3, FUJI_G4], [never, never], 2);
+      self.decode_pass(pump, params, [FUJI_G5, FUJI_B3], [never, never], 0);
+      self.decode_pass(pump, params, [FUJI_R4, FUJI_G6], [never, never], 1);
+      self.decode_pass(pump, params, [FUJI_G7, FUJI_B4], [never, never], 2);
+    }
+  }
+
+  fn copy_line(&mut self, from: usize, to: usize) {
+    for i in 0..self.pitch {
+      self.lines[to*self.pitch + i] = self.lines[from*self.pitch + i];
+    }
+  }
+
+  // Move the last lines of each color into the context lines and clear the rest
+  fn next_group(&mut self) {
+    self.copy_line(FUJI_R3, FUJI_R0);
+    self.copy_line(FUJI_R4, FUJI_R1);
+    self.copy_line(FUJI_G6, FUJI_G0);
+    self.copy_line(FUJI_G7, FUJI_G1);
+    self.copy_line(FUJI_B3, FUJI_B0);
+    self.copy_line(FUJI_B4, FUJI_B1);
+
+    for &(line, count) in [(FUJI_R2, 3), (FUJI_G2, 6), (FUJI_B2, 3)].iter() {
+      for val in self.lines[line*self.pitch..(line+count)*self.pitch].iter_mut() {
+        *val = 0;
+      }
+      self.extend(line);
+    }
+  }
+}
+
+impl<'a> RafDecoder<'a> {
+  fn decode_compressed(src: &[u8], width: usize, height: usize, cfa: &CFA, dummy: bool) -> Result<Vec<u16>,String> {
+    let header = FujiHeader::new(src)?;
+    if header.width != width || header.height != height {
+      return Err(format!("RAF: compressed size {}x{} doesn't match image size {}x{}",
+                         header.width, header.height, width, height).to_string())
+    }
+    let params = FujiParams::new(&header);
+
+    // The header is followed by the sizes of each stripe and then the stripes themselves
+    let mut offset = 4 * header.blocks;
+    if offset & 0xC != 0 {
+      offset += 0x10 - (offset & 0xC);
+    }
+    offset += 16;
+    if src.len() < 16 + 4 * header.blocks {
+      return Err("RAF: compressed stripe sizes are truncated".to_string())
+    }
+    let mut stripes = Vec::new();
+    for block in 0..header.blocks {
+      let size = BEu32(src, 16 + block*4) as usize;
+      if offset + size > src.len() {
+        return Err("RAF: compressed stripe is truncated".to_string())
+      }
+      stripes.push((offset, size));
+      offset += size;
+    }
+
+    let mut out = alloc_image_ok!(width, height, dummy);
+
+    let bsize = header.block_size;
+    let decoded = decode_threaded_multiline(bsize, height * header.blocks, height, dummy, &(|strip: &mut [u16], row| {
+      let block = row / height;
+      let (start, size) = stripes[block];
+      let strip_width = if block + 1 == header.blocks { width - bsize * block } else { bsize };
+
+      // The bit pump reads ahead so pad the end of the data if the file ends right there
+      let padded: Vec<u8>;
+      let data = if start + size + 16 <= src.len() {
+        &src[start..]
+      } else {
+        padded = [&src[start..start+size], &[0u8; 16][..]].concat();
+        &padded[..]
+      };
+      let mut pump = BitPumpMSB::new(data);
+      let mut stripe = FujiStripe::new(&params);
+
+      for group in 0..header.total_lines {
+        stripe.decode_group(&mut pump, &params, header.xtrans);
+
+        for r in 0..6 {
+          let outrow = &mut strip[(group*6+r)*bsize..];
+          for col in 0..strip_width {
+            let line = match cfa.color_at(r, col) {
+              0 => FUJI_R2 + (r >> 1),
+              2 => FUJI_B2 + (r >> 1),
+              _ => FUJI_G2 + r,
+            };
+            let pos = if header.xtrans {
+              (((col*2/3) & !1) | ((col%3) & 1)) + ((col%3) >> 1)
+            } else {
+              col >> 1
+            };
+            outrow[col] = stripe.lines[stripe.pos(line, pos)];
+          }
+        }
+
+        stripe.next_group();
+      }
+    }));
+
+    for (row, line) in out.chunks_exact_mut(width).enumerate() {
+      for block in 0..header.blocks {
+        let start = block * bsize;
+        let end = cmp::min(start + bsize, width);
+        let from = (block * height + row) * bsize;
+        line[start..end].copy_from_slice(&decoded[from..from + end - start]);
+      }
+    }
+
+    Ok(out)
+  }
 }
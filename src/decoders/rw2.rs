@@ -4,6 +4,12 @@ use crate::decoders::*;
 use crate::decoders::tiff::*;
 use crate::decoders::basics::*;
 
+// Bit widths of the values packed in each 16 byte block of the format 6 encoding, two
+// full pixels followed by groups of a 2 bit shift and three deltas. The 12 bit layout
+// fills all 128 bits with 14 pixels, the 14 bit one leaves the lowest 4 bits unused.
+const PANA_V6_14BIT: [u32;14] = [14,14,2,10,10,10,2,10,10,10,2,10,10,10];
+const PANA_V6_12BIT: [u32;18] = [12,12,2,8,8,8,2,8,8,8,2,8,8,8,2,8,8,8];
+
 #[derive(Debug, Clone)]
 pub struct Rw2Decoder<'a> {
   buffer: &'a [u8],
@@ -33,7 +39,15 @@ impl<'a> Decoder for Rw2Decoder<'a> {
         height = fetch_tag!(raw, Tag::PanaLength).get_usize(0);
         let offset = fetch_tag!(raw, Tag::PanaOffsets).get_usize(0);
         let src = &self.buffer[offset..];
-        Rw2Decoder::decode_panasonic(src, width, height, true, dummy)
+        let bps = match raw.find_entry(Tag::PanaBitsPerSample) {
+          Some(bps) => bps.get_usize(0),
+          None => 12,
+        };
+        match raw.find_entry(Tag::PanaRawFormat).map(|f| f.get_u32(0)) {
+          Some(6) => Rw2Decoder::decode_panasonic_v6(src, width, height, bps, dummy)?,
+          Some(7) => Rw2Decoder::decode_panasonic_v7(src, width, height, bps, dummy)?,
+          _ => Rw2Decoder::decode_panasonic(src, width, height, true, dummy),
+        }
       } else {
         let raw = fetch_ifd!(&self.tiff, Tag::StripOffsets);
         width = fetch_tag!(raw, Tag::PanaWidth).get_usize(0);
@@ -128,6 +142,112 @@ impl<'a> Rw2Decoder<'a> {
       }
     }))
   }
+
+  // Format 6 packs 11 14 bit or 14 12 bit pixels into each 16 byte block
+  pub(crate) fn decode_panasonic_v6(buf: &[u8], width: usize, height: usize, bps: usize, dummy: bool) -> Result<Vec<u16>,String> {
+    let (widths, pixels): (&[u32], usize) = match bps {
+      12 => (&PANA_V6_12BIT, 14),
+      14 => (&PANA_V6_14BIT, 11),
+      _ => return Err(format!("RW2: Don't know how to decode format 6 with {} bps", bps).to_string()),
+    };
+    let (base0, base_limit, limit, mask) = if bps == 12 {
+      (0x80, 0x800, 0x3fff, 0xfff)
+    } else {
+      (0x200, 0x2000, 0xffff, 0x3fff)
+    };
+    let blocks = width / pixels;
+    if buf.len() < blocks * 16 * height {
+      return Err("RW2: image data is truncated".to_string())
+    }
+
+    Ok(decode_threaded(width, height, dummy, &(|out: &mut [u16], row| {
+      let mut vals = [0 as u32; 18];
+      for (block, out) in out.chunks_exact_mut(pixels).take(blocks).enumerate() {
+        let pos = (row * blocks + block) * 16;
+        pana_block_values(&buf[pos..pos+16], widths, &mut vals);
+        let mut vals = vals.iter();
+        let mut next = || *vals.next().unwrap();
+
+        let mut oddeven = [0 as u32; 2];
+        let mut nonzero = [0 as u32; 2];
+        let mut pmul = 0;
+        let mut pixel_base = 0;
+        for (pix, out) in out.iter_mut().enumerate() {
+          if pix % 3 == 2 {
+            let shift = match next() { 3 => 4, s => s };
+            pixel_base = base0 << shift;
+            pmul = 1 << shift;
+          }
+          let mut epixel = next();
+          if oddeven[pix % 2] != 0 {
+            epixel *= pmul;
+            if pixel_base < base_limit && nonzero[pix % 2] > pixel_base {
+              epixel += nonzero[pix % 2] - pixel_base;
+            }
+            nonzero[pix % 2] = epixel;
+          } else {
+            oddeven[pix % 2] = epixel;
+            if epixel != 0 {
+              nonzero[pix % 2] = epixel;
+            } else {
+              epixel = nonzero[pix % 2];
+            }
+          }
+          let spix = epixel.wrapping_sub(0xf);
+          *out = if spix <= limit {
+            (spix & limit) as u16
+          } else if epixel < 0xf {
+            0
+          } else {
+            mask as u16
+          };
+        }
+      }
+    })))
+  }
+
+  // Format 7 is plain little endian bit packing of 9 14 bit or 10 12 bit pixels into each
+  // 16 byte block
+  pub(crate) fn decode_panasonic_v7(buf: &[u8], width: usize, height: usize, bps: usize, dummy: bool) -> Result<Vec<u16>,String> {
+    let pixels = match bps {
+      12 => 10,
+      14 => 9,
+      _ => return Err(format!("RW2: Don't know how to decode format 7 with {} bps", bps).to_string()),
+    };
+    let blocks = width / pixels;
+    if buf.len() < blocks * 16 * height {
+      return Err("RW2: image data is truncated".to_string())
+    }
+
+    Ok(decode_threaded(width, height, dummy, &(|out: &mut [u16], row| {
+      for (block, out) in out.chunks_exact_mut(pixels).take(blocks).enumerate() {
+        let pos = (row * blocks + block) * 16;
+        let mut bits = u128::from_le_bytes(to_block(&buf[pos..pos+16]));
+        for o in out.iter_mut() {
+          *o = (bits & ((1 << bps) - 1)) as u16;
+          bits >>= bps;
+        }
+      }
+    })))
+  }
+}
+
+fn to_block(src: &[u8]) -> [u8;16] {
+  let mut block = [0 as u8; 16];
+  block.copy_from_slice(src);
+  block
+}
+
+// Read the values of a format 6 block, which is a 128 bit little endian number read
+// starting at the most significant bits
+fn pana_block_values(block: &[u8], widths: &[u32], out: &mut [u32]) {
+  debug_assert!(widths.iter().sum::<u32>() <= 128, "format 6 block layout is larger than the block");
+  let bits = u128::from_le_bytes(to_block(block));
+  let mut pos = 128;
+  for (o, &width) in out.iter_mut().zip(widths.iter()) {
+    pos -= width;
+    *o = ((bits >> pos) & ((1 << width) - 1)) as u32;
+  }
 }
 
 pub struct BitPumpPanasonic<'a> {
@@ -0,0 +1,158 @@
+use std::f32::NAN;
+use std::cmp;
+
+use crate::decoders::*;
+use crate::decoders::basics::*;
+use crate::decoders::cfa::*;
+
+const SER_HEADER_SIZE: usize = 178;
+// Timestamps are .NET ticks, 100ns intervals since 0001-01-01, this is the Unix epoch
+const SER_UNIX_EPOCH: i64 = 621355968000000000;
+
+pub fn is_ser(buf: &[u8]) -> bool {
+  buf.len() >= SER_HEADER_SIZE && buf[0..14] == b"LUCAM-RECORDER"[..]
+}
+
+#[derive(Debug, Clone)]
+pub struct SerDecoder<'a> {
+  buffer: &'a [u8],
+  color_id: u32,
+  little_endian: bool,
+  width: usize,
+  height: usize,
+  depth: usize,
+  planes: usize,
+  frame_size: usize,
+  frames: usize,
+  timestamps: Option<usize>,
+}
+
+impl<'a> SerDecoder<'a> {
+  pub fn new(buf: &'a [u8]) -> Result<SerDecoder<'a>, String> {
+    if buf.len() < SER_HEADER_SIZE {
+      return Err("SER: file is too short for the header".to_string())
+    }
+    let color_id = LEu32(buf, 18);
+    // The spec says 1 is little endian but the original software wrote it the other way
+    // around and everyone followed suit
+    let little_endian = LEu32(buf, 22) == 0;
+    let width = LEu32(buf, 26) as usize;
+    let height = LEu32(buf, 30) as usize;
+    let depth = LEu32(buf, 34) as usize;
+    let frames = LEu32(buf, 38) as usize;
+    if width == 0 || height == 0 || depth == 0 || depth > 16 {
+      return Err(format!("SER: Don't know how to decode {}x{} frames with depth {}", width, height, depth))
+    }
+    let planes = match color_id {
+      100 | 101 => 3,
+      _ => 1,
+    };
+    let frame_size = match width.checked_mul(height)
+      .and_then(|size| size.checked_mul(planes * if depth > 8 { 2 } else { 1 })) {
+      Some(size) => size,
+      None => return Err(format!("SER: {}x{} frames are too big", width, height)),
+    };
+
+    // Interrupted recordings can have fewer frames than the header says and no trailer
+    let available = (buf.len() - SER_HEADER_SIZE) / frame_size;
+    let data_end = frames.checked_mul(frame_size).and_then(|size| size.checked_add(SER_HEADER_SIZE));
+    let trailer_end = data_end.and_then(|end| frames.checked_mul(8).and_then(|size| end.checked_add(size)));
+    let timestamps = match (data_end, trailer_end) {
+      (Some(data_end), Some(trailer_end)) if frames <= available && buf.len() >= trailer_end => Some(data_end),
+      _ => None,
+    };
+
+    Ok(SerDecoder {
+      buffer: buf,
+      color_id: color_id,
+      little_endian: little_endian,
+      width: width,
+      height: height,
+      depth: depth,
+      planes: planes,
+      frame_size: frame_size,
+      frames: cmp::min(frames, available),
+      timestamps: timestamps,
+    })
+  }
+
+  // UTC timestamp of a frame in ticks, if the trailer has one
+  fn ticks(&self, frame: usize) -> Option<i64> {
+    let pos = self.timestamps? + frame * 8;
+    match LEu64(self.buffer, pos) as i64 {
+      0 => None,
+      ticks => Some(ticks),
+    }
+  }
+
+  fn get_string(&self, offset: usize) -> String {
+    String::from_utf8_lossy(&self.buffer[offset..offset+40]).split_terminator("\0").next().unwrap_or("").trim().to_string()
+  }
+}
+
+impl<'a> Decoder for SerDecoder<'a> {
+  fn image(&self, dummy: bool) -> Result<RawImage,String> {
+    self.frame(0, dummy)
+  }
+
+  fn frame_count(&self) -> usize {
+    self.frames
+  }
+
+  fn frame(&self, frame: usize, dummy: bool) -> Result<RawImage,String> {
+    if frame >= self.frames {
+      return Err(format!("SER: Couldn't find frame {}", frame))
+    }
+
+    let mut camera = Camera::new();
+    // There's no make and model, the closest is the instrument and telescope
+    let make = self.get_string(82);
+    let model = self.get_string(122);
+    camera.make = make.clone();
+    camera.clean_make = make;
+    camera.model = model.clone();
+    camera.clean_model = model;
+    let white = ((1u32 << self.depth) - 1) as u16;
+    camera.whitelevels = [white, white, white, white];
+    camera.cfa = match self.color_id {
+      0 | 100 | 101 => CFA::new(""),
+      8 => CFA::new("RGGB"),
+      9 => CFA::new("GRBG"),
+      10 => CFA::new("GBRG"),
+      11 => CFA::new("BGGR"),
+      id => return Err(format!("SER: Don't know how to decode color id {}", id)),
+    };
+
+    let src = &self.buffer[SER_HEADER_SIZE + frame * self.frame_size..];
+    let width = self.width * self.planes;
+    let mut image = if self.depth <= 8 {
+      decode_8bit(src, width, self.height, dummy)
+    } else if self.little_endian {
+      decode_16le(src, width, self.height, dummy)
+    } else {
+      decode_16be(src, width, self.height, dummy)
+    };
+    if self.color_id == 101 && !dummy {
+      for pixel in image.chunks_exact_mut(3) {
+        pixel.swap(0, 2);
+      }
+    }
+
+    let ticks = self.ticks(frame);
+    let mut img = RawImage::new(camera, self.width, self.height, [NAN,NAN,NAN,NAN], image, dummy);
+    img.cpp = self.planes;
+    img.frame_info = Some(FrameInfo {
+      number: frame,
+      timestamp: match (ticks, self.ticks(0)) {
+        (Some(time), Some(start)) => (time - start) as f64 / 10000000.0,
+        _ => std::f64::NAN,
+      },
+      exposure_time: NAN,
+      iso: 0,
+      fps: NAN,
+      timecode: None,
+      utc_time: ticks.map(|t| (t - SER_UNIX_EPOCH) as f64 / 10000000.0),
+    });
+    Ok(img)
+  }
+}
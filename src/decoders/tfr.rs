@@ -1,9 +1,12 @@
+use std::cmp;
 use std::f32::NAN;
+use std::ptr;
 
 use crate::decoders::*;
 use crate::decoders::tiff::*;
 use crate::decoders::ljpeg::*;
 use crate::decoders::basics::*;
+use crate::decoders::cfa::*;
 
 #[derive(Debug, Clone)]
 pub struct TfrDecoder<'a> {
@@ -22,29 +25,137 @@ impl<'a> TfrDecoder<'a> {
   }
 }
 
+// Multishot files have four frames with the sensor moved by one pixel between each, the
+// 6-shot ones add two more frames shifted by half a pixel that don't fit in that grid
+const TFR_PIXEL_SHIFT: [(usize,usize);4] = [(0,0), (0,1), (1,1), (1,0)];
+
 impl<'a> Decoder for TfrDecoder<'a> {
   fn image(&self, dummy: bool) -> Result<RawImage,String> {
-    let camera = self.rawhide.check_supported(&self.tiff)?;
-    let raw = fetch_ifd!(&self.tiff, Tag::WhiteLevel);
+    self.frame(0, dummy)
+  }
+
+  fn frame_count(&self) -> usize {
+    cmp::max(1, self.raw_ifds().len())
+  }
+
+  fn frame(&self, frame: usize, dummy: bool) -> Result<RawImage,String> {
+    let raws = self.raw_ifds();
+    let raw = match raws.get(frame) {
+      Some(raw) => *raw,
+      None if frame == 0 => return Err("3FR: Couldn't find the raw data".to_string()),
+      None => return Err(format!("3FR: Couldn't find frame {}", frame)),
+    };
     let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
     let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
     let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
+    let cpp = match raw.find_entry(Tag::SamplesPerPixel) {
+      Some(spp) => spp.get_usize(0),
+      None => 1,
+    };
+    let compression = match raw.find_entry(Tag::Compression) {
+      Some(c) => c.get_u32(0),
+      None => 1,
+    };
+    if offset >= self.buffer.len() {
+      return Err("3FR: image data starts beyond the end of the file".to_string())
+    }
     let src = &self.buffer[offset..];
 
-    let image = if camera.find_hint("uncompressed") {
-      decode_16le(src, width, height, dummy)
-    } else {
+    let camera = match self.rawhide.check_supported(&self.tiff) {
+      Ok(camera) => camera,
+      // Flextight scans and full color Imacon files aren't in the camera database but
+      // they don't need anything from it, the levels are in the file
+      Err(_) if cpp == 3 && fetch_tag!(self.tiff, Tag::Make).get_str() == "Imacon" => self.scanner_camera()?,
+      Err(e) => return Err(e),
+    };
+
+    // Newer cameras (X1D, H6D) and FFF files can be stored uncompressed, check that
+    // the strip is exactly that size as older files say uncompressed for LJPEG data
+    let strip_size = raw.find_entry(Tag::StripByteCounts)
+      .map(|counts| (0..counts.count()).map(|i| counts.get_usize(i)).sum::<usize>());
+    let uncompressed = camera.find_hint("uncompressed") ||
+      (compression == 1 && strip_size == Some(width*height*cpp*2));
+    let image = if uncompressed {
+      if raw.little_endian() {
+        decode_16le(src, width*cpp, height, dummy)
+      } else {
+        decode_16be(src, width*cpp, height, dummy)
+      }
+    } else if cpp == 1 {
       self.decode_compressed(src, width, height, dummy)?
+    } else {
+      return Err(format!("3FR: Don't know how to decode compressed images with {} components", cpp))
     };
 
-    ok_image(camera, width, height, self.get_wb()?, image)
+    let black = match raw.find_entry(Tag::BlackLevels) {
+      Some(levels) => levels.get_force_u32(0) as u16,
+      None => camera.blacklevels[0],
+    };
+    let white = match raw.find_entry(Tag::WhiteLevel) {
+      Some(levels) => levels.get_force_u32(0) as u16,
+      None => camera.whitelevels[0],
+    };
+    let frames = raws.len();
+    let mut img = ok_image_with_black_white(camera, width*cpp, height, self.get_wb()?, black, white, image)?;
+    if cpp == 3 {
+      // Imacon/Flextight scans are already full color
+      img.cpp = 3;
+      img.width = width;
+      img.cfa = CFA::new("");
+    }
+    if frames >= TFR_PIXEL_SHIFT.len() && frame < TFR_PIXEL_SHIFT.len() {
+      img.pixel_shift = Some(TFR_PIXEL_SHIFT[frame]);
+    }
+    Ok(img)
   }
 }
 
 impl<'a> TfrDecoder<'a> {
+  // The raw IFDs are the ones with a WhiteLevel, all the same size as the first one.
+  // FFF files from Imacon backs and scanners don't have it so use the largest image.
+  fn raw_ifds(&self) -> Vec<&TiffIFD> {
+    let size = |ifd: &TiffIFD| -> Option<(usize, usize)> {
+      Some((ifd.find_entry(Tag::ImageWidth)?.get_usize(0), ifd.find_entry(Tag::ImageLength)?.get_usize(0)))
+    };
+    let mut candidates = self.tiff.find_ifds_with_tag(Tag::WhiteLevel);
+    if candidates.is_empty() {
+      candidates = self.tiff.find_ifds_with_tag(Tag::StripOffsets);
+      candidates.sort_by_key(|ifd| size(ifd).map(|(w,h)| w*h).unwrap_or(0));
+      candidates.reverse();
+    }
+    let main = match candidates.first() {
+      Some(main) => size(main),
+      None => return Vec::new(),
+    };
+
+    let mut raws: Vec<&TiffIFD> = Vec::new();
+    for ifd in candidates {
+      if ifd.has_entry(Tag::StripOffsets) && size(ifd).is_some() && size(ifd) == main &&
+         !raws.iter().any(|r| ptr::eq(*r, ifd)) {
+        raws.push(ifd);
+      }
+    }
+    raws
+  }
+
+  fn scanner_camera(&self) -> Result<Camera, String> {
+    let mut camera = Camera::new();
+    let make = fetch_tag!(self.tiff, Tag::Make).get_str().to_string();
+    let model = self.tiff.find_entry(Tag::Model).map(|m| m.get_str().to_string()).unwrap_or_default();
+    camera.make = make.clone();
+    camera.clean_make = make;
+    camera.model = model.clone();
+    camera.clean_model = model;
+    camera.whitelevels = [65535, 65535, 65535, 65535];
+    camera.orientation = Orientation::from_tiff(&self.tiff);
+    Ok(camera)
+  }
+
   fn get_wb(&self) -> Result<[f32;4], String> {
-    let levels = fetch_tag!(self.tiff, Tag::AsShotNeutral);
-    Ok([1.0/levels.get_f32(0),1.0/levels.get_f32(1),1.0/levels.get_f32(2),NAN])
+    match self.tiff.find_entry(Tag::AsShotNeutral) {
+      Some(levels) => Ok([1.0/levels.get_f32(0),1.0/levels.get_f32(1),1.0/levels.get_f32(2),NAN]),
+      None => Ok([NAN,NAN,NAN,NAN]),
+    }
   }
 
   fn decode_compressed(&self, src: &[u8], width: usize, height: usize, dummy: bool) -> Result<Vec<u16>,String> {
@@ -8,6 +8,7 @@ use crate::decoders::basics::*;
   pub enum Tag {
     PanaWidth        = 0x0002,
     PanaLength       = 0x0003,
+    PanaBitsPerSample = 0x000A,
     NefWB0           = 0x000C,
     PanaWBsR         = 0x0011,
     PanaWBsB         = 0x0012,
@@ -16,6 +17,7 @@ use crate::decoders::basics::*;
     PanaWBs2R        = 0x0024,
     PanaWBs2G        = 0x0025,
     PanaWBs2B        = 0x0026,
+    PanaRawFormat    = 0x002D,
     Cr2PowerShotWB   = 0x0029,
     NewSubFileType   = 0x00FE,
     Cr2OldOffset     = 0x0081,
@@ -23,6 +25,7 @@ use crate::decoders::basics::*;
     NefMeta2         = 0x0096,
     NefWB1           = 0x0097,
     Cr2OldWB         = 0x00A4,
+    CanonSensorInfo  = 0x00E0,
     NefKey           = 0x00a7,
     ImageWidth       = 0x0100,
     ImageLength      = 0x0101,
@@ -34,14 +37,18 @@ use crate::decoders::basics::*;
     StripOffsets     = 0x0111,
     Orientation      = 0x0112,
     SamplesPerPixel  = 0x0115,
+    RowsPerStrip     = 0x0116,
     StripByteCounts  = 0x0117,
     PanaOffsets      = 0x0118,
     GrayResponse     = 0x0123,
     Software         = 0x0131,
+    Predictor        = 0x013D,
     TileWidth        = 0x0142,
     TileLength       = 0x0143,
     TileOffsets      = 0x0144,
+    TileByteCounts   = 0x0145,
     SubIFDs          = 0x014A,
+    SampleFormat     = 0x0153,
     PefBlackLevels   = 0x0200,
     PefWB            = 0x0201,
     PefHuffman       = 0x0220,
@@ -61,11 +68,15 @@ use crate::decoders::basics::*;
     SonyLength       = 0x7201,
     SonyKey          = 0x7221,
     SonyGRBG         = 0x7303,
+    SonyBlackLevel   = 0x7310,
     SonyRGGB         = 0x7313,
+    SonyWhiteLevel   = 0x787F,
     CFAPattern       = 0x828E,
     KodakIFD         = 0x8290,
+    ExposureTime     = 0x829A,
     LeafMetadata     = 0x8606,
     ExifIFDPointer   = 0x8769,
+    ISOSpeedRatings  = 0x8827,
     Makernote        = 0x927C,
     SrwSensorAreas   = 0xA010,
     SrwRGGBLevels    = 0xA021,
@@ -73,15 +84,40 @@ use crate::decoders::basics::*;
     Cr2Id            = 0xc5d8,
     DNGVersion       = 0xC612,
     Linearization    = 0xC618,
+    BlackLevelRepeatDim = 0xC619,
     BlackLevels      = 0xC61A,
+    BlackLevelDeltaH = 0xC61B,
+    BlackLevelDeltaV = 0xC61C,
     WhiteLevel       = 0xC61D,
+    DefaultScale     = 0xC61E,
+    DefaultCropOrigin = 0xC61F,
+    DefaultCropSize  = 0xC620,
     ColorMatrix1     = 0xC621,
     ColorMatrix2     = 0xC622,
+    CameraCalibration1 = 0xC623,
+    CameraCalibration2 = 0xC624,
+    AnalogBalance    = 0xC627,
     AsShotNeutral    = 0xC628,
+    AsShotWhiteXY    = 0xC629,
+    BaselineExposure = 0xC62A,
     DNGPrivateArea   = 0xC634,
     Cr2StripeWidths  = 0xC640,
+    CalibrationIlluminant1 = 0xC65A,
+    CalibrationIlluminant2 = 0xC65B,
+    BestQualityScale = 0xC65C,
     ActiveArea       = 0xC68D,
     MaskedAreas      = 0xC68E,
+    ForwardMatrix1   = 0xC714,
+    ForwardMatrix2   = 0xC715,
+    OpcodeList1      = 0xC740,
+    OpcodeList2      = 0xC741,
+    OpcodeList3      = 0xC74E,
+    TimeCodes        = 0xC763,
+    FrameRate        = 0xC764,
+    DefaultUserCrop  = 0xC7B5,
+    SemanticName     = 0xCD2E,
+    SemanticInstanceID = 0xCD30,
+    MaskSubArea      = 0xCD38,
     RafRawSubIFD     = 0xF000,
     RafImageWidth    = 0xF001,
     RafImageLength   = 0xF002,
@@ -472,6 +508,12 @@ impl<'a> TiffEntry<'a> {
       let a = self.endian.ri32(self.data, idx*8) as f32;
       let b = self.endian.ri32(self.data, idx*8+4) as f32;
       a / b
+    } else if self.typ == 11 { // Float
+      f32::from_bits(self.endian.ru32(self.data, idx*4))
+    } else if self.typ == 12 { // Double
+      let (lo, hi) = if self.endian.little() { (idx*8, idx*8+4) } else { (idx*8+4, idx*8) };
+      let bits = ((self.endian.ru32(self.data, hi) as u64) << 32) | (self.endian.ru32(self.data, lo) as u64);
+      f64::from_bits(bits) as f32
     } else {
       self.get_u32(idx) as f32
     }
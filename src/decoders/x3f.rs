@@ -1,4 +1,6 @@
 use std::f32::NAN;
+use std::cmp;
+use rayon::prelude::*;
 
 use crate::decoders::*;
 use crate::decoders::tiff::*;
@@ -124,26 +126,351 @@ impl<'a> Decoder for X3fDecoder<'a> {
         .ok_or("X3F: Couldn't find image".to_string())?;
     let width = imginfo.width;
     let height = imginfo.height;
-    let offset = imginfo.doffset;
-    let src = &self.buffer[offset..];
 
     let image = match imginfo.format {
-      35 => self.decode_compressed(src, width, height, dummy)?,
-        x => return Err(format!("X3F Don't know how to decode format {}", x).to_string())
-      };
-  
-      let mut img = RawImage::new(camera, width, height, self.get_wb()?, image, dummy);
-      img.cpp = 3;
-      Ok(img)
+      30 | 31 | 35 => self.decode_true(imginfo, width, height, dummy)?,
+      x => return Err(format!("X3F Don't know how to decode format {}", x).to_string())
+    };
+
+    let mut img = RawImage::new(camera, width, height, self.get_wb(), image, dummy);
+    img.cpp = 3;
+    Ok(img)
   }
 }
 
 impl<'a> X3fDecoder<'a> {
-  fn get_wb(&self) -> Result<[f32;4], String> {
-    Ok([NAN,NAN,NAN,NAN])
+  fn find_dir(&self, id: &str) -> Option<&[u8]> {
+    self.dir.dirs.iter().find(|d| d.id == id).map(|d| {
+      let end = cmp::min(d.offset + d.len, self.buffer.len());
+      &self.buffer[d.offset..end]
+    })
+  }
+
+  fn get_wb(&self) -> [f32;4] {
+    let camf = match self.get_camf() {
+      Some(camf) => camf,
+      None => return [NAN,NAN,NAN,NAN],
+    };
+
+    // The name of the white balance preset is in the PROP section and CAMF has the
+    // gains for each preset, plus a few per unit and per exposure corrections
+    let wb = self.get_prop("WB_DESC").unwrap_or("Auto".to_string());
+    let mut gains = match camf.wb_matrix("WhiteBalanceGains", &wb)
+                          .or_else(|| camf.wb_matrix("DP1_WhiteBalanceGains", &wb)) {
+      Some(ref gains) if gains.len() >= 3 => [gains[0], gains[1], gains[2]],
+      _ => return [NAN,NAN,NAN,NAN],
+    };
+    for fact in ["SensorAdjustmentGainFact", "TempGainFact", "FNumberGainFact"].iter() {
+      if let Some(vals) = camf.matrix(fact) {
+        if vals.len() >= 3 {
+          for i in 0..3 {
+            gains[i] *= vals[i];
+          }
+        }
+      }
+    }
+
+    [gains[0] as f32, gains[1] as f32, gains[2] as f32, NAN]
+  }
+
+  // Properties are pairs of zero terminated UTF-16 strings
+  fn get_prop(&self, name: &str) -> Option<String> {
+    let sec = self.find_dir("PROP")?;
+    if sec.len() < 24 || &sec[0..4] != b"SECp" || LEu32(sec, 12) != 0 {
+      return None
+    }
+    let num = LEu32(sec, 8) as usize;
+    let data = 24 + num * 8;
+    let read_str = |off: usize| -> String {
+      let chars: Vec<u16> = sec[data+off*2..].chunks_exact(2)
+        .map(|c| LEu16(c, 0))
+        .take_while(|&c| c != 0)
+        .collect();
+      String::from_utf16_lossy(&chars)
+    };
+    for i in 0..num {
+      if read_str(LEu32(sec, 24+i*8) as usize) == name {
+        return Some(read_str(LEu32(sec, 28+i*8) as usize))
+      }
+    }
+    None
+  }
+
+  fn get_camf(&self) -> Option<X3fCamf> {
+    let sec = self.find_dir("CAMF")?;
+    if sec.len() < 28 || &sec[0..4] != b"SECc" {
+      return None
+    }
+    let typ = LEu32(sec, 8);
+    let vals = [LEu32(sec, 12), LEu32(sec, 16), LEu32(sec, 20), LEu32(sec, 24)];
+    let src = &sec[28..];
+
+    let data = match typ {
+      2 => {
+        // Older cameras just obfuscate the contents
+        let mut key = vals[3];
+        src.iter().map(|&val| {
+          key = key.wrapping_mul(1597).wrapping_add(51749) % 244944;
+          let tmp = ((key as u64 * 301593171) >> 24) as u32;
+          val ^ (((((key << 8).wrapping_sub(tmp) >> 1) + tmp) >> 17) as u8)
+        }).collect()
+      },
+      4 | 5 => {
+        let size = vals[0] as usize;
+        let bias = vals[1] as i32;
+        // The huffman table isn't followed by a terminator here, the stream starts
+        // right at the first zero size
+        let mut pos = 0;
+        while pos+1 < src.len() && src[pos] != 0 {
+          pos += 2;
+        }
+        let huff = X3fHuffman::new(&src[0..pos]).ok()?;
+        let mut pump = BitPumpMSB::new(&src[pos..]);
+        if typ == 4 {
+          // Encoded in the same way as the image planes with two 12 bit values
+          // packed into each three bytes
+          let cols = vals[2] as usize;
+          let rows = vals[3] as usize;
+          let values = X3fDecoder::decode_true_plane(&mut pump, &huff, bias, cols, rows);
+          let mut data = Vec::with_capacity(size+2);
+          for pair in values.chunks(2) {
+            if data.len() >= size {
+              break
+            }
+            let v0 = pair[0];
+            let v1 = if pair.len() > 1 { pair[1] } else { 0 };
+            data.push((v0 >> 4) as u8);
+            data.push((((v0 << 4) & 0xf0) | ((v1 >> 8) & 0x0f)) as u8);
+            data.push(v1 as u8);
+          }
+          data.truncate(size);
+          data
+        } else {
+          let mut acc = bias;
+          (0..size).map(|_| {
+            acc += huff.diff(&mut pump);
+            acc as u8
+          }).collect()
+        }
+      },
+      _ => return None,
+    };
+
+    Some(X3fCamf{ data: data })
+  }
+
+  fn decode_true(&self, imginfo: &X3fImage, width: usize, height: usize, dummy: bool) -> Result<Vec<u16>, String> {
+    let data = &self.buffer[imginfo.doffset..];
+    let quattro = imginfo.format == 35;
+    let mut pos = 0;
+
+    // Quattro sensors have a top layer with double the resolution of the other two
+    let mut dims: [(usize, usize);3] = [(width, height);3];
+    if quattro {
+      for i in 0..3 {
+        dims[i] = (LEu16(data, pos) as usize, LEu16(data, pos+2) as usize);
+        pos += 4;
+      }
+      if dims[0].1 != height && dims[0].1 != height/2 {
+        return Err(format!("X3F: Quattro file with unknown layer size {}", dims[0].1).to_string())
+      }
+    }
+
+    let seeds = [LEu16(data, pos) as i32, LEu16(data, pos+2) as i32, LEu16(data, pos+4) as i32];
+    pos += 8;
+    let mut tlen = 0;
+    while data[pos+tlen] != 0 {
+      tlen += 2;
+    }
+    let huff = X3fHuffman::new(&data[pos..pos+tlen])?;
+    pos += tlen + 2;
+    if quattro {
+      pos += 4;
+    }
+
+    // Each plane is 16 byte aligned
+    let mut offsets = [0;3];
+    let mut offset = pos + 12;
+    for i in 0..3 {
+      offsets[i] = offset;
+      offset += (LEu32(data, pos + i*4) as usize + 15) / 16 * 16;
+    }
+    if offset > data.len() {
+      return Err("X3F: image data is truncated".to_string())
+    }
+
+    let mut out = alloc_image_ok!(width*3, height, dummy);
+    let planes: Vec<Vec<i32>> = (0..3usize).into_par_iter().map(|i| {
+      let mut pump = BitPumpMSB::new(&data[offsets[i]..]);
+      X3fDecoder::decode_true_plane(&mut pump, &huff, seeds[i], dims[i].0, dims[i].1)
+    }).collect();
+
+    for (i, plane) in planes.iter().enumerate() {
+      let (pwidth, pheight) = dims[i];
+      // Lower resolution layers get upscaled and binned layers can be slightly
+      // wider than the image so they get cropped
+      let scale = if pheight < height { 2 } else { 1 };
+      for (row, line) in out.chunks_exact_mut(width*3).enumerate() {
+        let prow = cmp::min(row / scale, pheight - 1);
+        for col in 0..width {
+          let pcol = cmp::min(col / scale, pwidth - 1);
+          line[col*3+i] = clampbits(plane[prow*pwidth+pcol], 16);
+        }
+      }
+    }
+
+    Ok(out)
+  }
+
+  // Values are differences to the previous pixel of the same color in a 2x2 pattern,
+  // with the first two pixels of each line predicted from the line two above
+  fn decode_true_plane(pump: &mut BitPumpMSB, huff: &X3fHuffman, seed: i32, width: usize, height: usize) -> Vec<i32> {
+    let mut out = vec![0 as i32; width*height];
+    let mut row_start = [[seed;2];2];
+    for row in 0..height {
+      let mut acc = [0 as i32;2];
+      for col in 0..width {
+        let diff = huff.diff(pump);
+        let value = if col < 2 {
+          row_start[row&1][col&1] += diff;
+          row_start[row&1][col&1]
+        } else {
+          acc[col&1] + diff
+        };
+        acc[col&1] = value;
+        out[row*width+col] = value;
+      }
+    }
+    out
+  }
+}
+
+// Huffman table for the number of bits of each difference, with codes of up to 8 bits
+#[derive(Debug, Clone)]
+struct X3fHuffman {
+  table: Vec<(u32, u32)>,
+}
+
+impl X3fHuffman {
+  fn new(src: &[u8]) -> Result<X3fHuffman, String> {
+    let mut table = vec![(0,0); 256];
+    for (bits, entry) in src.chunks_exact(2).enumerate() {
+      let len = entry[0] as u32;
+      if len == 0 {
+        continue
+      }
+      if len > 8 {
+        return Err(format!("X3F: invalid huffman code length {}", len).to_string())
+      }
+      let code = entry[1] as usize;
+      let count = 1 << (8 - len);
+      let start = code & !(count - 1);
+      for t in table[start..start+count].iter_mut() {
+        *t = (len, bits as u32);
+      }
+    }
+    Ok(X3fHuffman {
+      table: table,
+    })
+  }
+
+  #[inline(always)]
+  fn diff(&self, pump: &mut BitPumpMSB) -> i32 {
+    let (len, bits) = self.table[pump.peek_bits(8) as usize];
+    pump.consume_bits(len);
+    if bits == 0 {
+      return 0
+    }
+    let diff = pump.get_bits(bits) as i32;
+    if (diff >> (bits - 1)) == 0 {
+      diff - ((1 << bits) - 1)
+    } else {
+      diff
+    }
+  }
+}
+
+// Decoded CAMF section, a list of entries that are either text, property lists or
+// multi-dimensional matrices
+#[derive(Debug, Clone)]
+struct X3fCamf {
+  data: Vec<u8>,
+}
+
+impl X3fCamf {
+  fn entry(&self, typ: u8, name: &str) -> Option<(&[u8], usize)> {
+    let mut pos = 0;
+    while pos + 20 <= self.data.len() {
+      let magic = LEu32(&self.data, pos);
+      if magic & 0xffffff != 0x624d43 { // "CMb"
+        break
+      }
+      let size = LEu32(&self.data, pos+8) as usize;
+      if size < 20 || pos + size > self.data.len() {
+        break
+      }
+      let entry = &self.data[pos..pos+size];
+      if (magic >> 24) as u8 == typ && X3fCamf::string(entry, LEu32(entry, 12) as usize) == name {
+        return Some((entry, LEu32(entry, 16) as usize))
+      }
+      pos += size;
+    }
+    None
+  }
+
+  fn string(entry: &[u8], offset: usize) -> String {
+    let bytes: Vec<u8> = entry[cmp::min(offset, entry.len())..].iter()
+      .cloned()
+      .take_while(|&c| c != 0)
+      .collect();
+    String::from_utf8_lossy(&bytes).to_string()
+  }
+
+  fn property(&self, list: &str, name: &str) -> Option<String> {
+    let (entry, value) = self.entry(b'P', list)?;
+    let num = LEu32(entry, value) as usize;
+    let off = LEu32(entry, value+4) as usize;
+    for i in 0..num {
+      let pos = value + 8 + i*8;
+      if X3fCamf::string(entry, off + LEu32(entry, pos) as usize) == name {
+        return Some(X3fCamf::string(entry, off + LEu32(entry, pos+4) as usize))
+      }
+    }
+    None
+  }
+
+  fn matrix(&self, name: &str) -> Option<Vec<f64>> {
+    let (entry, value) = self.entry(b'M', name)?;
+    let typ = LEu32(entry, value);
+    let dims = LEu32(entry, value+4) as usize;
+    let off = LEu32(entry, value+8) as usize;
+    let mut count = 1;
+    for i in 0..dims {
+      count *= LEu32(entry, value + 12 + i*12) as usize;
+    }
+    let size = match typ {
+      0 | 6 => 2,
+      5 => 1,
+      _ => 4,
+    };
+    if off + count*size > entry.len() {
+      return None
+    }
+    Some((0..count).map(|i| {
+      let pos = off + i*size;
+      match typ {
+        0 => LEu16(entry, pos) as i16 as f64,
+        3 => LEf32(entry, pos) as f64,
+        5 => entry[pos] as f64,
+        6 => LEu16(entry, pos) as f64,
+        _ => LEu32(entry, pos) as f64,
+      }
+    }).collect())
   }
 
-  fn decode_compressed(&self, _buf: &[u8], _width: usize, _height: usize, _dummy: bool) -> Result<Vec<u16>, String> {
-    return Err("X3F decoding not implemented yet".to_string())
+  // White balance dependent values are stored as a property list that points to
+  // the matrix to use for each preset
+  fn wb_matrix(&self, list: &str, wb: &str) -> Option<Vec<f64>> {
+    self.matrix(&self.property(list, wb)?)
   }
 }
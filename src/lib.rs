@@ -56,6 +56,24 @@ pub use decoders::Orientation;
 pub use decoders::RawHide;
 pub use decoders::RawImage;
 pub use decoders::RawImageData;
+pub use decoders::DecodeOptions;
+pub use decoders::SuperCcdLayout;
+pub use decoders::FrameInfo;
+pub use decoders::TimeCode;
+pub use decoders::DngSequence;
+pub use decoders::DngColorData;
+pub use decoders::DngLevels;
+pub use decoders::DngDefaults;
+pub use decoders::DngSemanticMask;
+pub use decoders::DngOpcodes;
+pub use decoders::DngOpcode;
+pub use decoders::DngOpcodeArea;
+pub use decoders::DngOperation;
+pub use decoders::DngGainMap;
+pub use decoders::IiqCalibration;
+pub use decoders::IiqDefect;
+pub use decoders::IiqDefectKind;
+pub use decoders::IiqFlatField;
 
 lazy_static! {
   static ref LOADER: RawHide = decoders::RawHide::new();
@@ -120,6 +138,109 @@ pub fn decode(reader: &mut dyn Read) -> Result<RawImage, RawHideError> {
   LOADER.decode(reader, false).map_err(|err| RawHideError::new(err))
 }
 
+/// Take a path to a raw file and return a decoded image or an error, using non-default
+/// decoding options
+///
+/// # Example
+/// ```rust,ignore
+/// let options = rawhide::DecodeOptions { superccd_native: true, ..Default::default() };
+/// let image = match rawhide::decode_file_with_options("path/to/your/file.RAF", &options) {
+///   Ok(val) => val,
+///   Err(e) => ... some appropriate action when the file is unreadable ...
+/// };
+/// ```
+pub fn decode_file_with_options<P: AsRef<Path>>(path: P, options: &DecodeOptions) -> Result<RawImage, RawHideError> {
+  LOADER
+    .decode_file_with_options(path.as_ref(), options)
+    .map_err(|err| RawHideError::new(err))
+}
+
+/// Take a readable source and return a decoded image or an error, using non-default
+/// decoding options
+pub fn decode_with_options(reader: &mut dyn Read, options: &DecodeOptions) -> Result<RawImage, RawHideError> {
+  LOADER.decode_with_options(reader, options, false).map_err(|err| RawHideError::new(err))
+}
+
+/// Take a path to a raw file and return all the frames it contains or an error. Most
+/// formats only have one, Canon Dual Pixel RAW files have the full image followed by
+/// the A-only frame and Fuji SuperCCD SR files the S and R photodiode frames, both
+/// with the same size and crops.
+///
+/// # Example
+/// ```rust,ignore
+/// let frames = match rawhide::decode_file_frames("path/to/your/file.CR2") {
+///   Ok(val) => val,
+///   Err(e) => ... some appropriate action when the file is unreadable ...
+/// };
+/// ```
+pub fn decode_file_frames<P: AsRef<Path>>(path: P) -> Result<Vec<RawImage>, RawHideError> {
+  LOADER
+    .decode_file_frames(path.as_ref())
+    .map_err(|err| RawHideError::new(err))
+}
+
+/// Take a readable source and return all the frames it contains or an error
+pub fn decode_frames(reader: &mut dyn Read) -> Result<Vec<RawImage>, RawHideError> {
+  LOADER.decode_frames(reader, false).map_err(|err| RawHideError::new(err))
+}
+
+/// Take a buffer and return how many frames it contains or an error
+///
+/// # Example
+/// ```rust,ignore
+/// let mut file = File::open("path/to/your/file.MLV").unwrap();
+/// let buffer = rawhide::Buffer::new(&mut file).unwrap();
+/// for frame in 0..rawhide::frame_count(&buffer).unwrap() {
+///   let image = rawhide::decode_frame(&buffer, frame).unwrap();
+///   ... process the frame ...
+/// }
+/// ```
+pub fn frame_count(buffer: &Buffer) -> Result<usize, RawHideError> {
+  LOADER.frame_count(buffer).map_err(|err| RawHideError::new(err))
+}
+
+/// Take a buffer and return one of its frames or an error
+pub fn decode_frame(buffer: &Buffer, frame: usize) -> Result<RawImage, RawHideError> {
+  LOADER
+    .decode_frame(buffer, frame, &DecodeOptions::default(), false)
+    .map_err(|err| RawHideError::new(err))
+}
+
+/// Take a buffer and return the timing of one of its frames without decoding the image data,
+/// None for formats that don't record any
+///
+/// # Example
+/// ```rust,ignore
+/// let mut file = File::open("path/to/your/file.ser").unwrap();
+/// let buffer = rawhide::Buffer::new(&mut file).unwrap();
+/// for frame in 0..rawhide::frame_count(&buffer).unwrap() {
+///   let info = rawhide::frame_info(&buffer, frame).unwrap();
+///   println!("frame {} captured at {:?}", frame, info.and_then(|i| i.utc_time));
+/// }
+/// ```
+pub fn frame_info(buffer: &Buffer, frame: usize) -> Result<Option<FrameInfo>, RawHideError> {
+  LOADER
+    .decode_frame(buffer, frame, &DecodeOptions::default(), true)
+    .map(|image| image.frame_info)
+    .map_err(|err| RawHideError::new(err))
+}
+
+/// Take a directory of CinemaDNG frames and return a sequence to decode them from or an error
+///
+/// # Example
+/// ```rust,ignore
+/// let clip = rawhide::open_dng_sequence("path/to/your/clip").unwrap();
+/// println!("{} frames at {:?} fps", clip.frame_count(), clip.frame_rate());
+/// for image in clip.decode_frames(0..clip.frame_count()) {
+///   ... process the frame ...
+/// }
+/// ```
+pub fn open_dng_sequence<P: AsRef<Path>>(path: P) -> Result<DngSequence<'static>, RawHideError> {
+  LOADER
+    .open_dng_sequence(path.as_ref())
+    .map_err(|err| RawHideError::new(err))
+}
+
 // Used to force lazy_static initializations. Useful for fuzzing.
 #[doc(hidden)]
 pub fn force_initialization() {